@@ -0,0 +1,103 @@
+use sha2::{Digest, Sha256};
+
+// Background colours of generated avatars, all dark enough for white initials
+const PALETTE: &[&str] = &[
+    "#e53935", "#d81b60", "#8e24aa", "#5e35b1", "#3949ab", "#1e88e5", "#00838f", "#00897b",
+    "#43a047", "#6d4c41", "#f4511e", "#546e7a",
+];
+
+// Where users and chats without an image of their own get theirs from
+pub fn user_avatar_url(user_id: i64) -> String {
+    format!("/avatars/users/{}", user_id)
+}
+
+pub fn chat_avatar_url(chat_id: &str) -> String {
+    format!("/avatars/chats/{}", chat_id)
+}
+
+// The colour comes from the id so it stays the same when the name changes, only the initials
+// follow the name
+pub fn render_avatar(id: &str, name: &str) -> String {
+    let hash = Sha256::digest(id.as_bytes());
+    let color = PALETTE[hash[0] as usize % PALETTE.len()];
+    format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"128\" height=\"128\" viewBox=\"0 0 128 128\">\
+<rect width=\"128\" height=\"128\" fill=\"{}\"/>\
+<text x=\"64\" y=\"64\" dy=\".35em\" text-anchor=\"middle\" font-family=\"sans-serif\" font-size=\"52\" fill=\"#ffffff\">{}</text>\
+</svg>",
+        color,
+        escape_xml(&initials(name))
+    )
+}
+
+// First letters of the first and last words, "?" for an empty name
+fn initials(name: &str) -> String {
+    let mut words = name.split_whitespace().filter_map(|word| {
+        word.chars()
+            .find(|c| c.is_alphanumeric())
+            .map(|c| c.to_uppercase().collect::<String>())
+    });
+    let Some(first) = words.next() else {
+        return "?".into();
+    };
+    match words.next_back() {
+        Some(last) => first + &last,
+        None => first,
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initials_of_first_and_last_words() {
+        assert_eq!(initials("ana"), "A");
+        assert_eq!(initials("ana maria souza"), "AS");
+        assert_eq!(initials("  joão   silva "), "JS");
+        assert_eq!(initials("élise ßtraße"), "ÉSS");
+        assert_eq!(initials("(grupo) #dev"), "GD");
+    }
+
+    #[test]
+    fn initials_of_an_empty_name() {
+        assert_eq!(initials(""), "?");
+        assert_eq!(initials("   "), "?");
+        assert_eq!(initials("!! --"), "?");
+    }
+
+    #[test]
+    fn escape_xml_escapes_markup() {
+        assert_eq!(
+            escape_xml("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&apos;&amp;&apos;&lt;/a&gt;"
+        );
+        assert_eq!(escape_xml("AS"), "AS");
+    }
+
+    #[test]
+    fn render_avatar_keeps_the_colour_of_the_id() {
+        let fill = |svg: &str| svg.split("fill=\"").nth(1).unwrap()[..7].to_string();
+        let before = render_avatar("42", "ana");
+        let after = render_avatar("42", "<b>bia</b>");
+        assert_eq!(fill(&before), fill(&after));
+        assert!(PALETTE.contains(&fill(&before).as_str()));
+        assert!(after.contains(">B</text>"));
+        assert!(!after.contains("<b>"));
+    }
+}
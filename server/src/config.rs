@@ -0,0 +1,521 @@
+use std::{env, fmt, fs, io, path::PathBuf, str::FromStr, time::Duration};
+
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::sockets::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+
+const CONFIG_PATH_ENV: &str = "CHATAPP_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+const ENV_PREFIX: &str = "CHATAPP_";
+const MIN_SESSION_KEY_LEN: usize = 64;
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub database: DatabaseConfig,
+    pub log: LogConfig,
+    pub session: SessionConfig,
+    pub cors: CorsConfig,
+    pub sockets: SocketConfig,
+    pub limits: LimitsConfig,
+    pub storage: StorageConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: "127.0.0.1".into(),
+            port: 8080,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    pub path: PathBuf,
+    pub read_connections: u32,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            path: "database.sqlite".into(),
+            read_connections: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub file: PathBuf,
+    pub level: String,
+    pub console_level: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            file: "log/output.log".into(),
+            level: "info".into(),
+            console_level: "info".into(),
+        }
+    }
+}
+
+impl LogConfig {
+    pub fn level_filter(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.level).unwrap_or(LevelFilter::Info)
+    }
+
+    pub fn console_level_filter(&self) -> LevelFilter {
+        LevelFilter::from_str(&self.console_level).unwrap_or(LevelFilter::Info)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+impl FromStr for SameSite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "strict" => Ok(SameSite::Strict),
+            "lax" => Ok(SameSite::Lax),
+            "none" => Ok(SameSite::None),
+            _ => Err("expected one of strict, lax or none".into()),
+        }
+    }
+}
+
+impl From<SameSite> for actix_web::cookie::SameSite {
+    fn from(same_site: SameSite) -> Self {
+        match same_site {
+            SameSite::Strict => actix_web::cookie::SameSite::Strict,
+            SameSite::Lax => actix_web::cookie::SameSite::Lax,
+            SameSite::None => actix_web::cookie::SameSite::None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    // At least 64 bytes, when missing a random key is generated and sessions do not survive restarts.
+    pub key: Option<String>,
+    pub cookie_name: String,
+    pub cookie_secure: bool,
+    pub cookie_http_only: bool,
+    pub cookie_same_site: SameSite,
+    pub ttl_days: i64,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            key: None,
+            cookie_name: "ssid".into(),
+            cookie_secure: false,
+            cookie_http_only: true,
+            cookie_same_site: SameSite::Strict,
+            ttl_days: 14,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CorsConfig {
+    // Empty means any origin is allowed.
+    pub allowed_origins: Vec<String>,
+    pub max_age_secs: usize,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            max_age_secs: 3600,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketConfig {
+    pub heartbeat_interval_secs: u64,
+    pub client_timeout_secs: u64,
+    // Past this many missed messages a resuming client is told to refetch instead.
+    pub max_replay_messages: usize,
+    // A typing indicator goes away when the client doesn't refresh it for this long.
+    pub typing_timeout_secs: u64,
+    // Typing frames closer than this to the previous one from the same user are dropped.
+    pub typing_min_interval_ms: u64,
+    // Connected users show as idle after this long without sending anything.
+    pub idle_after_secs: u64,
+}
+
+impl Default for SocketConfig {
+    fn default() -> Self {
+        Self {
+            heartbeat_interval_secs: HEARTBEAT_INTERVAL.as_secs(),
+            client_timeout_secs: CLIENT_TIMEOUT.as_secs(),
+            max_replay_messages: 200,
+            typing_timeout_secs: 6,
+            typing_min_interval_ms: 1000,
+            idle_after_secs: 5 * 60,
+        }
+    }
+}
+
+impl SocketConfig {
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_secs(self.heartbeat_interval_secs)
+    }
+
+    pub fn client_timeout(&self) -> Duration {
+        Duration::from_secs(self.client_timeout_secs)
+    }
+
+    pub fn typing_timeout(&self) -> Duration {
+        Duration::from_secs(self.typing_timeout_secs)
+    }
+
+    pub fn typing_min_interval(&self) -> Duration {
+        Duration::from_millis(self.typing_min_interval_ms)
+    }
+
+    pub fn idle_after(&self) -> Duration {
+        Duration::from_secs(self.idle_after_secs)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Characters, chat_messages.message is a VARCHAR(512).
+    pub max_message_len: usize,
+    pub max_json_payload: usize,
+    pub max_ws_frame: usize,
+    // Messages per page of /chat/messages, callers may ask for less.
+    pub max_page_size: usize,
+    // Authors can edit a message for this long after sending it, 0 never closes the window.
+    pub edit_window_secs: u32,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_message_len: 512,
+            max_json_payload: 32 * 1024,
+            max_ws_frame: 64 * 1024,
+            max_page_size: 100,
+            edit_window_secs: 15 * 60,
+        }
+    }
+}
+
+impl LimitsConfig {
+    pub fn edit_window(&self) -> Option<Duration> {
+        match self.edit_window_secs {
+            0 => None,
+            secs => Some(Duration::from_secs(secs.into())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    // Uploaded files, each one named after the sha256 of its contents.
+    pub root: PathBuf,
+    // Bytes, bigger uploads are rejected while they are still being received.
+    pub max_upload_bytes: usize,
+    // Resumable uploads that get no new chunk for this long are dropped.
+    pub upload_expiry_secs: u64,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            root: "uploads".into(),
+            max_upload_bytes: 10 * 1024 * 1024,
+            upload_expiry_secs: 24 * 60 * 60,
+        }
+    }
+}
+
+impl StorageConfig {
+    pub fn upload_expiry(&self) -> Duration {
+        Duration::from_secs(self.upload_expiry_secs)
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, err: io::Error },
+    Parse { path: PathBuf, err: toml::de::Error },
+    Env { var: String, value: String, reason: String },
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, err } => {
+                write!(f, "could not read {}: {}", path.display(), err)
+            }
+            ConfigError::Parse { path, err } => {
+                write!(f, "could not parse {}: {}", path.display(), err)
+            }
+            ConfigError::Env { var, value, reason } => {
+                write!(f, "invalid value {:?} for {}: {}", value, var, reason)
+            }
+            ConfigError::Invalid { field, reason } => write!(f, "invalid {}: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    // Reads CHATAPP_CONFIG (or ./config.toml when present), then applies the CHATAPP_* variables
+    // from the environment / .env on top of it.
+    pub fn load() -> Result<Config, ConfigError> {
+        let mut config = match env::var(CONFIG_PATH_ENV) {
+            Ok(path) => Self::from_file(PathBuf::from(path))?,
+            Err(_) if PathBuf::from(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(PathBuf::from(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: PathBuf) -> Result<Config, ConfigError> {
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) => return Err(ConfigError::Read { path, err }),
+        };
+        toml::from_str(&contents).map_err(|err| ConfigError::Parse { path, err })
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        // URL was the only setting before the config file existed, keep honoring it.
+        if let Ok(url) = env::var("URL") {
+            self.server.bind = url;
+        }
+        override_env("SERVER_BIND", &mut self.server.bind)?;
+        override_env("SERVER_PORT", &mut self.server.port)?;
+        override_env("DATABASE_PATH", &mut self.database.path)?;
+        override_env(
+            "DATABASE_READ_CONNECTIONS",
+            &mut self.database.read_connections,
+        )?;
+        override_env("LOG_FILE", &mut self.log.file)?;
+        override_env("LOG_LEVEL", &mut self.log.level)?;
+        override_env("LOG_CONSOLE_LEVEL", &mut self.log.console_level)?;
+        if let Ok(key) = env::var(format!("{ENV_PREFIX}SESSION_KEY")) {
+            self.session.key = Some(key);
+        }
+        override_env("SESSION_COOKIE_NAME", &mut self.session.cookie_name)?;
+        override_env("SESSION_COOKIE_SECURE", &mut self.session.cookie_secure)?;
+        override_env("SESSION_COOKIE_HTTP_ONLY", &mut self.session.cookie_http_only)?;
+        override_env("SESSION_COOKIE_SAME_SITE", &mut self.session.cookie_same_site)?;
+        override_env("SESSION_TTL_DAYS", &mut self.session.ttl_days)?;
+        if let Ok(origins) = env::var(format!("{ENV_PREFIX}CORS_ALLOWED_ORIGINS")) {
+            self.cors.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        override_env("CORS_MAX_AGE_SECS", &mut self.cors.max_age_secs)?;
+        override_env(
+            "SOCKETS_HEARTBEAT_INTERVAL_SECS",
+            &mut self.sockets.heartbeat_interval_secs,
+        )?;
+        override_env(
+            "SOCKETS_CLIENT_TIMEOUT_SECS",
+            &mut self.sockets.client_timeout_secs,
+        )?;
+        override_env(
+            "SOCKETS_MAX_REPLAY_MESSAGES",
+            &mut self.sockets.max_replay_messages,
+        )?;
+        override_env(
+            "SOCKETS_TYPING_TIMEOUT_SECS",
+            &mut self.sockets.typing_timeout_secs,
+        )?;
+        override_env(
+            "SOCKETS_TYPING_MIN_INTERVAL_MS",
+            &mut self.sockets.typing_min_interval_ms,
+        )?;
+        override_env(
+            "SOCKETS_IDLE_AFTER_SECS",
+            &mut self.sockets.idle_after_secs,
+        )?;
+        override_env("LIMITS_MAX_MESSAGE_LEN", &mut self.limits.max_message_len)?;
+        override_env("LIMITS_MAX_JSON_PAYLOAD", &mut self.limits.max_json_payload)?;
+        override_env("LIMITS_MAX_WS_FRAME", &mut self.limits.max_ws_frame)?;
+        override_env("LIMITS_MAX_PAGE_SIZE", &mut self.limits.max_page_size)?;
+        override_env("LIMITS_EDIT_WINDOW_SECS", &mut self.limits.edit_window_secs)?;
+        override_env("STORAGE_ROOT", &mut self.storage.root)?;
+        override_env(
+            "STORAGE_MAX_UPLOAD_BYTES",
+            &mut self.storage.max_upload_bytes,
+        )?;
+        override_env(
+            "STORAGE_UPLOAD_EXPIRY_SECS",
+            &mut self.storage.upload_expiry_secs,
+        )?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.server.bind.trim().is_empty() {
+            return invalid("server.bind", "must not be empty");
+        }
+        if self.server.port == 0 {
+            return invalid("server.port", "must be between 1 and 65535");
+        }
+        if self.database.read_connections == 0 {
+            return invalid("database.read_connections", "must be at least 1");
+        }
+        for (field, level) in [
+            ("log.level", &self.log.level),
+            ("log.console_level", &self.log.console_level),
+        ] {
+            if LevelFilter::from_str(level).is_err() {
+                return invalid(
+                    field,
+                    format!("unknown level {:?}, expected off, error, warn, info, debug or trace", level),
+                );
+            }
+        }
+        if let Some(key) = &self.session.key {
+            if key.len() < MIN_SESSION_KEY_LEN {
+                return invalid(
+                    "session.key",
+                    format!("must be at least {} bytes long", MIN_SESSION_KEY_LEN),
+                );
+            }
+        }
+        if self.session.cookie_name.is_empty() {
+            return invalid("session.cookie_name", "must not be empty");
+        }
+        if self.session.cookie_same_site == SameSite::None && !self.session.cookie_secure {
+            return invalid(
+                "session.cookie_same_site",
+                "\"none\" requires session.cookie_secure = true",
+            );
+        }
+        if self.session.ttl_days <= 0 {
+            return invalid("session.ttl_days", "must be at least 1");
+        }
+        if let Some(origin) = self
+            .cors
+            .allowed_origins
+            .iter()
+            .find(|origin| !(origin.starts_with("http://") || origin.starts_with("https://")))
+        {
+            return invalid(
+                "cors.allowed_origins",
+                format!("{:?} must start with http:// or https://", origin),
+            );
+        }
+        if self.sockets.heartbeat_interval_secs == 0 {
+            return invalid("sockets.heartbeat_interval_secs", "must be at least 1");
+        }
+        if self.sockets.client_timeout_secs <= self.sockets.heartbeat_interval_secs {
+            return invalid(
+                "sockets.client_timeout_secs",
+                "must be greater than sockets.heartbeat_interval_secs",
+            );
+        }
+        if self.sockets.typing_timeout_secs == 0 {
+            return invalid("sockets.typing_timeout_secs", "must be at least 1");
+        }
+        if self.sockets.typing_min_interval() >= self.sockets.typing_timeout() {
+            return invalid(
+                "sockets.typing_min_interval_ms",
+                "must be shorter than sockets.typing_timeout_secs",
+            );
+        }
+        if self.sockets.idle_after_secs == 0 {
+            return invalid("sockets.idle_after_secs", "must be at least 1");
+        }
+        if self.limits.max_message_len == 0 {
+            return invalid("limits.max_message_len", "must be at least 1");
+        }
+        if self.limits.max_json_payload == 0 {
+            return invalid("limits.max_json_payload", "must be at least 1");
+        }
+        if self.limits.max_ws_frame < self.limits.max_message_len {
+            return invalid(
+                "limits.max_ws_frame",
+                "must be at least limits.max_message_len",
+            );
+        }
+        if self.limits.max_page_size == 0 {
+            return invalid("limits.max_page_size", "must be at least 1");
+        }
+        if self.storage.root.as_os_str().is_empty() {
+            return invalid("storage.root", "must not be empty");
+        }
+        if self.storage.max_upload_bytes == 0 {
+            return invalid("storage.max_upload_bytes", "must be at least 1");
+        }
+        if self.storage.upload_expiry_secs == 0 {
+            return invalid("storage.upload_expiry_secs", "must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+fn invalid(field: &'static str, reason: impl Into<String>) -> Result<(), ConfigError> {
+    Err(ConfigError::Invalid {
+        field,
+        reason: reason.into(),
+    })
+}
+
+fn override_env<T>(name: &str, target: &mut T) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let var = format!("{ENV_PREFIX}{name}");
+    let Ok(value) = env::var(&var) else {
+        return Ok(());
+    };
+    match value.parse() {
+        Ok(parsed) => {
+            *target = parsed;
+            Ok(())
+        }
+        Err(err) => Err(ConfigError::Env {
+            var,
+            value,
+            reason: err.to_string(),
+        }),
+    }
+}
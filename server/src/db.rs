@@ -1,38 +1,160 @@
+pub mod attachment_db;
 pub mod chat_db;
 pub mod chat_message_db;
+pub mod message_reaction_db;
+pub mod message_search_db;
+pub mod migrations;
 pub mod session_db;
+pub mod upload_db;
 pub mod user_db;
 
-use rusqlite::{Connection, Error};
+use std::{fmt, time::Duration};
 
-use user_db::USER_TABLE_SQL;
+use actix_web::{error::BlockingError, web};
+use r2d2::{Pool, PooledConnection};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{ErrorCode, OpenFlags};
 
-use self::chat_db::{CHAT_TABLE_SQL, CHAT_USERS_TABLE_SQL};
-use self::chat_message_db::CHAT_MESSAGES_TABLE_SQL;
+use crate::config::DatabaseConfig;
 
-const DB_NAME: &str = "database.sqlite";
-pub fn get() -> Result<Database, rusqlite::Error> {
-    let conn = Connection::open(DB_NAME).unwrap();
-    let db = Database { conn };
-    db.creation()?;
-    Ok(db)
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn get(config: &DatabaseConfig) -> Result<DatabasePool, DatabaseError> {
+    let writer = Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::file(&config.path).with_init(init_connection))?;
+    {
+        let conn = writer.get()?;
+        conn.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        migrations::migrate(&conn)?;
+    }
+
+    // Readers are opened after the migrations so they never see a half created schema.
+    let readers = Pool::builder().max_size(config.read_connections).build(
+        SqliteConnectionManager::file(&config.path)
+            .with_flags(OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX)
+            .with_init(init_connection),
+    )?;
+    Ok(DatabasePool { readers, writer })
+}
+
+fn init_connection(conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // Needed for ON DELETE CASCADE, removing a chat takes its messages and members with it.
+    conn.pragma_update(None, "foreign_keys", true)
 }
 
+// A connection checked out of the pool, the *Table traits are implemented on it.
 #[derive(Debug)]
 pub struct Database {
-    conn: Connection,
-}
-
-impl Database {
-    fn creation(&self) -> Result<(), Error> {
-        self.conn.execute_batch(&format!(
-            "BEGIN;
-            {USER_TABLE_SQL}
-            {CHAT_TABLE_SQL}
-            {CHAT_MESSAGES_TABLE_SQL}
-            {CHAT_USERS_TABLE_SQL}
-            COMMIT;"
-        ))?;
-        Ok(())
+    conn: PooledConnection<SqliteConnectionManager>,
+}
+
+// A migrated in-memory database for the tests of the *Table traits
+#[cfg(test)]
+pub fn test_database() -> Database {
+    let pool = Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory().with_init(init_connection))
+        .unwrap();
+    let conn = pool.get().unwrap();
+    migrations::migrate(&conn).unwrap();
+    Database { conn }
+}
+
+// Several read only connections plus a single writer, every query runs on actix's blocking
+// thread pool so the workers and actors are never stuck waiting on sqlite.
+#[derive(Debug, Clone)]
+pub struct DatabasePool {
+    readers: Pool<SqliteConnectionManager>,
+    writer: Pool<SqliteConnectionManager>,
+}
+
+impl DatabasePool {
+    pub async fn read<F, T>(&self, query: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Database) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run(self.readers.clone(), query).await
+    }
+
+    pub async fn write<F, T>(&self, query: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Database) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        Self::run(self.writer.clone(), query).await
+    }
+
+    async fn run<F, T>(pool: Pool<SqliteConnectionManager>, query: F) -> Result<T, DatabaseError>
+    where
+        F: FnOnce(&Database) -> Result<T, rusqlite::Error> + Send + 'static,
+        T: Send + 'static,
+    {
+        web::block(move || {
+            let db = Database { conn: pool.get()? };
+            Ok(query(&db)?)
+        })
+        .await?
+    }
+}
+
+#[derive(Debug)]
+pub enum DatabaseError {
+    Sqlite(rusqlite::Error),
+    Pool(r2d2::Error),
+    Blocking(BlockingError),
+    SchemaTooNew { found: i64, supported: i64 },
+}
+
+impl DatabaseError {
+    pub fn sqlite_error_code(&self) -> Option<ErrorCode> {
+        match self {
+            DatabaseError::Sqlite(err) => err.sqlite_error_code(),
+            _ => None,
+        }
+    }
+
+    pub fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            DatabaseError::Sqlite(rusqlite::Error::QueryReturnedNoRows)
+        )
+    }
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DatabaseError::Sqlite(err) => write!(f, "sqlite error: {}", err),
+            DatabaseError::Pool(err) => write!(f, "connection pool error: {}", err),
+            DatabaseError::Blocking(err) => write!(f, "blocking task error: {}", err),
+            DatabaseError::SchemaTooNew { found, supported } => write!(
+                f,
+                "database schema version {} is newer than the latest supported version {}, refusing to start",
+                found, supported
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DatabaseError {}
+
+impl From<rusqlite::Error> for DatabaseError {
+    fn from(err: rusqlite::Error) -> Self {
+        DatabaseError::Sqlite(err)
+    }
+}
+
+impl From<r2d2::Error> for DatabaseError {
+    fn from(err: r2d2::Error) -> Self {
+        DatabaseError::Pool(err)
+    }
+}
+
+impl From<BlockingError> for DatabaseError {
+    fn from(err: BlockingError) -> Self {
+        DatabaseError::Blocking(err)
     }
 }
@@ -0,0 +1,261 @@
+use rusqlite::{params, params_from_iter, types::Value, OptionalExtension, Row};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::storage::images::ImageInfo;
+
+use super::{chat_message_db::ChatMessage, Database};
+
+// Uploaded files, the bytes are kept by Storage under sha256. chat_id is the chat the file was
+// uploaded to (NULL for user avatars) and chat_message_id the message it was sent with, NULL
+// until then.
+pub const ATTACHMENTS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS attachments (
+    attachment_id VARCHAR(36) PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    chat_id VARCHAR(36),
+    chat_message_id VARCHAR(36),
+    sha256 CHAR(64) NOT NULL,
+    file_name VARCHAR(255) NOT NULL,
+    mime_type VARCHAR(127) NOT NULL,
+    size INTEGER NOT NULL,
+    date_created VARCHAR(32),
+
+    FOREIGN KEY (user_id) REFERENCES users(user_id),
+    FOREIGN KEY (chat_id) REFERENCES chats(chat_id) ON DELETE CASCADE,
+    FOREIGN KEY (chat_message_id) REFERENCES chat_messages(chat_message_id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_attachments_message ON attachments(chat_message_id);";
+
+// Size and blurhash of images, NULL for any other file
+pub const ATTACHMENTS_IMAGE_MIGRATION_SQL: &str = "ALTER TABLE attachments ADD COLUMN width INTEGER;
+ALTER TABLE attachments ADD COLUMN height INTEGER;
+ALTER TABLE attachments ADD COLUMN blurhash VARCHAR(64);";
+
+// The cleanup sweep looks blobs up by their contents
+pub const ATTACHMENTS_SHA256_INDEX_SQL: &str =
+    "CREATE INDEX IF NOT EXISTS idx_attachments_sha256 ON attachments(sha256);";
+
+pub const MAX_ATTACHMENTS_PER_MESSAGE: usize = 10;
+
+const ATTACHMENT_SELECT_SQL: &str =
+    "SELECT attachment_id, file_name, mime_type, size, sha256, user_id, chat_id, chat_message_id, width, height, blurhash FROM attachments";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Attachment {
+    pub id: String,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    // Where the file is downloaded from, images also take ?size= for a thumbnail
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    // Placeholder shown while the image loads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    #[serde(skip)]
+    pub sha256: String,
+    #[serde(skip)]
+    pub user_id: i64,
+    #[serde(skip)]
+    pub chat_id: Option<String>,
+    #[serde(skip)]
+    pub message_id: Option<String>,
+}
+
+pub fn attachment_url(attachment_id: &str) -> String {
+    format!("/attachments/{}", attachment_id)
+}
+
+fn attachment_from_row(row: &Row) -> Result<Attachment, rusqlite::Error> {
+    let id: String = row.get(0)?;
+    Ok(Attachment {
+        url: attachment_url(&id),
+        id,
+        file_name: row.get(1)?,
+        mime_type: row.get(2)?,
+        size: row.get(3)?,
+        sha256: row.get(4)?,
+        user_id: row.get(5)?,
+        chat_id: row.get(6)?,
+        message_id: row.get(7)?,
+        width: row.get(8)?,
+        height: row.get(9)?,
+        blurhash: row.get(10)?,
+    })
+}
+
+pub struct InsertAttachment {
+    pub user_id: i64,
+    pub chat_id: Option<String>,
+    pub sha256: String,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub image: Option<ImageInfo>,
+    pub date_created: String,
+}
+
+pub trait AttachmentsTable {
+    fn insert_attachment(
+        &self,
+        attachment: InsertAttachment,
+    ) -> Result<Attachment, rusqlite::Error>;
+    fn get_attachment(&self, attachment_id: &str) -> Result<Option<Attachment>, rusqlite::Error>;
+    // How many of the ids are uploads of user_id to the chat that were not sent yet
+    fn count_unsent_attachments(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        attachment_ids: &[String],
+    ) -> Result<usize, rusqlite::Error>;
+    fn attach_files(&self, messages: &mut [ChatMessage]) -> Result<(), rusqlite::Error>;
+    // Uploads created before `before` that were never sent, user and chat images are kept
+    fn delete_unsent_attachments(&self, before: &str) -> Result<usize, rusqlite::Error>;
+    // The ones no attachment points to anymore
+    fn unreferenced_blobs(&self, sha256s: Vec<String>) -> Result<Vec<String>, rusqlite::Error>;
+}
+
+impl AttachmentsTable for Database {
+    fn insert_attachment(
+        &self,
+        attachment: InsertAttachment,
+    ) -> Result<Attachment, rusqlite::Error> {
+        let attachment_id = Uuid::new_v4().to_string();
+        let image = attachment.image;
+        self.conn.execute(
+            "INSERT INTO attachments (attachment_id, user_id, chat_id, sha256, file_name, mime_type, size, width, height, blurhash, date_created) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                attachment_id,
+                attachment.user_id,
+                attachment.chat_id,
+                attachment.sha256,
+                attachment.file_name,
+                attachment.mime_type,
+                attachment.size,
+                image.as_ref().map(|image| image.width),
+                image.as_ref().map(|image| image.height),
+                image.as_ref().and_then(|image| image.blurhash.clone()),
+                attachment.date_created
+            ],
+        )?;
+        Ok(Attachment {
+            url: attachment_url(&attachment_id),
+            id: attachment_id,
+            file_name: attachment.file_name,
+            mime_type: attachment.mime_type,
+            size: attachment.size,
+            width: image.as_ref().map(|image| image.width),
+            height: image.as_ref().map(|image| image.height),
+            blurhash: image.and_then(|image| image.blurhash),
+            sha256: attachment.sha256,
+            user_id: attachment.user_id,
+            chat_id: attachment.chat_id,
+            message_id: None,
+        })
+    }
+
+    fn get_attachment(&self, attachment_id: &str) -> Result<Option<Attachment>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                &format!("{} WHERE attachment_id = ?", ATTACHMENT_SELECT_SQL),
+                params![attachment_id],
+                attachment_from_row,
+            )
+            .optional()
+    }
+
+    fn count_unsent_attachments(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        attachment_ids: &[String],
+    ) -> Result<usize, rusqlite::Error> {
+        if attachment_ids.is_empty() {
+            return Ok(0);
+        }
+        let placeholders = vec!["?"; attachment_ids.len()].join(", ");
+        let ids = attachment_ids.iter().map(|id| Value::Text(id.clone()));
+        let params = [Value::Text(chat_id.to_string()), Value::Integer(user_id)]
+            .into_iter()
+            .chain(ids);
+        self.conn.query_row(
+            &format!(
+                "SELECT COUNT(*) FROM attachments WHERE chat_id = ? AND user_id = ? AND chat_message_id IS NULL AND attachment_id IN ({})",
+                placeholders
+            ),
+            params_from_iter(params),
+            |row| row.get(0),
+        )
+    }
+
+    // Fills ChatMessage::attachments for a page of messages in a single query
+    fn attach_files(&self, messages: &mut [ChatMessage]) -> Result<(), rusqlite::Error> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let placeholders = vec!["?"; messages.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE chat_message_id IN ({}) ORDER BY rowid",
+            ATTACHMENT_SELECT_SQL, placeholders
+        ))?;
+        let ids = messages
+            .iter()
+            .map(|message| Value::Text(message.id.clone()));
+        let attachments = stmt.query_map(params_from_iter(ids), attachment_from_row)?;
+        for attachment in attachments {
+            let attachment = attachment?;
+            let Some(message) = messages
+                .iter_mut()
+                .find(|message| Some(&message.id) == attachment.message_id.as_ref())
+            else {
+                continue;
+            };
+            message.attachments.push(attachment);
+        }
+        Ok(())
+    }
+
+    // Images are referenced by their url, see attachment_url
+    fn delete_unsent_attachments(&self, before: &str) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM attachments WHERE chat_message_id IS NULL AND date_created < ?
+                AND NOT EXISTS (SELECT 1 FROM users WHERE user_image = '/attachments/' || attachments.attachment_id)
+                AND NOT EXISTS (SELECT 1 FROM chats WHERE chat_image = '/attachments/' || attachments.attachment_id)",
+            params![before],
+        )
+    }
+
+    fn unreferenced_blobs(&self, sha256s: Vec<String>) -> Result<Vec<String>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT EXISTS (SELECT 1 FROM attachments WHERE sha256 = ?)")?;
+        let mut unreferenced = Vec::new();
+        for sha256 in sha256s {
+            let referenced: bool = stmt.query_row(params![sha256], |row| row.get(0))?;
+            if !referenced {
+                unreferenced.push(sha256);
+            }
+        }
+        Ok(unreferenced)
+    }
+}
+
+// Links the uploads to the message being inserted, see ChatMessagesTable::insert_message
+pub fn link_attachments(
+    conn: &rusqlite::Connection,
+    chat_id: &str,
+    message_id: &str,
+    user_id: i64,
+    attachment_ids: &[String],
+) -> Result<(), rusqlite::Error> {
+    for attachment_id in attachment_ids {
+        conn.execute(
+            "UPDATE attachments SET chat_message_id = ? WHERE attachment_id = ? AND chat_id = ? AND user_id = ? AND chat_message_id IS NULL",
+            params![message_id, attachment_id, chat_id, user_id],
+        )?;
+    }
+    Ok(())
+}
@@ -1,9 +1,16 @@
 use chrono::Utc;
-use rusqlite::params;
+use rusqlite::{
+    params,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    OptionalExtension, Row, ToSql,
+};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use crate::message::format_date;
+use crate::{
+    avatar::{chat_avatar_url, user_avatar_url},
+    message::format_date,
+};
 
 use super::{
     chat_message_db::{ChatMessage, ChatMessagesTable},
@@ -28,12 +35,97 @@ pub const CHAT_USERS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS chat_users (
     FOREIGN KEY (chat_id) REFERENCES chats(chat_id),
     FOREIGN KEY (user_id) REFERENCES users(user_id)
 );";
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+
+// chat_users was never written to, rebuild it with the right chat_id type and make every
+// creator a member of their chats.
+pub const CHAT_USERS_MEMBERSHIP_MIGRATION_SQL: &str = "CREATE TABLE chat_users_new (
+    chat_user_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id VARCHAR(36) NOT NULL,
+    user_id INTEGER NOT NULL,
+    date_joined VARCHAR(32),
+    UNIQUE (chat_id, user_id),
+    FOREIGN KEY (chat_id) REFERENCES chats(chat_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+INSERT OR IGNORE INTO chat_users_new (chat_id, user_id)
+    SELECT CAST(chat_id AS TEXT), user_id FROM chat_users
+    WHERE CAST(chat_id AS TEXT) IN (SELECT chat_id FROM chats);
+INSERT OR IGNORE INTO chat_users_new (chat_id, user_id, date_joined)
+    SELECT chat_id, user_id, date_created FROM chats WHERE user_id IS NOT NULL;
+DROP TABLE chat_users;
+ALTER TABLE chat_users_new RENAME TO chat_users;
+CREATE INDEX chat_users_user_id ON chat_users (user_id);";
+
+pub const CHAT_USERS_ROLE_MIGRATION_SQL: &str = "ALTER TABLE chat_users ADD COLUMN role VARCHAR(8) NOT NULL DEFAULT 'MEMBER';
+UPDATE chat_users SET role = 'OWNER' WHERE (chat_id, user_id) IN (SELECT chat_id, user_id FROM chats);";
+
+// One row per pair of users (user_low < user_high), so two users can only ever share one DM.
+pub const DIRECT_CHATS_MIGRATION_SQL: &str = "ALTER TABLE chats ADD COLUMN chat_type VARCHAR(8) NOT NULL DEFAULT 'GROUP';
+CREATE TABLE direct_chats (
+    chat_id VARCHAR(36) PRIMARY KEY,
+    user_low INTEGER NOT NULL,
+    user_high INTEGER NOT NULL,
+    UNIQUE (user_low, user_high),
+    CHECK (user_low < user_high),
+    FOREIGN KEY (chat_id) REFERENCES chats(chat_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_low) REFERENCES users(user_id),
+    FOREIGN KEY (user_high) REFERENCES users(user_id)
+);";
+
+// Everyone starts with what was already in their chats marked as read
+pub const CHAT_USERS_READ_MARKER_MIGRATION_SQL: &str = "ALTER TABLE chat_users ADD COLUMN last_read_seq INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_users ADD COLUMN last_read_at VARCHAR(32);
+UPDATE chat_users SET last_read_seq = IFNULL(
+    (SELECT MAX(seq) FROM chat_messages m WHERE m.chat_id = chat_users.chat_id), 0);";
+
+// DMs show the name and image of the other participant, ?1 is the user looking at the chat.
+// The last three columns are the user's read marker, how many messages came after it in the
+// main timeline (not counting their own or deleted ones) and the first of those.
+const CHAT_SELECT_SQL: &str = "SELECT c.chat_id,
+    CASE WHEN c.chat_type = 'USER' THEN IFNULL(o.user_nick, '') ELSE c.chat_name END,
+    c.chat_desc, c.user_id, c.date_created,
+    CASE WHEN c.chat_type = 'USER' THEN o.user_image ELSE c.chat_image END,
+    c.chat_type, o.user_id, r.last_read_seq,
+    (SELECT COUNT(*) FROM chat_messages m WHERE m.chat_id = c.chat_id AND m.thread_id IS NULL
+        AND m.deleted_at IS NULL AND m.user_id IS NOT ?1 AND m.seq > r.last_read_seq),
+    (SELECT MIN(m.seq) FROM chat_messages m WHERE m.chat_id = c.chat_id AND m.thread_id IS NULL
+        AND m.deleted_at IS NULL AND m.user_id IS NOT ?1 AND m.seq > r.last_read_seq)
+FROM chats c
+LEFT JOIN direct_chats d ON d.chat_id = c.chat_id
+LEFT JOIN users o ON o.user_id = CASE WHEN d.user_low = ?1 THEN d.user_high ELSE d.user_low END
+LEFT JOIN chat_users r ON r.chat_id = c.chat_id AND r.user_id = ?1";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 pub enum ChatTypes {
     USER,
     GROUP,
 }
 
+impl ChatTypes {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatTypes::USER => "USER",
+            ChatTypes::GROUP => "GROUP",
+        }
+    }
+}
+
+impl ToSql for ChatTypes {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ChatTypes {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "USER" => Ok(ChatTypes::USER),
+            "GROUP" => Ok(ChatTypes::GROUP),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Chat {
     pub chat_id: String,
@@ -41,49 +133,193 @@ pub struct Chat {
     pub chat_name: String,
     pub chat_desc: String,
     pub date_created: String,
+    // A generated avatar when the chat has no image, see set_chat_image
     pub chat_image: Option<String>,
     pub chat_type: ChatTypes,
     pub last_message: Option<ChatMessage>,
+    // The other participant of a DM
+    pub direct_user_id: Option<i64>,
+    // Only when the chat is read for one of its members
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub read_state: Option<ReadState>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadState {
+    pub last_read_seq: i64,
+    pub unread_count: i64,
+    // Where the client should open the chat, None when everything was read
+    pub first_unread_seq: Option<i64>,
+}
+
+// Sent to the other members and the reader's other devices when a marker moves forward
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadReceipt {
+    pub chat_id: String,
+    pub user_id: i64,
+    pub message_id: String,
+    pub seq: i64,
+    pub read_at: String,
+    // What is still unread for the user after the marker
+    pub unread_count: i64,
+}
+
+#[derive(Debug)]
+pub enum ReadOutcome {
+    Advanced(ReadReceipt),
+    // The marker was already at or past the message
+    Unchanged,
+    NotFound,
+}
+
+fn chat_from_row(row: &Row) -> Result<Chat, rusqlite::Error> {
+    let chat_id: String = row.get(0)?;
+    let chat_type = row.get(6)?;
+    let direct_user_id = row.get(7)?;
+    // DMs fall back to the other participant's avatar, same as they show their image
+    let chat_image = match (row.get(5)?, chat_type, direct_user_id) {
+        (Some(image), _, _) => image,
+        (None, ChatTypes::USER, Some(user_id)) => user_avatar_url(user_id),
+        (None, _, _) => chat_avatar_url(&chat_id),
+    };
+    Ok(Chat {
+        chat_id,
+        chat_name: row.get(1)?,
+        chat_desc: row.get(2)?,
+        creator_id: row.get(3)?,
+        date_created: row.get(4)?,
+        chat_image: Some(chat_image),
+        chat_type,
+        last_message: None,
+        direct_user_id,
+        read_state: match row.get(8)? {
+            Some(last_read_seq) => Some(ReadState {
+                last_read_seq,
+                unread_count: row.get(9)?,
+                first_unread_seq: row.get(10)?,
+            }),
+            None => None,
+        },
+    })
+}
+
+// Declared from least to most privileged so roles can be compared with >=
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChatRole {
+    MEMBER,
+    ADMIN,
+    OWNER,
+}
+
+impl ChatRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::MEMBER => "MEMBER",
+            ChatRole::ADMIN => "ADMIN",
+            ChatRole::OWNER => "OWNER",
+        }
+    }
+}
+
+impl ToSql for ChatRole {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for ChatRole {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match value.as_str()? {
+            "MEMBER" => Ok(ChatRole::MEMBER),
+            "ADMIN" => Ok(ChatRole::ADMIN),
+            "OWNER" => Ok(ChatRole::OWNER),
+            _ => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChatMember {
+    pub user_id: i64,
+    pub user_nick: String,
+    pub user_image: Option<String>,
+    pub date_joined: Option<String>,
+    pub role: ChatRole,
 }
+
 pub trait ChatTable {
     fn create_chat(&self, nome: &str, id_usuario: i64) -> Result<String, rusqlite::Error>;
-    fn get_chats(&self) -> Result<Vec<Chat>, rusqlite::Error>;
-    fn get_chat(&self, chat_id: &str, t: ChatTypes) -> Result<Chat, rusqlite::Error>;
+    // Only the chats user_id is a member of
+    fn get_chats(&self, user_id: i64) -> Result<Vec<Chat>, rusqlite::Error>;
+    fn get_chat(&self, chat_id: &str, user_id: i64) -> Result<Chat, rusqlite::Error>;
+    // Returns the DM between both users, creating it when it doesn't exist yet. The bool is
+    // true when it was created.
+    fn open_direct_chat(
+        &self,
+        user_id: i64,
+        other_user_id: i64,
+    ) -> Result<(String, bool), rusqlite::Error>;
     fn remove_chat(&self, chat_id: &str) -> Result<usize, rusqlite::Error>;
+    // chat_image is left alone, see set_chat_image
     fn update_chat(&self, chat: Chat) -> Result<usize, rusqlite::Error>;
+    fn set_chat_image(&self, chat_id: &str, image: Option<&str>) -> Result<usize, rusqlite::Error>;
+    fn add_chat_member(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        role: ChatRole,
+    ) -> Result<usize, rusqlite::Error>;
+    fn remove_chat_member(&self, chat_id: &str, user_id: i64) -> Result<usize, rusqlite::Error>;
+    fn get_chat_members(&self, chat_id: &str) -> Result<Vec<ChatMember>, rusqlite::Error>;
+    // None when user_id is not a member
+    fn get_chat_role(&self, chat_id: &str, user_id: i64)
+        -> Result<Option<ChatRole>, rusqlite::Error>;
+    fn set_chat_role(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        role: ChatRole,
+    ) -> Result<usize, rusqlite::Error>;
+    // new_owner becomes OWNER and owner an ADMIN, 0 when new_owner isn't a member
+    fn transfer_chat(
+        &self,
+        chat_id: &str,
+        owner: i64,
+        new_owner: i64,
+    ) -> Result<usize, rusqlite::Error>;
+    // Moves user_id's marker up to message_id, never backwards
+    fn mark_read(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        message_id: &str,
+    ) -> Result<ReadOutcome, rusqlite::Error>;
 }
 
 impl ChatTable for Database {
     fn create_chat(&self, nome: &str, id_usuario: i64) -> Result<String, rusqlite::Error> {
         let uuid = Uuid::new_v4();
-        let mut stmt = self.conn.prepare(
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
             "INSERT INTO chats (chat_id, chat_name, user_id, date_created) VALUES (?, ?, ?, ?)",
+            params![
+                uuid.to_string(),
+                nome,
+                id_usuario,
+                format_date(Utc::now()),
+            ],
         )?;
-        stmt.execute(params![
-            uuid.to_string(),
-            nome,
-            id_usuario,
-            format_date(Utc::now()),
-        ])?;
+        self.add_chat_member(&uuid.to_string(), id_usuario, ChatRole::OWNER)?;
+        tx.commit()?;
         Ok(uuid.to_string())
     }
-    fn get_chats(&self) -> Result<Vec<Chat>, rusqlite::Error> {
+    fn get_chats(&self, user_id: i64) -> Result<Vec<Chat>, rusqlite::Error> {
         let mut chats: Vec<Chat> = Vec::new();
-        let mut stmt = self.conn.prepare(
-            "SELECT chat_id, chat_name, chat_desc, user_id, date_created, chat_image FROM chats",
-        )?;
-        let rows = stmt.query_map((), |row| {
-            Ok(Chat {
-                chat_id: row.get(0)?,
-                chat_name: row.get(1)?,
-                chat_desc: row.get(2)?,
-                creator_id: row.get(3)?,
-                date_created: row.get(4)?,
-                chat_image: row.get(5)?,
-                chat_type: ChatTypes::GROUP,
-                last_message: None,
-            })
-        })?;
+        let mut stmt = self.conn.prepare(&format!(
+            "{CHAT_SELECT_SQL}
+            INNER JOIN chat_users cu ON cu.chat_id = c.chat_id WHERE cu.user_id = ?1"
+        ))?;
+        let rows = stmt.query_map(params![user_id], chat_from_row)?;
 
         for row in rows {
             let mut row = row?;
@@ -104,25 +340,52 @@ impl ChatTable for Database {
         }
         Ok(chats)
     }
-    fn get_chat(&self, chat_id: &str, _: ChatTypes) -> Result<Chat, rusqlite::Error> {
+    fn get_chat(&self, chat_id: &str, user_id: i64) -> Result<Chat, rusqlite::Error> {
         let mut stmt = self
             .conn
-            .prepare("SELECT chat_id, chat_name, chat_desc, user_id, date_created, chat_image FROM chats WHERE chat_id = ? LIMIT 1")?;
-        let res = stmt.query_row(params![chat_id.to_string()], |row| {
-            Ok(Chat {
-                chat_id: row.get(0)?,
-                chat_name: row.get(1)?,
-                chat_desc: row.get(2)?,
-                creator_id: row.get(3)?,
-                date_created: row.get(4)?,
-                chat_image: row.get(5)?,
-                chat_type: ChatTypes::GROUP,
-                last_message: None,
-            })
-        })?;
+            .prepare(&format!("{CHAT_SELECT_SQL} WHERE c.chat_id = ?2 LIMIT 1"))?;
+        let res = stmt.query_row(params![user_id, chat_id], chat_from_row)?;
         Ok(res)
     }
 
+    fn open_direct_chat(
+        &self,
+        user_id: i64,
+        other_user_id: i64,
+    ) -> Result<(String, bool), rusqlite::Error> {
+        let (user_low, user_high) = if user_id < other_user_id {
+            (user_id, other_user_id)
+        } else {
+            (other_user_id, user_id)
+        };
+        let tx = self.conn.unchecked_transaction()?;
+        let existing: Option<String> = tx
+            .query_row(
+                "SELECT chat_id FROM direct_chats WHERE user_low = ? AND user_high = ?",
+                params![user_low, user_high],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(chat_id) = existing {
+            return Ok((chat_id, false));
+        }
+
+        let chat_id = Uuid::new_v4().to_string();
+        tx.execute(
+            "INSERT INTO chats (chat_id, user_id, date_created, chat_type) VALUES (?, ?, ?, ?)",
+            params![chat_id, user_id, format_date(Utc::now()), ChatTypes::USER],
+        )?;
+        tx.execute(
+            "INSERT INTO direct_chats (chat_id, user_low, user_high) VALUES (?, ?, ?)",
+            params![chat_id, user_low, user_high],
+        )?;
+        // Nobody owns a DM, neither side can delete it, rename it or invite others.
+        self.add_chat_member(&chat_id, user_low, ChatRole::MEMBER)?;
+        self.add_chat_member(&chat_id, user_high, ChatRole::MEMBER)?;
+        tx.commit()?;
+        Ok((chat_id, true))
+    }
+
     fn remove_chat(&self, chat_id: &str) -> Result<usize, rusqlite::Error> {
         // let res = self.conn.prepare("DELETE FROM ")
         let res = self
@@ -134,15 +397,157 @@ impl ChatTable for Database {
     }
 
     fn update_chat(&self, chat: Chat) -> Result<usize, rusqlite::Error> {
-        let mut stmt = self.conn.prepare("UPDATE chats SET chat_name = ?, chat_desc = ?, chat_image = ? WHERE chat_id = ? AND user_id = ?")?;
-        let res = stmt.execute(params![
-            chat.chat_name,
-            chat.chat_desc,
-            chat.chat_image,
-            chat.chat_id,
-            chat.creator_id
-        ]);
+        let mut stmt = self
+            .conn
+            .prepare("UPDATE chats SET chat_name = ?, chat_desc = ? WHERE chat_id = ?")?;
+        let res = stmt.execute(params![chat.chat_name, chat.chat_desc, chat.chat_id]);
         println!("{:?}", stmt.expanded_sql());
         res
     }
+
+    fn set_chat_image(&self, chat_id: &str, image: Option<&str>) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE chats SET chat_image = ? WHERE chat_id = ?",
+            params![image, chat_id],
+        )
+    }
+
+    fn add_chat_member(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        role: ChatRole,
+    ) -> Result<usize, rusqlite::Error> {
+        // New members don't get the whole history as unread
+        self.conn.execute(
+            "INSERT OR IGNORE INTO chat_users (chat_id, user_id, date_joined, role, last_read_seq)
+            VALUES (?1, ?2, ?3, ?4, (SELECT IFNULL(MAX(seq), 0) FROM chat_messages WHERE chat_id = ?1))",
+            params![chat_id, user_id, format_date(Utc::now()), role],
+        )
+    }
+
+    fn remove_chat_member(&self, chat_id: &str, user_id: i64) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM chat_users WHERE chat_id = ? AND user_id = ?",
+            params![chat_id, user_id],
+        )
+    }
+
+    fn get_chat_members(&self, chat_id: &str) -> Result<Vec<ChatMember>, rusqlite::Error> {
+        let mut members = Vec::new();
+        let mut stmt = self.conn.prepare(
+            "SELECT u.user_id, u.user_nick, u.user_image, cu.date_joined, cu.role FROM chat_users cu
+            INNER JOIN users u ON u.user_id = cu.user_id WHERE cu.chat_id = ? ORDER BY cu.chat_user_id",
+        )?;
+        let rows = stmt.query_map(params![chat_id], |row| {
+            let user_id = row.get(0)?;
+            Ok(ChatMember {
+                user_id,
+                user_nick: row.get(1)?,
+                user_image: Some(
+                    row.get::<_, Option<String>>(2)?
+                        .unwrap_or_else(|| user_avatar_url(user_id)),
+                ),
+                date_joined: row.get(3)?,
+                role: row.get(4)?,
+            })
+        })?;
+        for member in rows {
+            members.push(member?);
+        }
+        Ok(members)
+    }
+
+    fn get_chat_role(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+    ) -> Result<Option<ChatRole>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT role FROM chat_users WHERE chat_id = ? AND user_id = ?",
+                params![chat_id, user_id],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    fn set_chat_role(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        role: ChatRole,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE chat_users SET role = ? WHERE chat_id = ? AND user_id = ?",
+            params![role, chat_id, user_id],
+        )
+    }
+
+    fn transfer_chat(
+        &self,
+        chat_id: &str,
+        owner: i64,
+        new_owner: i64,
+    ) -> Result<usize, rusqlite::Error> {
+        // A chat never ends up with two owners or none
+        let tx = self.conn.unchecked_transaction()?;
+        let modified = tx.execute(
+            "UPDATE chat_users SET role = ? WHERE chat_id = ? AND user_id = ?",
+            params![ChatRole::OWNER, chat_id, new_owner],
+        )?;
+        if modified < 1 {
+            return Ok(0);
+        }
+        tx.execute(
+            "UPDATE chat_users SET role = ? WHERE chat_id = ? AND user_id = ?",
+            params![ChatRole::ADMIN, chat_id, owner],
+        )?;
+        tx.commit()?;
+        Ok(modified)
+    }
+
+    // Thread replies share the chat's sequence, so only main timeline messages move the marker
+    fn mark_read(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        message_id: &str,
+    ) -> Result<ReadOutcome, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let seq: Option<i64> = tx
+            .query_row(
+                "SELECT seq FROM chat_messages WHERE chat_id = ? AND chat_message_id = ? AND thread_id IS NULL",
+                params![chat_id, message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(seq) = seq else {
+            return Ok(ReadOutcome::NotFound);
+        };
+        let read_at = format_date(Utc::now());
+        let advanced = tx.execute(
+            "UPDATE chat_users SET last_read_seq = ?, last_read_at = ?
+            WHERE chat_id = ? AND user_id = ? AND last_read_seq < ?",
+            params![seq, read_at, chat_id, user_id, seq],
+        )?;
+        if advanced == 0 {
+            return Ok(ReadOutcome::Unchanged);
+        }
+        let unread_count = tx.query_row(
+            "SELECT COUNT(*) FROM chat_messages WHERE chat_id = ? AND thread_id IS NULL
+            AND deleted_at IS NULL AND user_id IS NOT ? AND seq > ?",
+            params![chat_id, user_id, seq],
+            |row| row.get(0),
+        )?;
+        tx.commit()?;
+        Ok(ReadOutcome::Advanced(ReadReceipt {
+            chat_id: chat_id.to_string(),
+            user_id,
+            message_id: message_id.to_string(),
+            seq,
+            read_at,
+            unread_count,
+        }))
+    }
 }
@@ -1,8 +1,18 @@
-use rusqlite::params;
+use std::time::Duration;
+
+use chrono::Utc;
+use rusqlite::{params, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
-use super::Database;
+use crate::message::format_date;
+
+use super::{
+    attachment_db::{link_attachments, Attachment, AttachmentsTable},
+    chat_db::{ChatRole, ChatTable},
+    message_reaction_db::ReactionCount,
+    Database,
+};
 
 pub const CHAT_MESSAGES_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS chat_messages (
     chat_message_id VARCHAR(36) PRIMARY KEY,
@@ -15,61 +25,416 @@ pub const CHAT_MESSAGES_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS chat_messa
     FOREIGN KEY (user_id) REFERENCES users(user_id)
 );";
 
+// The key a client picks for a message, retrying with the same key never stores it twice
+pub const CHAT_MESSAGES_CLIENT_ID_MIGRATION_SQL: &str = "
+ALTER TABLE chat_messages ADD COLUMN client_msg_id VARCHAR(64);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chat_messages_client_msg_id
+    ON chat_messages(chat_id, user_id, client_msg_id) WHERE client_msg_id IS NOT NULL;
+";
+
+// Every chat numbers its messages 1, 2, 3... in the order they were stored, chats.last_seq
+// holds the last number handed out. Existing messages are numbered by date, then insertion.
+pub const CHAT_MESSAGES_SEQ_MIGRATION_SQL: &str = "
+ALTER TABLE chats ADD COLUMN last_seq INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_messages ADD COLUMN seq INTEGER;
+UPDATE chat_messages SET seq = (
+    SELECT numbered.seq FROM (
+        SELECT rowid AS message_rowid, ROW_NUMBER() OVER (
+            PARTITION BY chat_id ORDER BY datetime(date_created), rowid
+        ) AS seq FROM chat_messages
+    ) AS numbered WHERE numbered.message_rowid = chat_messages.rowid
+);
+UPDATE chats SET last_seq = (
+    SELECT COALESCE(MAX(seq), 0) FROM chat_messages WHERE chat_messages.chat_id = chats.chat_id
+);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chat_messages_seq ON chat_messages(chat_id, seq);
+";
+
+// Every edit keeps the text it replaced, chat_messages always holds the current one
+pub const CHAT_MESSAGE_EDITS_MIGRATION_SQL: &str = "
+ALTER TABLE chat_messages ADD COLUMN edited_at VARCHAR(32);
+CREATE TABLE IF NOT EXISTS chat_message_edits (
+    edit_id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_message_id VARCHAR(36) NOT NULL,
+    message VARCHAR(512),
+    date_created VARCHAR(32),
+    date_replaced VARCHAR(32),
+
+    FOREIGN KEY (chat_message_id) REFERENCES chat_messages(chat_message_id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_chat_message_edits_message ON chat_message_edits(chat_message_id);
+";
+
+// Deleted messages stay as tombstones without text so seqs and paging don't shift
+pub const CHAT_MESSAGE_TOMBSTONES_MIGRATION_SQL: &str = "
+ALTER TABLE chat_messages ADD COLUMN deleted_at VARCHAR(32);
+ALTER TABLE chat_messages ADD COLUMN deleted_by INTEGER REFERENCES users(user_id);
+ALTER TABLE chat_messages ADD COLUMN delete_reason VARCHAR(256);
+";
+
+pub const MAX_DELETE_REASON_LEN: usize = 256;
+
+// A message can answer another one (reply_to) and/or belong to a thread, thread_id being the
+// message that started it. Thread replies stay out of the chat's main history.
+pub const CHAT_MESSAGE_THREADS_MIGRATION_SQL: &str = "
+ALTER TABLE chat_messages ADD COLUMN reply_to VARCHAR(36) REFERENCES chat_messages(chat_message_id);
+ALTER TABLE chat_messages ADD COLUMN thread_id VARCHAR(36) REFERENCES chat_messages(chat_message_id);
+ALTER TABLE chat_messages ADD COLUMN reply_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE chat_messages ADD COLUMN last_reply_at VARCHAR(32);
+CREATE INDEX IF NOT EXISTS idx_chat_messages_thread ON chat_messages(chat_id, thread_id, seq);
+CREATE TABLE IF NOT EXISTS thread_followers (
+    chat_message_id VARCHAR(36) NOT NULL,
+    user_id INTEGER NOT NULL,
+
+    PRIMARY KEY (chat_message_id, user_id),
+    FOREIGN KEY (chat_message_id) REFERENCES chat_messages(chat_message_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id)
+);
+";
+
+// The reply preview is the first 100 characters of the answered message, read with subqueries
+// so every query can keep appending its own WHERE
+const CHAT_MESSAGE_SELECT_SQL: &str = "SELECT chat_message_id, user_id, message, date_created, client_msg_id, seq, edited_at, deleted_at, deleted_by, delete_reason, reply_to, thread_id, reply_count, last_reply_at,
+    (SELECT parent.user_id FROM chat_messages AS parent WHERE parent.chat_message_id = chat_messages.reply_to),
+    (SELECT substr(parent.message, 1, 100) FROM chat_messages AS parent WHERE parent.chat_message_id = chat_messages.reply_to),
+    (SELECT parent.deleted_at IS NOT NULL FROM chat_messages AS parent WHERE parent.chat_message_id = chat_messages.reply_to)
+    FROM chat_messages";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub id: String,
     pub message: String,
     pub date_created: String,
     pub user_id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_msg_id: Option<String>,
+    pub seq: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_by: Option<i64>,
+    // Only for moderators, see hide_delete_reason
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub delete_reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reply_preview: Option<ReplyPreview>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    // Only set on messages that started a thread
+    #[serde(skip_serializing_if = "is_zero")]
+    pub reply_count: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_reply_at: Option<String>,
+    // Only filled for history, see MessageReactionsTable::attach_reactions
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<ReactionCount>,
+    // Files sent with the message, see AttachmentsTable::attach_files
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+}
+
+fn is_zero(count: &i64) -> bool {
+    *count == 0
+}
+
+// The start of the answered message, enough for the client to quote it
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReplyPreview {
+    pub user_id: i64,
+    pub message: String,
+    pub deleted: bool,
+}
+
+impl ChatMessage {
+    pub fn hide_delete_reason(&mut self) {
+        self.delete_reason = None;
+    }
+}
+
+fn message_from_row(row: &Row) -> Result<ChatMessage, rusqlite::Error> {
+    Ok(ChatMessage {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        message: row.get(2)?,
+        date_created: row.get(3)?,
+        client_msg_id: row.get(4)?,
+        seq: row.get(5)?,
+        edited_at: row.get(6)?,
+        deleted_at: row.get(7)?,
+        deleted_by: row.get(8)?,
+        delete_reason: row.get(9)?,
+        reply_to: row.get(10)?,
+        thread_id: row.get(11)?,
+        reply_count: row.get(12)?,
+        last_reply_at: row.get(13)?,
+        reply_preview: match row.get::<_, Option<i64>>(14)? {
+            Some(user_id) => Some(ReplyPreview {
+                user_id,
+                message: row.get(15)?,
+                deleted: row.get(16)?,
+            }),
+            None => None,
+        },
+        reactions: Vec::new(),
+        attachments: Vec::new(),
+    })
+}
+
+// A text the message had before an edit, from date_created until date_replaced
+#[derive(Debug, Serialize)]
+pub struct ChatMessageEdit {
+    pub message: String,
+    pub date_created: String,
+    pub date_replaced: String,
+}
+
+pub struct EditChatMessage {
+    pub chat_id: String,
+    pub message_id: String,
+    pub user_id: i64,
+    pub message: String,
+    pub date_edited: String,
+    // Messages created before this date can't be edited anymore
+    pub editable_after: Option<String>,
+}
+
+impl EditChatMessage {
+    pub fn new(
+        chat_id: String,
+        message_id: String,
+        user_id: i64,
+        message: String,
+        edit_window: Option<Duration>,
+    ) -> Self {
+        let now = Utc::now();
+        Self {
+            chat_id,
+            message_id,
+            user_id,
+            message,
+            date_edited: format_date(now),
+            editable_after: edit_window
+                .and_then(|window| chrono::Duration::from_std(window).ok())
+                .and_then(|window| now.checked_sub_signed(window))
+                .map(format_date),
+        }
+    }
+}
+
+pub struct DeleteChatMessage {
+    pub chat_id: String,
+    pub message_id: String,
+    pub user_id: i64,
+    pub reason: Option<String>,
+    pub date_deleted: String,
+}
+
+impl DeleteChatMessage {
+    pub fn new(chat_id: String, message_id: String, user_id: i64, reason: Option<String>) -> Self {
+        Self {
+            chat_id,
+            message_id,
+            user_id,
+            reason,
+            date_deleted: format_date(Utc::now()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeleteOutcome {
+    // With the thread's root and its new counts when the message was a reply
+    Deleted(Box<ChatMessage>, Option<Box<ChatMessage>>),
+    NotFound,
+    Forbidden,
+}
+
+#[derive(Debug)]
+pub enum EditOutcome {
+    Edited(Box<ChatMessage>),
+    NotFound,
+    NotAuthor,
+    WindowClosed,
+}
+
+// Where a page of history starts, always a seq of the chat
+#[derive(Debug, Clone, Copy)]
+pub enum MessageCursor {
+    Latest,
+    Before(i64),
+    After(i64),
+    // The page holds the message itself and its neighbours on both sides
+    Around(i64),
+}
+
+#[derive(Debug, Serialize)]
+pub struct MessagePage {
+    pub messages: Vec<ChatMessage>,
+    pub has_more_before: bool,
+    pub has_more_after: bool,
+    // Pass as before= / after= to keep scrolling
+    pub prev_cursor: Option<i64>,
+    pub next_cursor: Option<i64>,
 }
 
 pub trait ChatMessagesTable {
-    fn insert_message(&self, chat_message: InsertChatMessage) -> Result<String, rusqlite::Error>;
+    fn insert_message(
+        &self,
+        chat_message: InsertChatMessage,
+    ) -> Result<ChatMessage, rusqlite::Error>;
+    fn get_client_message(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        client_msg_id: &str,
+    ) -> Result<Option<ChatMessage>, rusqlite::Error>;
     fn get_chat_messages(
         &self,
         chat_id: String,
         offset: usize,
     ) -> Result<Vec<ChatMessage>, rusqlite::Error>;
     fn get_last_chat_message(&self, chat_id: String) -> Result<ChatMessage, rusqlite::Error>;
+    fn get_message_seq(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<i64>, rusqlite::Error>;
+    fn get_chat_message(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<ChatMessage>, rusqlite::Error>;
+    fn get_messages_after(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        seq: i64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, rusqlite::Error>;
+    fn get_messages_before(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        seq: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, rusqlite::Error>;
+    fn get_message_page(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        cursor: MessageCursor,
+        limit: usize,
+    ) -> Result<MessagePage, rusqlite::Error>;
+    fn follow_thread(&self, thread_id: &str, user_id: i64) -> Result<usize, rusqlite::Error>;
+    fn unfollow_thread(&self, thread_id: &str, user_id: i64) -> Result<usize, rusqlite::Error>;
+    fn get_thread_followers(&self, thread_id: &str) -> Result<Vec<i64>, rusqlite::Error>;
+    fn edit_message(&self, edit: EditChatMessage) -> Result<EditOutcome, rusqlite::Error>;
+    fn delete_message(&self, delete: DeleteChatMessage) -> Result<DeleteOutcome, rusqlite::Error>;
+    fn get_message_edits(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<Vec<ChatMessageEdit>>, rusqlite::Error>;
 }
 
-pub struct InsertChatMessage<'t> {
+pub struct InsertChatMessage {
     pub chat_id: String,
     pub user_id: i64,
-    pub message: &'t str,
+    pub message: String,
     pub date_created: String,
+    pub client_msg_id: Option<String>,
+    pub reply_to: Option<String>,
+    pub thread_id: Option<String>,
+    // Uploads of user_id to the chat that were not sent yet
+    pub attachments: Vec<String>,
 }
 impl ChatMessagesTable for Database {
-    fn insert_message(&self, chat_message: InsertChatMessage) -> Result<String, rusqlite::Error> {
+    fn insert_message(
+        &self,
+        chat_message: InsertChatMessage,
+    ) -> Result<ChatMessage, rusqlite::Error> {
         let message_id = Uuid::new_v4().to_string();
-        let res = self.conn.execute(
-            "INSERT INTO chat_messages (chat_message_id, chat_id, user_id, message, date_created) VALUES (?, ?, ?, ?, ?)",
+        let tx = self.conn.unchecked_transaction()?;
+        // QueryReturnedNoRows when the chat does not exist (anymore)
+        let seq: i64 = tx.query_row(
+            "UPDATE chats SET last_seq = last_seq + 1 WHERE chat_id = ? RETURNING last_seq",
+            params![chat_message.chat_id],
+            |row| row.get(0),
+        )?;
+        tx.execute(
+            "INSERT INTO chat_messages (chat_message_id, chat_id, user_id, message, date_created, client_msg_id, seq, reply_to, thread_id) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
             params![
                 message_id,
                 chat_message.chat_id,
                 chat_message.user_id,
                 chat_message.message,
-                chat_message.date_created
+                chat_message.date_created,
+                chat_message.client_msg_id,
+                seq,
+                chat_message.reply_to,
+                chat_message.thread_id
             ],
-        );
-        if let Err(err) = res {
-            return Err(err);
+        )?;
+        link_attachments(
+            &tx,
+            &chat_message.chat_id,
+            &message_id,
+            chat_message.user_id,
+            &chat_message.attachments,
+        )?;
+        // Replying in a thread follows it, so does whoever started it
+        if let Some(thread_id) = &chat_message.thread_id {
+            tx.execute(
+                "UPDATE chat_messages SET reply_count = reply_count + 1, last_reply_at = ? WHERE chat_message_id = ?",
+                params![chat_message.date_created, thread_id],
+            )?;
+            tx.execute(
+                "INSERT OR IGNORE INTO thread_followers (chat_message_id, user_id) SELECT chat_message_id, user_id FROM chat_messages WHERE chat_message_id = ?",
+                params![thread_id],
+            )?;
+            self.follow_thread(thread_id, chat_message.user_id)?;
         }
-        Ok(message_id)
+        let mut message = tx.query_row(
+            &format!("{} WHERE chat_message_id = ?", CHAT_MESSAGE_SELECT_SQL),
+            params![message_id],
+            message_from_row,
+        )?;
+        self.attach_files(std::slice::from_mut(&mut message))?;
+        tx.commit()?;
+        Ok(message)
     }
 
-    fn get_last_chat_message(&self, chat_id: String) -> Result<ChatMessage, rusqlite::Error> {
-        let mut stmt = self.conn.prepare("SELECT chat_message_id, user_id, message, date_created FROM chat_messages WHERE chat_id = ? ORDER BY datetime(date_created) DESC LIMIT 1")?;
-        let query = stmt.query_row(params![chat_id], |row| {
-            Ok(ChatMessage {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                message: row.get(2)?,
-                date_created: row.get(3)?,
-            })
-        })?;
+    fn get_client_message(
+        &self,
+        chat_id: &str,
+        user_id: i64,
+        client_msg_id: &str,
+    ) -> Result<Option<ChatMessage>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                &format!(
+                    "{} WHERE chat_id = ? AND user_id = ? AND client_msg_id = ?",
+                    CHAT_MESSAGE_SELECT_SQL
+                ),
+                params![chat_id, user_id, client_msg_id],
+                message_from_row,
+            )
+            .optional()
+    }
 
-        Ok(query)
+    fn get_last_chat_message(&self, chat_id: String) -> Result<ChatMessage, rusqlite::Error> {
+        self.conn.query_row(
+            &format!(
+                "{} WHERE chat_id = ? AND thread_id IS NULL AND deleted_at IS NULL ORDER BY seq DESC LIMIT 1",
+                CHAT_MESSAGE_SELECT_SQL
+            ),
+            params![chat_id],
+            message_from_row,
+        )
     }
 
     fn get_chat_messages(
@@ -78,23 +443,461 @@ impl ChatMessagesTable for Database {
         offset: usize,
     ) -> Result<Vec<ChatMessage>, rusqlite::Error> {
         let mut messages = Vec::new();
-        let mut stmt = self
-            .conn
-            .prepare("SELECT chat_message_id, user_id, message, date_created FROM chat_messages WHERE chat_id = ? ORDER BY datetime(date_created) DESC LIMIT 10 OFFSET ?")?;
-
-        let query = stmt.query_map(params![chat_id, offset], |row| {
-            Ok(ChatMessage {
-                id: row.get(0)?,
-                user_id: row.get(1)?,
-                message: row.get(2)?,
-                date_created: row.get(3)?,
-            })
-        })?;
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE chat_id = ? AND thread_id IS NULL ORDER BY seq DESC LIMIT 10 OFFSET ?",
+            CHAT_MESSAGE_SELECT_SQL
+        ))?;
+
+        let query = stmt.query_map(params![chat_id, offset], message_from_row)?;
 
         for message in query {
             messages.push(message?);
         }
         messages.reverse();
+        self.attach_files(&mut messages)?;
         Ok(messages)
     }
+
+    // None when the message is not part of the chat
+    fn get_message_seq(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<i64>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                "SELECT seq FROM chat_messages WHERE chat_id = ? AND chat_message_id = ?",
+                params![chat_id, message_id],
+                |row| row.get(0),
+            )
+            .optional()
+    }
+
+    fn get_chat_message(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<ChatMessage>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                &format!(
+                    "{} WHERE chat_id = ? AND chat_message_id = ?",
+                    CHAT_MESSAGE_SELECT_SQL
+                ),
+                params![chat_id, message_id],
+                message_from_row,
+            )
+            .optional()
+    }
+
+    // thread_id None reads the main history, without thread replies
+    fn get_messages_after(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        seq: i64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE chat_id = ? AND thread_id IS ? AND seq > ? ORDER BY seq LIMIT ?",
+            CHAT_MESSAGE_SELECT_SQL
+        ))?;
+        let mut messages = stmt
+            .query_map(params![chat_id, thread_id, seq, limit], message_from_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        self.attach_files(&mut messages)?;
+        Ok(messages)
+    }
+
+    // Oldest first, None starts from the newest message
+    fn get_messages_before(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        seq: Option<i64>,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>, rusqlite::Error> {
+        let mut stmt = self.conn.prepare(&format!(
+            "{} WHERE chat_id = ? AND thread_id IS ? AND seq < ? ORDER BY seq DESC LIMIT ?",
+            CHAT_MESSAGE_SELECT_SQL
+        ))?;
+        let mut messages = stmt
+            .query_map(
+                params![chat_id, thread_id, seq.unwrap_or(i64::MAX), limit],
+                message_from_row,
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        messages.reverse();
+        self.attach_files(&mut messages)?;
+        Ok(messages)
+    }
+
+    // Every side is read with one extra row to know if there is more past the page
+    fn get_message_page(
+        &self,
+        chat_id: &str,
+        thread_id: Option<&str>,
+        cursor: MessageCursor,
+        limit: usize,
+    ) -> Result<MessagePage, rusqlite::Error> {
+        let (mut before, mut after) = match cursor {
+            MessageCursor::Latest => (
+                self.get_messages_before(chat_id, thread_id, None, limit + 1)?,
+                vec![],
+            ),
+            MessageCursor::Before(seq) => (
+                self.get_messages_before(chat_id, thread_id, Some(seq), limit + 1)?,
+                vec![],
+            ),
+            MessageCursor::After(seq) => (
+                vec![],
+                self.get_messages_after(chat_id, thread_id, seq, limit + 1)?,
+            ),
+            MessageCursor::Around(seq) => (
+                self.get_messages_before(
+                    chat_id,
+                    thread_id,
+                    Some(seq.saturating_add(1)),
+                    limit + 1,
+                )?,
+                self.get_messages_after(chat_id, thread_id, seq, limit + 1)?,
+            ),
+        };
+
+        // Around splits the page in half, a side that runs out leaves its room to the other one
+        let mut after_len = after.len().min(limit / 2);
+        let before_len = before.len().min(limit - after_len);
+        after_len = after.len().min(limit - before_len);
+
+        let mut has_more_before = before.len() > before_len;
+        before.drain(..before.len() - before_len);
+        let mut has_more_after = after.len() > after_len;
+        after.truncate(after_len);
+
+        // The side that was not read is only known by looking past the cursor
+        match cursor {
+            MessageCursor::Latest | MessageCursor::Around(_) => (),
+            MessageCursor::Before(seq) => {
+                has_more_after = has_messages(self, chat_id, thread_id, "seq >= ?", seq)?
+            }
+            MessageCursor::After(seq) => {
+                has_more_before = has_messages(self, chat_id, thread_id, "seq <= ?", seq)?
+            }
+        }
+
+        before.append(&mut after);
+        let messages = before;
+        // An empty page still points back to where the cursor was
+        let (first_seq, last_seq) = match (messages.first(), messages.last(), cursor) {
+            (Some(first), Some(last), _) => (first.seq, last.seq),
+            (_, _, MessageCursor::Before(seq)) => (seq, seq.saturating_sub(1)),
+            (_, _, MessageCursor::After(seq)) => (seq.saturating_add(1), seq),
+            _ => (0, 0),
+        };
+        Ok(MessagePage {
+            prev_cursor: has_more_before.then_some(first_seq),
+            next_cursor: has_more_after.then_some(last_seq),
+            messages,
+            has_more_before,
+            has_more_after,
+        })
+    }
+
+    // Only the author edits, and only while the window is open. Dates share one format so they
+    // compare as strings.
+    fn edit_message(&self, edit: EditChatMessage) -> Result<EditOutcome, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let Some(current) = tx
+            .query_row(
+                &format!(
+                    "{} WHERE chat_id = ? AND chat_message_id = ?",
+                    CHAT_MESSAGE_SELECT_SQL
+                ),
+                params![edit.chat_id, edit.message_id],
+                message_from_row,
+            )
+            .optional()?
+        else {
+            return Ok(EditOutcome::NotFound);
+        };
+        if current.deleted_at.is_some() {
+            return Ok(EditOutcome::NotFound);
+        }
+        if current.user_id != edit.user_id {
+            return Ok(EditOutcome::NotAuthor);
+        }
+        if edit
+            .editable_after
+            .is_some_and(|editable_after| current.date_created < editable_after)
+        {
+            return Ok(EditOutcome::WindowClosed);
+        }
+
+        tx.execute(
+            "INSERT INTO chat_message_edits (chat_message_id, message, date_created, date_replaced) VALUES (?, ?, ?, ?)",
+            params![
+                current.id,
+                current.message,
+                current.edited_at.as_ref().unwrap_or(&current.date_created),
+                edit.date_edited
+            ],
+        )?;
+        tx.execute(
+            "UPDATE chat_messages SET message = ?, edited_at = ? WHERE chat_message_id = ?",
+            params![edit.message, edit.date_edited, current.id],
+        )?;
+        tx.commit()?;
+        Ok(EditOutcome::Edited(Box::new(ChatMessage {
+            message: edit.message,
+            edited_at: Some(edit.date_edited),
+            ..current
+        })))
+    }
+
+    // Authors delete their own messages, admins and owners anyone's. The text and its past
+    // revisions and attachments are dropped, the row stays behind as a tombstone.
+    fn delete_message(&self, delete: DeleteChatMessage) -> Result<DeleteOutcome, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let Some(current) = tx
+            .query_row(
+                &format!(
+                    "{} WHERE chat_id = ? AND chat_message_id = ? AND deleted_at IS NULL",
+                    CHAT_MESSAGE_SELECT_SQL
+                ),
+                params![delete.chat_id, delete.message_id],
+                message_from_row,
+            )
+            .optional()?
+        else {
+            return Ok(DeleteOutcome::NotFound);
+        };
+        if current.user_id != delete.user_id {
+            let role = self.get_chat_role(&delete.chat_id, delete.user_id)?;
+            if role.is_none_or(|role| role < ChatRole::ADMIN) {
+                return Ok(DeleteOutcome::Forbidden);
+            }
+        }
+
+        tx.execute(
+            "UPDATE chat_messages SET message = '', deleted_at = ?, deleted_by = ?, delete_reason = ? WHERE chat_message_id = ?",
+            params![delete.date_deleted, delete.user_id, delete.reason, current.id],
+        )?;
+        tx.execute(
+            "DELETE FROM chat_message_edits WHERE chat_message_id = ?",
+            params![current.id],
+        )?;
+        tx.execute(
+            "DELETE FROM message_reactions WHERE chat_message_id = ?",
+            params![current.id],
+        )?;
+        // Other attachments may point to the same contents, the cleanup sweep removes the files
+        // once none does
+        tx.execute(
+            "DELETE FROM attachments WHERE chat_message_id = ?",
+            params![current.id],
+        )?;
+        // Counted again instead of decremented so last_reply_at goes back to the newest reply left
+        if let Some(thread_id) = &current.thread_id {
+            tx.execute(
+                "UPDATE chat_messages SET reply_count = (SELECT COUNT(*) FROM chat_messages WHERE thread_id = ?1 AND deleted_at IS NULL), last_reply_at = (SELECT MAX(date_created) FROM chat_messages WHERE thread_id = ?1 AND deleted_at IS NULL) WHERE chat_message_id = ?1",
+                params![thread_id],
+            )?;
+        }
+        tx.commit()?;
+        let root = match &current.thread_id {
+            Some(thread_id) => self.get_chat_message(&delete.chat_id, thread_id)?,
+            None => None,
+        };
+        Ok(DeleteOutcome::Deleted(
+            Box::new(ChatMessage {
+                message: String::new(),
+                deleted_at: Some(delete.date_deleted),
+                deleted_by: Some(delete.user_id),
+                delete_reason: delete.reason,
+                ..current
+            }),
+            root.map(Box::new),
+        ))
+    }
+
+    // Oldest first, None when the message is not part of the chat
+    fn get_message_edits(
+        &self,
+        chat_id: &str,
+        message_id: &str,
+    ) -> Result<Option<Vec<ChatMessageEdit>>, rusqlite::Error> {
+        if self.get_message_seq(chat_id, message_id)?.is_none() {
+            return Ok(None);
+        }
+        let mut stmt = self.conn.prepare(
+            "SELECT message, date_created, date_replaced FROM chat_message_edits WHERE chat_message_id = ? ORDER BY edit_id",
+        )?;
+        let edits = stmt
+            .query_map(params![message_id], |row| {
+                Ok(ChatMessageEdit {
+                    message: row.get(0)?,
+                    date_created: row.get(1)?,
+                    date_replaced: row.get(2)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Some(edits))
+    }
+
+    fn follow_thread(&self, thread_id: &str, user_id: i64) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "INSERT OR IGNORE INTO thread_followers (chat_message_id, user_id) VALUES (?, ?)",
+            params![thread_id, user_id],
+        )
+    }
+
+    fn unfollow_thread(&self, thread_id: &str, user_id: i64) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM thread_followers WHERE chat_message_id = ? AND user_id = ?",
+            params![thread_id, user_id],
+        )
+    }
+
+    fn get_thread_followers(&self, thread_id: &str) -> Result<Vec<i64>, rusqlite::Error> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT user_id FROM thread_followers WHERE chat_message_id = ?")?;
+        let followers = stmt
+            .query_map(params![thread_id], |row| row.get(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(followers)
+    }
+}
+
+// condition compares seq against the bound parameter
+fn has_messages(
+    db: &Database,
+    chat_id: &str,
+    thread_id: Option<&str>,
+    condition: &str,
+    seq: i64,
+) -> Result<bool, rusqlite::Error> {
+    db.conn.query_row(
+        &format!(
+            "SELECT EXISTS(SELECT 1 FROM chat_messages WHERE chat_id = ? AND thread_id IS ? AND {})",
+            condition
+        ),
+        params![chat_id, thread_id, seq],
+        |row| row.get(0),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{test_database, user_db::UserTable};
+
+    // A chat with messages seq 1 to count
+    fn chat_with_messages(count: usize) -> (Database, String) {
+        let db = test_database();
+        let user_id = db.create_user("a".into(), "x".into()).unwrap();
+        let chat_id = db.create_chat("c", user_id).unwrap();
+        for i in 1..=count {
+            db.insert_message(InsertChatMessage {
+                chat_id: chat_id.clone(),
+                user_id,
+                message: format!("m{}", i),
+                date_created: format_date(Utc::now()),
+                client_msg_id: None,
+                reply_to: None,
+                thread_id: None,
+                attachments: vec![],
+            })
+            .unwrap();
+        }
+        (db, chat_id)
+    }
+
+    fn page(db: &Database, chat_id: &str, cursor: MessageCursor, limit: usize) -> MessagePage {
+        db.get_message_page(chat_id, None, cursor, limit).unwrap()
+    }
+
+    fn seqs(page: &MessagePage) -> Vec<i64> {
+        page.messages.iter().map(|message| message.seq).collect()
+    }
+
+    #[test]
+    fn latest_is_the_newest_messages() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::Latest, 3);
+        assert_eq!(seqs(&page), vec![8, 9, 10]);
+        assert!(page.has_more_before);
+        assert!(!page.has_more_after);
+        assert_eq!(page.prev_cursor, Some(8));
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn before_excludes_the_cursor() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::Before(5), 3);
+        assert_eq!(seqs(&page), vec![2, 3, 4]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+        assert_eq!(page.prev_cursor, Some(2));
+        assert_eq!(page.next_cursor, Some(4));
+
+        let page = self::page(&db, &chat_id, MessageCursor::Before(3), 3);
+        assert_eq!(seqs(&page), vec![1, 2]);
+        assert!(!page.has_more_before);
+        assert_eq!(page.prev_cursor, None);
+    }
+
+    #[test]
+    fn after_excludes_the_cursor() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::After(4), 3);
+        assert_eq!(seqs(&page), vec![5, 6, 7]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+        assert_eq!(page.prev_cursor, Some(5));
+        assert_eq!(page.next_cursor, Some(7));
+
+        let page = self::page(&db, &chat_id, MessageCursor::After(7), 3);
+        assert_eq!(seqs(&page), vec![8, 9, 10]);
+        assert!(!page.has_more_after);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn around_splits_the_page() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::Around(5), 4);
+        assert_eq!(seqs(&page), vec![4, 5, 6, 7]);
+        assert!(page.has_more_before);
+        assert!(page.has_more_after);
+    }
+
+    #[test]
+    fn around_gives_the_room_of_a_short_side_to_the_other() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::Around(9), 4);
+        assert_eq!(seqs(&page), vec![7, 8, 9, 10]);
+        assert!(page.has_more_before);
+        assert!(!page.has_more_after);
+
+        let page = self::page(&db, &chat_id, MessageCursor::Around(1), 4);
+        assert_eq!(seqs(&page), vec![1, 2, 3, 4]);
+        assert!(!page.has_more_before);
+        assert!(page.has_more_after);
+    }
+
+    #[test]
+    fn empty_pages_point_back_to_the_cursor() {
+        let (db, chat_id) = chat_with_messages(10);
+        let page = page(&db, &chat_id, MessageCursor::After(10), 3);
+        assert!(page.messages.is_empty());
+        assert_eq!(page.prev_cursor, Some(11));
+        assert_eq!(page.next_cursor, None);
+
+        let page = self::page(&db, &chat_id, MessageCursor::Before(1), 3);
+        assert!(page.messages.is_empty());
+        assert_eq!(page.prev_cursor, None);
+        assert_eq!(page.next_cursor, Some(0));
+    }
 }
@@ -0,0 +1,166 @@
+use rusqlite::{params, params_from_iter, types::Value, OptionalExtension};
+use serde::{Deserialize, Serialize};
+
+use super::{chat_message_db::ChatMessage, Database};
+
+// One row per user and emoji, reacting twice with the same emoji does nothing
+pub const MESSAGE_REACTIONS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS message_reactions (
+    chat_message_id VARCHAR(36) NOT NULL,
+    user_id INTEGER NOT NULL,
+    emoji VARCHAR(32) NOT NULL,
+    date_created VARCHAR(32),
+
+    PRIMARY KEY (chat_message_id, user_id, emoji),
+    FOREIGN KEY (chat_message_id) REFERENCES chat_messages(chat_message_id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users(user_id)
+);";
+
+// Bytes, a single emoji with modifiers and joiners fits
+pub const MAX_EMOJI_LEN: usize = 32;
+
+pub fn valid_emoji(emoji: &str) -> bool {
+    !emoji.is_empty()
+        && emoji.len() <= MAX_EMOJI_LEN
+        && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+// How many users reacted with the emoji, in the order the emojis were first used
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: i64,
+    // Whether the user reading the history is one of them
+    pub me: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Reaction {
+    pub chat_id: String,
+    pub message_id: String,
+    pub user_id: i64,
+    pub emoji: String,
+}
+
+#[derive(Debug)]
+pub enum ReactionOutcome {
+    // Both carry how many users react with the emoji afterwards
+    Changed(i64),
+    // Already reacted, or nothing to remove
+    Unchanged(i64),
+    NotFound,
+}
+
+pub trait MessageReactionsTable {
+    fn add_reaction(
+        &self,
+        reaction: &Reaction,
+        date_created: &str,
+    ) -> Result<ReactionOutcome, rusqlite::Error>;
+    fn remove_reaction(&self, reaction: &Reaction) -> Result<ReactionOutcome, rusqlite::Error>;
+    fn attach_reactions(
+        &self,
+        messages: &mut [ChatMessage],
+        viewer_id: i64,
+    ) -> Result<(), rusqlite::Error>;
+}
+
+impl MessageReactionsTable for Database {
+    // Deleted messages can't get reactions
+    fn add_reaction(
+        &self,
+        reaction: &Reaction,
+        date_created: &str,
+    ) -> Result<ReactionOutcome, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM chat_messages WHERE chat_id = ? AND chat_message_id = ? AND deleted_at IS NULL",
+                params![reaction.chat_id, reaction.message_id],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if exists.is_none() {
+            return Ok(ReactionOutcome::NotFound);
+        }
+        let inserted = tx.execute(
+            "INSERT OR IGNORE INTO message_reactions (chat_message_id, user_id, emoji, date_created) VALUES (?, ?, ?, ?)",
+            params![reaction.message_id, reaction.user_id, reaction.emoji, date_created],
+        )?;
+        let count = count_reactions(&tx, reaction)?;
+        tx.commit()?;
+        if inserted == 0 {
+            return Ok(ReactionOutcome::Unchanged(count));
+        }
+        Ok(ReactionOutcome::Changed(count))
+    }
+
+    fn remove_reaction(&self, reaction: &Reaction) -> Result<ReactionOutcome, rusqlite::Error> {
+        let tx = self.conn.unchecked_transaction()?;
+        let exists = tx
+            .query_row(
+                "SELECT 1 FROM chat_messages WHERE chat_id = ? AND chat_message_id = ?",
+                params![reaction.chat_id, reaction.message_id],
+                |_| Ok(()),
+            )
+            .optional()?;
+        if exists.is_none() {
+            return Ok(ReactionOutcome::NotFound);
+        }
+        let removed = tx.execute(
+            "DELETE FROM message_reactions WHERE chat_message_id = ? AND user_id = ? AND emoji = ?",
+            params![reaction.message_id, reaction.user_id, reaction.emoji],
+        )?;
+        let count = count_reactions(&tx, reaction)?;
+        tx.commit()?;
+        if removed == 0 {
+            return Ok(ReactionOutcome::Unchanged(count));
+        }
+        Ok(ReactionOutcome::Changed(count))
+    }
+
+    // Fills ChatMessage::reactions for a page of history in a single query
+    fn attach_reactions(
+        &self,
+        messages: &mut [ChatMessage],
+        viewer_id: i64,
+    ) -> Result<(), rusqlite::Error> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let placeholders = vec!["?"; messages.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT chat_message_id, emoji, COUNT(*), MAX(user_id = ?) FROM message_reactions
+                WHERE chat_message_id IN ({})
+                GROUP BY chat_message_id, emoji ORDER BY MIN(rowid)",
+            placeholders
+        ))?;
+        let ids = messages
+            .iter()
+            .map(|message| Value::Text(message.id.clone()));
+        let params = std::iter::once(Value::Integer(viewer_id)).chain(ids);
+        let mut rows = stmt.query(params_from_iter(params))?;
+        while let Some(row) = rows.next()? {
+            let message_id: String = row.get(0)?;
+            let Some(message) = messages.iter_mut().find(|message| message.id == message_id) else {
+                continue;
+            };
+            message.reactions.push(ReactionCount {
+                emoji: row.get(1)?,
+                count: row.get(2)?,
+                me: row.get(3)?,
+            });
+        }
+        Ok(())
+    }
+}
+
+fn count_reactions(
+    conn: &rusqlite::Connection,
+    reaction: &Reaction,
+) -> Result<i64, rusqlite::Error> {
+    conn.query_row(
+        "SELECT COUNT(*) FROM message_reactions WHERE chat_message_id = ? AND emoji = ?",
+        params![reaction.message_id, reaction.emoji],
+        |row| row.get(0),
+    )
+}
@@ -0,0 +1,403 @@
+use chrono::{Days, NaiveDate};
+use rusqlite::{params_from_iter, types::Value};
+use serde::Serialize;
+
+use super::Database;
+
+// External content index over chat_messages.message, the triggers keep it in sync with sends,
+// edits and deletes (a tombstone blanks the text so it stops matching). It is keyed by the
+// rowid of chat_messages, run INSERT INTO chat_messages_fts(chat_messages_fts) VALUES ('rebuild')
+// after a VACUUM.
+pub const MESSAGE_SEARCH_MIGRATION_SQL: &str = "CREATE VIRTUAL TABLE chat_messages_fts USING fts5(
+    message,
+    content = 'chat_messages',
+    tokenize = 'unicode61 remove_diacritics 2'
+);
+CREATE TRIGGER chat_messages_fts_insert AFTER INSERT ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (rowid, message) VALUES (new.rowid, new.message);
+END;
+CREATE TRIGGER chat_messages_fts_delete AFTER DELETE ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, message) VALUES ('delete', old.rowid, old.message);
+END;
+CREATE TRIGGER chat_messages_fts_update AFTER UPDATE OF message ON chat_messages BEGIN
+    INSERT INTO chat_messages_fts (chat_messages_fts, rowid, message) VALUES ('delete', old.rowid, old.message);
+    INSERT INTO chat_messages_fts (rowid, message) VALUES (new.rowid, new.message);
+END;
+INSERT INTO chat_messages_fts (chat_messages_fts) VALUES ('rebuild');";
+
+// snippet() wraps matches in these, they become <mark> once the text around them is escaped
+const MATCH_START: char = '\u{E000}';
+const MATCH_END: char = '\u{E001}';
+const SNIPPET_TOKENS: u8 = 16;
+
+// What the user typed in the search box, free text plus from:, in:, before:, after: and has:
+#[derive(Debug, Default)]
+pub struct SearchQuery {
+    // FTS5 expression, None when only operators were given
+    text: Option<String>,
+    from: Option<String>,
+    // Id or name of one of the user's chats
+    chat: Option<String>,
+    before: Option<String>,
+    after: Option<String>,
+    has_attachment: bool,
+}
+
+impl SearchQuery {
+    pub fn parse(input: &str) -> Result<SearchQuery, String> {
+        let mut query = SearchQuery::default();
+        let mut terms = Vec::new();
+        for (token, quoted) in tokenize(input) {
+            let operator = match token.split_once(':') {
+                Some((key, value)) if !quoted && !value.is_empty() => Some((key, value)),
+                _ => None,
+            };
+            match operator {
+                Some(("from", nick)) => query.from = Some(nick.to_string()),
+                Some(("in", chat)) => query.chat = Some(chat.to_string()),
+                Some(("before", date)) => {
+                    let date = parse_date("before", date)?;
+                    query.before = Some(date.format("%Y-%m-%d").to_string());
+                }
+                Some(("after", date)) => {
+                    // after: a day means from the start of the next one
+                    let date = parse_date("after", date)?;
+                    let next = date.checked_add_days(Days::new(1)).unwrap_or(date);
+                    query.after = Some(next.format("%Y-%m-%d").to_string());
+                }
+                Some(("has", "attachment" | "attachments")) => query.has_attachment = true,
+                Some(("has", other)) => {
+                    return Err(format!("has:{} nao existe, use has:attachment", other))
+                }
+                _ => terms.push(fts_term(&token, quoted)),
+            }
+        }
+        terms.retain(|term| !term.is_empty());
+        if !terms.is_empty() {
+            query.text = Some(terms.join(" "));
+        }
+        if query.text.is_none()
+            && query.from.is_none()
+            && query.chat.is_none()
+            && query.before.is_none()
+            && query.after.is_none()
+            && !query.has_attachment
+        {
+            return Err("A busca esta vazia".into());
+        }
+        Ok(query)
+    }
+}
+
+// Splits on whitespace, "double quotes" keep spaces together (also in in:"chat name").
+// The bool tells whether the token started with a quote, those are never operators.
+fn tokenize(input: &str) -> Vec<(String, bool)> {
+    let mut tokens = Vec::new();
+    let (mut current, mut in_quotes, mut quoted) = (String::new(), false, false);
+    for c in input.chars() {
+        match c {
+            '"' => {
+                if current.is_empty() && !in_quotes {
+                    quoted = true;
+                }
+                in_quotes = !in_quotes;
+            }
+            c if c.is_whitespace() && !in_quotes => {
+                if !current.is_empty() {
+                    tokens.push((std::mem::take(&mut current), quoted));
+                }
+                quoted = false;
+            }
+            c => current.push(c),
+        }
+    }
+    if !current.is_empty() {
+        tokens.push((current, quoted));
+    }
+    tokens
+}
+
+fn parse_date(operator: &str, date: &str) -> Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| format!("Data invalida em {}:{}, use AAAA-MM-DD", operator, date))
+}
+
+// Every term becomes an FTS5 string so nothing the user types is read as FTS syntax,
+// a trailing * on an unquoted word still searches by prefix
+fn fts_term(token: &str, quoted: bool) -> String {
+    let (word, prefix) = match token.strip_suffix('*') {
+        Some(word) if !quoted => (word, true),
+        _ => (token, false),
+    };
+    let word = word.trim();
+    if word.is_empty() {
+        return String::new();
+    }
+    let escaped = format!("\"{}\"", word.replace('"', "\"\""));
+    if prefix {
+        escaped + "*"
+    } else {
+        escaped
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    pub chat_id: String,
+    pub message_id: String,
+    // Pass as ?around= to /chat/messages/{chat_id}, or to the thread's history when thread_id is set
+    pub seq: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread_id: Option<String>,
+    pub user_id: i64,
+    pub user_nick: Option<String>,
+    pub date_created: String,
+    // HTML escaped, matches inside <mark></mark>
+    pub snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchPage {
+    pub results: Vec<SearchHit>,
+    // Newest first, pass as ?cursor= for the next page. It is the message_id of the last result,
+    // rowids can change with a VACUUM.
+    pub next_cursor: Option<String>,
+}
+
+pub trait MessageSearchTable {
+    // Only looks at chats user_id is a member of
+    fn search_messages(
+        &self,
+        user_id: i64,
+        query: &SearchQuery,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SearchPage, rusqlite::Error>;
+}
+
+impl MessageSearchTable for Database {
+    fn search_messages(
+        &self,
+        user_id: i64,
+        query: &SearchQuery,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<SearchPage, rusqlite::Error> {
+        let snippet = match query.text {
+            Some(_) => format!(
+                "snippet(chat_messages_fts, 0, '{}', '{}', '...', {})",
+                MATCH_START, MATCH_END, SNIPPET_TOKENS
+            ),
+            None => "substr(m.message, 1, 100)".to_string(),
+        };
+        let mut sql = format!(
+            "SELECT m.chat_id, m.chat_message_id, m.seq, m.thread_id, m.user_id, u.user_nick,
+                m.date_created, {snippet}
+            FROM chat_messages m"
+        );
+        let mut params = vec![Value::Integer(user_id)];
+        if query.text.is_some() {
+            sql.push_str(" INNER JOIN chat_messages_fts ON chat_messages_fts.rowid = m.rowid");
+        }
+        sql.push_str(
+            " INNER JOIN chats c ON c.chat_id = m.chat_id
+            LEFT JOIN users u ON u.user_id = m.user_id
+            WHERE m.chat_id IN (SELECT chat_id FROM chat_users WHERE user_id = ?)
+            AND m.deleted_at IS NULL",
+        );
+        if let Some(text) = &query.text {
+            sql.push_str(" AND chat_messages_fts MATCH ?");
+            params.push(Value::Text(text.clone()));
+        }
+        if let Some(from) = &query.from {
+            sql.push_str(" AND u.user_nick = ? COLLATE NOCASE");
+            params.push(Value::Text(from.clone()));
+        }
+        if let Some(chat) = &query.chat {
+            sql.push_str(" AND (c.chat_id = ? OR c.chat_name = ? COLLATE NOCASE)");
+            params.push(Value::Text(chat.clone()));
+            params.push(Value::Text(chat.clone()));
+        }
+        if let Some(before) = &query.before {
+            sql.push_str(" AND m.date_created < ?");
+            params.push(Value::Text(before.clone()));
+        }
+        if let Some(after) = &query.after {
+            sql.push_str(" AND m.date_created >= ?");
+            params.push(Value::Text(after.clone()));
+        }
+        if query.has_attachment {
+            sql.push_str(
+                " AND EXISTS (SELECT 1 FROM attachments a WHERE a.chat_message_id = m.chat_message_id)",
+            );
+        }
+        if let Some(cursor) = cursor {
+            sql.push_str(
+                " AND (m.date_created, m.chat_message_id) <
+                (SELECT date_created, chat_message_id FROM chat_messages WHERE chat_message_id = ?)",
+            );
+            params.push(Value::Text(cursor.to_string()));
+        }
+        sql.push_str(" ORDER BY m.date_created DESC, m.chat_message_id DESC LIMIT ?");
+        // One more than asked to know whether there is a next page
+        params.push(Value::Integer(limit as i64 + 1));
+
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params_from_iter(params), |row| {
+            let snippet: Option<String> = row.get(7)?;
+            Ok(SearchHit {
+                chat_id: row.get(0)?,
+                message_id: row.get(1)?,
+                seq: row.get(2)?,
+                thread_id: row.get(3)?,
+                user_id: row.get(4)?,
+                user_nick: row.get(5)?,
+                date_created: row.get(6)?,
+                snippet: highlight(&snippet.unwrap_or_default()),
+            })
+        })?;
+        let mut results = rows.collect::<Result<Vec<_>, _>>()?;
+        let next_cursor = if results.len() > limit {
+            results.truncate(limit);
+            results.last().map(|hit| hit.message_id.clone())
+        } else {
+            None
+        };
+        Ok(SearchPage {
+            results,
+            next_cursor,
+        })
+    }
+}
+
+fn highlight(snippet: &str) -> String {
+    let mut html = String::with_capacity(snippet.len());
+    for c in snippet.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{
+        chat_db::ChatTable,
+        chat_message_db::{ChatMessagesTable, InsertChatMessage},
+        test_database,
+        user_db::UserTable,
+    };
+
+    #[test]
+    fn parse_reads_operators_and_free_text() {
+        let query =
+            SearchQuery::parse("hello from:ana in:\"my chat\" has:attachment world*").unwrap();
+        assert_eq!(query.text.as_deref(), Some("\"hello\" \"world\"*"));
+        assert_eq!(query.from.as_deref(), Some("ana"));
+        assert_eq!(query.chat.as_deref(), Some("my chat"));
+        assert!(query.has_attachment);
+    }
+
+    #[test]
+    fn parse_moves_after_to_the_next_day() {
+        let query = SearchQuery::parse("before:2024-03-01 after:2024-02-28").unwrap();
+        assert_eq!(query.before.as_deref(), Some("2024-03-01"));
+        assert_eq!(query.after.as_deref(), Some("2024-02-29"));
+        assert!(query.text.is_none());
+    }
+
+    #[test]
+    fn parse_keeps_quoted_operators_as_text() {
+        let query = SearchQuery::parse("\"from:ana\" to:").unwrap();
+        assert_eq!(query.text.as_deref(), Some("\"from:ana\" \"to:\""));
+        assert!(query.from.is_none());
+    }
+
+    #[test]
+    fn parse_rejects_bad_input() {
+        assert!(SearchQuery::parse("").is_err());
+        assert!(SearchQuery::parse("   * ").is_err());
+        assert!(SearchQuery::parse("before:yesterday").is_err());
+        assert!(SearchQuery::parse("has:link").is_err());
+    }
+
+    #[test]
+    fn fts_term_escapes_fts_syntax() {
+        assert_eq!(fts_term("word", false), "\"word\"");
+        assert_eq!(fts_term("wor*", false), "\"wor\"*");
+        assert_eq!(fts_term("wor*", true), "\"wor*\"");
+        assert_eq!(fts_term("a\"b", false), "\"a\"\"b\"");
+        assert_eq!(fts_term("NOT", false), "\"NOT\"");
+        assert_eq!(fts_term("*", false), "");
+    }
+
+    #[test]
+    fn highlight_marks_matches_and_escapes_html() {
+        let snippet = format!("a {}<b>{} & 'c\"", MATCH_START, MATCH_END);
+        assert_eq!(
+            highlight(&snippet),
+            "a <mark>&lt;b&gt;</mark> &amp; &#39;c&quot;"
+        );
+    }
+
+    #[test]
+    fn search_pages_follow_the_cursor() {
+        let db = test_database();
+        let user_id = db.create_user("a".into(), "x".into()).unwrap();
+        let chat_id = db.create_chat("c", user_id).unwrap();
+        // Two messages in the same second, the message id breaks the tie
+        let dates = [
+            "2024-01-01 10:00:00",
+            "2024-01-02 10:00:00",
+            "2024-01-02 10:00:00",
+        ];
+        for (i, date) in dates.iter().enumerate() {
+            db.insert_message(InsertChatMessage {
+                chat_id: chat_id.clone(),
+                user_id,
+                message: format!("palavra {}", i),
+                date_created: date.to_string(),
+                client_msg_id: None,
+                reply_to: None,
+                thread_id: None,
+                attachments: vec![],
+            })
+            .unwrap();
+        }
+        db.insert_message(InsertChatMessage {
+            chat_id: chat_id.clone(),
+            user_id,
+            message: "outra coisa".into(),
+            date_created: "2024-01-03 10:00:00".into(),
+            client_msg_id: None,
+            reply_to: None,
+            thread_id: None,
+            attachments: vec![],
+        })
+        .unwrap();
+
+        let query = SearchQuery::parse("palavra").unwrap();
+        let first = db.search_messages(user_id, &query, None, 2).unwrap();
+        assert_eq!(first.results.len(), 2);
+        assert_eq!(first.results[0].date_created, "2024-01-02 10:00:00");
+        assert!(first.results[0].message_id > first.results[1].message_id);
+        let cursor = first.next_cursor.unwrap();
+        assert_eq!(cursor, first.results[1].message_id);
+
+        let second = db
+            .search_messages(user_id, &query, Some(&cursor), 2)
+            .unwrap();
+        assert_eq!(second.results.len(), 1);
+        assert_eq!(second.results[0].date_created, "2024-01-01 10:00:00");
+        assert_eq!(second.next_cursor, None);
+    }
+}
@@ -0,0 +1,219 @@
+use rusqlite::Connection;
+
+use super::{
+    attachment_db::{
+        ATTACHMENTS_IMAGE_MIGRATION_SQL, ATTACHMENTS_SHA256_INDEX_SQL, ATTACHMENTS_TABLE_SQL,
+    },
+    chat_db::{
+        CHAT_TABLE_SQL, CHAT_USERS_MEMBERSHIP_MIGRATION_SQL, CHAT_USERS_READ_MARKER_MIGRATION_SQL,
+        CHAT_USERS_ROLE_MIGRATION_SQL, CHAT_USERS_TABLE_SQL, DIRECT_CHATS_MIGRATION_SQL,
+    },
+    chat_message_db::{
+        CHAT_MESSAGES_CLIENT_ID_MIGRATION_SQL, CHAT_MESSAGES_SEQ_MIGRATION_SQL,
+        CHAT_MESSAGES_TABLE_SQL, CHAT_MESSAGE_EDITS_MIGRATION_SQL,
+        CHAT_MESSAGE_THREADS_MIGRATION_SQL, CHAT_MESSAGE_TOMBSTONES_MIGRATION_SQL,
+    },
+    message_reaction_db::MESSAGE_REACTIONS_TABLE_SQL,
+    message_search_db::MESSAGE_SEARCH_MIGRATION_SQL,
+    upload_db::UPLOADS_TABLE_SQL,
+    user_db::{USERS_LAST_SEEN_MIGRATION_SQL, USER_TABLE_SQL},
+    DatabaseError,
+};
+
+pub struct Migration {
+    pub version: i64,
+    pub description: &'static str,
+    pub sql: &'static str,
+}
+
+// Applied in order, never edit or reorder an entry that was already released,
+// always append a new one. The schema version is stored in `PRAGMA user_version`.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "create users",
+        sql: USER_TABLE_SQL,
+    },
+    Migration {
+        version: 2,
+        description: "create chats",
+        sql: CHAT_TABLE_SQL,
+    },
+    Migration {
+        version: 3,
+        description: "create chat_messages",
+        sql: CHAT_MESSAGES_TABLE_SQL,
+    },
+    Migration {
+        version: 4,
+        description: "create chat_users",
+        sql: CHAT_USERS_TABLE_SQL,
+    },
+    Migration {
+        version: 5,
+        description: "chat membership",
+        sql: CHAT_USERS_MEMBERSHIP_MIGRATION_SQL,
+    },
+    Migration {
+        version: 6,
+        description: "chat member roles",
+        sql: CHAT_USERS_ROLE_MIGRATION_SQL,
+    },
+    Migration {
+        version: 7,
+        description: "direct chats",
+        sql: DIRECT_CHATS_MIGRATION_SQL,
+    },
+    Migration {
+        version: 8,
+        description: "chat message client ids",
+        sql: CHAT_MESSAGES_CLIENT_ID_MIGRATION_SQL,
+    },
+    Migration {
+        version: 9,
+        description: "chat message sequence numbers",
+        sql: CHAT_MESSAGES_SEQ_MIGRATION_SQL,
+    },
+    Migration {
+        version: 10,
+        description: "chat message edits",
+        sql: CHAT_MESSAGE_EDITS_MIGRATION_SQL,
+    },
+    Migration {
+        version: 11,
+        description: "chat message tombstones",
+        sql: CHAT_MESSAGE_TOMBSTONES_MIGRATION_SQL,
+    },
+    Migration {
+        version: 12,
+        description: "replies and threads",
+        sql: CHAT_MESSAGE_THREADS_MIGRATION_SQL,
+    },
+    Migration {
+        version: 13,
+        description: "create message_reactions",
+        sql: MESSAGE_REACTIONS_TABLE_SQL,
+    },
+    Migration {
+        version: 14,
+        description: "chat_users read markers",
+        sql: CHAT_USERS_READ_MARKER_MIGRATION_SQL,
+    },
+    Migration {
+        version: 15,
+        description: "users last_seen",
+        sql: USERS_LAST_SEEN_MIGRATION_SQL,
+    },
+    Migration {
+        version: 16,
+        description: "full text search over chat_messages",
+        sql: MESSAGE_SEARCH_MIGRATION_SQL,
+    },
+    Migration {
+        version: 17,
+        description: "create attachments",
+        sql: ATTACHMENTS_TABLE_SQL,
+    },
+    Migration {
+        version: 18,
+        description: "create uploads",
+        sql: UPLOADS_TABLE_SQL,
+    },
+    Migration {
+        version: 19,
+        description: "attachment image sizes",
+        sql: ATTACHMENTS_IMAGE_MIGRATION_SQL,
+    },
+    Migration {
+        version: 20,
+        description: "attachments by contents",
+        sql: ATTACHMENTS_SHA256_INDEX_SQL,
+    },
+];
+
+pub fn latest_version() -> i64 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub fn schema_version(conn: &Connection) -> Result<i64, rusqlite::Error> {
+    conn.query_row("PRAGMA user_version", (), |row| row.get(0))
+}
+
+pub fn migrate(conn: &Connection) -> Result<(), DatabaseError> {
+    let current = schema_version(conn)?;
+    let latest = latest_version();
+    if current > latest {
+        return Err(DatabaseError::SchemaTooNew {
+            found: current,
+            supported: latest,
+        });
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        log::info!(
+            "Applying migration {} ({})",
+            migration.version,
+            migration.description
+        );
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration.sql)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_are_consecutive() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(
+                migration.version,
+                index as i64 + 1,
+                "{}",
+                migration.description
+            );
+        }
+    }
+
+    #[test]
+    fn migrates_a_fresh_database() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+
+        let tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name IN ('users', 'chats', 'chat_messages', 'chat_users', 'attachments', 'uploads')",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(tables, 6);
+    }
+
+    #[test]
+    fn migrating_again_changes_nothing() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), latest_version());
+    }
+
+    #[test]
+    fn refuses_a_newer_schema() {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", latest_version() + 1)
+            .unwrap();
+        match migrate(&conn) {
+            Err(DatabaseError::SchemaTooNew { found, supported }) => {
+                assert_eq!(found, latest_version() + 1);
+                assert_eq!(supported, latest_version());
+            }
+            other => panic!("expected SchemaTooNew, got {:?}", other),
+        }
+    }
+}
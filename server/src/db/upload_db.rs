@@ -0,0 +1,136 @@
+use rusqlite::{params, OptionalExtension, Row};
+use serde::Serialize;
+use uuid::Uuid;
+
+use super::Database;
+
+// Resumable uploads still being received, the bytes are in Storage::partial_path. Once the last
+// chunk arrives the row is replaced by an attachment.
+pub const UPLOADS_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS uploads (
+    upload_id VARCHAR(36) PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    chat_id VARCHAR(36) NOT NULL,
+    file_name VARCHAR(255) NOT NULL,
+    size INTEGER NOT NULL,
+    received INTEGER NOT NULL DEFAULT 0,
+    date_created VARCHAR(32),
+    expires_at VARCHAR(32) NOT NULL,
+
+    FOREIGN KEY (user_id) REFERENCES users(user_id),
+    FOREIGN KEY (chat_id) REFERENCES chats(chat_id) ON DELETE CASCADE
+);
+CREATE INDEX IF NOT EXISTS idx_uploads_expires_at ON uploads(expires_at);";
+
+const UPLOAD_SELECT_SQL: &str =
+    "SELECT upload_id, user_id, chat_id, file_name, size, received, expires_at FROM uploads";
+
+#[derive(Debug, Serialize, Clone)]
+pub struct ResumableUpload {
+    pub id: String,
+    #[serde(skip)]
+    pub user_id: i64,
+    pub chat_id: String,
+    pub file_name: String,
+    // Bytes, declared when the upload was created
+    pub size: i64,
+    // Bytes stored so far, the next chunk starts here
+    pub offset: i64,
+    // Every chunk pushes it back
+    pub expires_at: String,
+}
+
+fn upload_from_row(row: &Row) -> Result<ResumableUpload, rusqlite::Error> {
+    Ok(ResumableUpload {
+        id: row.get(0)?,
+        user_id: row.get(1)?,
+        chat_id: row.get(2)?,
+        file_name: row.get(3)?,
+        size: row.get(4)?,
+        offset: row.get(5)?,
+        expires_at: row.get(6)?,
+    })
+}
+
+pub struct CreateUpload {
+    pub user_id: i64,
+    pub chat_id: String,
+    pub file_name: String,
+    pub size: i64,
+    pub date_created: String,
+    pub expires_at: String,
+}
+
+pub trait UploadsTable {
+    fn create_upload(&self, upload: CreateUpload) -> Result<ResumableUpload, rusqlite::Error>;
+    fn get_upload(&self, upload_id: &str) -> Result<Option<ResumableUpload>, rusqlite::Error>;
+    fn set_upload_offset(
+        &self,
+        upload_id: &str,
+        offset: i64,
+        expires_at: &str,
+    ) -> Result<usize, rusqlite::Error>;
+    fn delete_upload(&self, upload_id: &str) -> Result<usize, rusqlite::Error>;
+    // Their partial files are left to Storage::remove_stale_files
+    fn delete_expired_uploads(&self, now: &str) -> Result<usize, rusqlite::Error>;
+}
+
+impl UploadsTable for Database {
+    fn create_upload(&self, upload: CreateUpload) -> Result<ResumableUpload, rusqlite::Error> {
+        let upload_id = Uuid::new_v4().to_string();
+        self.conn.execute(
+            "INSERT INTO uploads (upload_id, user_id, chat_id, file_name, size, date_created, expires_at) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                upload_id,
+                upload.user_id,
+                upload.chat_id,
+                upload.file_name,
+                upload.size,
+                upload.date_created,
+                upload.expires_at
+            ],
+        )?;
+        Ok(ResumableUpload {
+            id: upload_id,
+            user_id: upload.user_id,
+            chat_id: upload.chat_id,
+            file_name: upload.file_name,
+            size: upload.size,
+            offset: 0,
+            expires_at: upload.expires_at,
+        })
+    }
+
+    fn get_upload(&self, upload_id: &str) -> Result<Option<ResumableUpload>, rusqlite::Error> {
+        self.conn
+            .query_row(
+                &format!("{} WHERE upload_id = ?", UPLOAD_SELECT_SQL),
+                params![upload_id],
+                upload_from_row,
+            )
+            .optional()
+    }
+
+    fn set_upload_offset(
+        &self,
+        upload_id: &str,
+        offset: i64,
+        expires_at: &str,
+    ) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE uploads SET received = ?, expires_at = ? WHERE upload_id = ?",
+            params![offset, expires_at, upload_id],
+        )
+    }
+
+    fn delete_upload(&self, upload_id: &str) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "DELETE FROM uploads WHERE upload_id = ?",
+            params![upload_id],
+        )
+    }
+
+    fn delete_expired_uploads(&self, now: &str) -> Result<usize, rusqlite::Error> {
+        self.conn
+            .execute("DELETE FROM uploads WHERE expires_at < ?", params![now])
+    }
+}
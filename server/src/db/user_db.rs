@@ -2,9 +2,11 @@ use argon2::{
     password_hash::{rand_core::OsRng, SaltString},
     Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
 };
-use rusqlite::params;
+use rusqlite::{params, params_from_iter};
 use serde::{Deserialize, Serialize};
 
+use crate::avatar::user_avatar_url;
+
 use super::Database;
 
 pub const USER_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS users (
@@ -19,6 +21,10 @@ pub const USER_TABLE_SQL: &str = "CREATE TABLE IF NOT EXISTS users (
     
 );";
 
+// When the user's last connection closed, NULL for who never connected since it was added
+pub const USERS_LAST_SEEN_MIGRATION_SQL: &str =
+    "ALTER TABLE users ADD COLUMN last_seen VARCHAR(32);";
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub user_id: i64,
@@ -26,6 +32,7 @@ pub struct User {
     pub user_name: Option<String>,
     pub user_status: Option<String>,
     pub user_email: Option<String>,
+    // A generated avatar when the user never uploaded one, see set_user_image
     pub user_image: Option<String>,
 }
 
@@ -39,7 +46,15 @@ pub trait UserTable {
     ) -> Result<Option<i64>, rusqlite::Error>;
 
     fn get_user(&self, id: i64) -> Result<User, rusqlite::Error>;
+    // user_image is left alone, see set_user_image
     fn update_user(&self, user: User) -> Result<usize, rusqlite::Error>;
+    fn set_user_image(&self, user_id: i64, image: Option<&str>) -> Result<usize, rusqlite::Error>;
+    fn set_last_seen(&self, user_id: i64, last_seen: &str) -> Result<usize, rusqlite::Error>;
+    // Ids that don't belong to any user are left out
+    fn get_last_seen(
+        &self,
+        user_ids: &[i64],
+    ) -> Result<Vec<(i64, Option<String>)>, rusqlite::Error>;
 }
 
 impl UserTable for Database {
@@ -90,26 +105,61 @@ impl UserTable for Database {
     fn get_user(&self, id: i64) -> Result<User, rusqlite::Error> {
         let mut stmt = self.conn.prepare("SELECT user_id, user_nick, user_name, user_status, user_email, user_image FROM users WHERE user_id = ? LIMIT 1")?;
         let user: User = stmt.query_row(params![id], |row| {
+            let user_id = row.get(0)?;
             Ok(User {
-                user_id: row.get(0)?,
+                user_id,
                 user_nick: row.get(1)?,
                 user_name: row.get(2)?,
                 user_status: row.get(3)?,
                 user_email: row.get(4)?,
-                user_image: row.get(5)?,
+                user_image: Some(
+                    row.get::<_, Option<String>>(5)?
+                        .unwrap_or_else(|| user_avatar_url(user_id)),
+                ),
             })
         })?;
         Ok(user)
     }
     fn update_user(&self, user: User) -> Result<usize, rusqlite::Error> {
-        let mut stmt = self.conn.prepare("UPDATE users SET user_nick=?, user_name=?, user_status=?, user_email=?, user_image=? WHERE user_id=?")?;
+        let mut stmt = self.conn.prepare("UPDATE users SET user_nick=?, user_name=?, user_status=?, user_email=? WHERE user_id=?")?;
         stmt.execute(params![
             user.user_nick,
             user.user_name,
             user.user_status,
             user.user_email,
-            user.user_image,
             user.user_id
         ])
     }
+
+    fn set_user_image(&self, user_id: i64, image: Option<&str>) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE users SET user_image = ? WHERE user_id = ?",
+            params![image, user_id],
+        )
+    }
+
+    fn set_last_seen(&self, user_id: i64, last_seen: &str) -> Result<usize, rusqlite::Error> {
+        self.conn.execute(
+            "UPDATE users SET last_seen = ? WHERE user_id = ?",
+            params![last_seen, user_id],
+        )
+    }
+
+    fn get_last_seen(
+        &self,
+        user_ids: &[i64],
+    ) -> Result<Vec<(i64, Option<String>)>, rusqlite::Error> {
+        if user_ids.is_empty() {
+            return Ok(Vec::new());
+        }
+        let placeholders = vec!["?"; user_ids.len()].join(", ");
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT user_id, last_seen FROM users WHERE user_id IN ({}) ORDER BY user_id",
+            placeholders
+        ))?;
+        let rows = stmt.query_map(params_from_iter(user_ids), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })?;
+        rows.collect()
+    }
 }
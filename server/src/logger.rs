@@ -7,9 +7,12 @@ use log4rs::{
     append::{console::ConsoleAppender, file::FileAppender},
     config::{Appender, Logger, Root},
     encode::pattern::PatternEncoder,
+    filter::threshold::ThresholdFilter,
     Config,
 };
 
+use crate::config::LogConfig;
+
 pub fn setup_env_logger() {
     Builder::new()
         .format(|buf, record| {
@@ -25,13 +28,13 @@ pub fn setup_env_logger() {
         .init();
 }
 
-pub fn setup_logger() -> Result<(), Box<dyn Error>> {
+pub fn setup_logger(log_config: &LogConfig) -> Result<(), Box<dyn Error>> {
     // setup_env_logger();
     let log_file = FileAppender::builder()
         .encoder(Box::new(PatternEncoder::new(
             "{d(%Y-%m-%d %H:%M:%S %Z)} [{l}] - {m} {n}",
         )))
-        .build("log/output.log")?;
+        .build(&log_config.file)?;
 
     let console_appender = ConsoleAppender::builder()
         .encoder(Box::new(PatternEncoder::new(
@@ -41,13 +44,19 @@ pub fn setup_logger() -> Result<(), Box<dyn Error>> {
 
     let config = Config::builder()
         .appender(Appender::builder().build("log_file", Box::new(log_file)))
-        .appender(Appender::builder().build("stdout", Box::new(console_appender)))
-        .logger(Logger::builder().build("stdout", log::LevelFilter::Info))
+        .appender(
+            Appender::builder()
+                .filter(Box::new(ThresholdFilter::new(
+                    log_config.console_level_filter(),
+                )))
+                .build("stdout", Box::new(console_appender)),
+        )
+        .logger(Logger::builder().build("stdout", log_config.console_level_filter()))
         .build(
             Root::builder()
                 .appender("log_file")
                 .appender("stdout")
-                .build(log::LevelFilter::Info),
+                .build(log_config.level_filter()),
         )?;
 
     log4rs::init_config(config)?;
@@ -1,12 +1,15 @@
+pub mod avatar;
+pub mod config;
 pub mod db;
 pub mod logger;
 pub mod message;
 pub mod routes;
 pub mod sockets;
+pub mod storage;
 
 use std::{
     collections::HashMap,
-    env,
+    process,
     sync::{Arc, Mutex},
 };
 
@@ -15,70 +18,119 @@ use actix_cors::Cors;
 use actix_session::{config::PersistentSession, storage::CookieSessionStore, SessionMiddleware};
 use actix_web::{
     cookie::{time::Duration, Key},
-    web::Data,
+    web::{Data, JsonConfig},
     App, HttpServer,
 };
-use db::Database;
+use config::Config;
+use db::DatabasePool;
 use logger::setup_logger;
 use routes::{
     base_route::{index_route, info_route},
+    attachment_route::attachment_scope,
+    avatar_route::avatar_scope,
     chat_route::chat_scope,
     user_route::user_scope,
 };
 use sockets::{chat::lobby_actor::Lobby, info::info_actor::Info};
+use storage::{cleanup::Cleanup, Storage};
 use uuid::Uuid;
 
 pub struct AppContext {
-    db: Arc<Mutex<Database>>,
+    db: DatabasePool,
     auth_tokens: Arc<Mutex<HashMap<Uuid, i64>>>,
     chat_server: Addr<Lobby>,
     info_server: Addr<Info>,
+    storage: Storage,
+    config: Arc<Config>,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // std::env::set_var("RUST_LOG", "debug");
     // env_logger::init();
-    if let Err(_) = dotenvy::dotenv() {
-        panic!("Error loading dotenv");
+    if let Err(err) = dotenvy::dotenv() {
+        if !err.not_found() {
+            eprintln!("Error loading .env: {}", err);
+            process::exit(1);
+        }
     };
-    let Ok(url_env) = env::var("URL") else {
-        panic!("Variavel de url nao encontrada no env")
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            eprintln!("Invalid configuration: {}", err);
+            process::exit(1);
+        }
     };
 
-    if let Err(err) = setup_logger() {
-        panic!("Error setting up logger! {}", err);
+    if let Err(err) = setup_logger(&config.log) {
+        eprintln!("Error setting up logger! {}", err);
+        process::exit(1);
     };
-    let db = Arc::new(Mutex::new(db::get().unwrap()));
-    let chat_server = Lobby::new(db.clone()).start();
-    let info_server = Info::new().start();
+    let db = match db::get(&config.database) {
+        Ok(db) => db,
+        Err(err) => {
+            log::error!("Error opening database {}: {}", config.database.path.display(), err);
+            process::exit(1);
+        }
+    };
+    let storage = match Storage::open(&config.storage) {
+        Ok(storage) => storage,
+        Err(err) => {
+            log::error!("Error opening storage {}: {}", config.storage.root.display(), err);
+            process::exit(1);
+        }
+    };
+    let session_key = match &config.session.key {
+        Some(key) => Key::from(key.as_bytes()),
+        None => {
+            log::warn!("No session key configured, generating one, sessions will not survive a restart");
+            Key::generate()
+        }
+    };
+    let info_server = Info::new(db.clone(), &config.sockets).start();
+    let chat_server = Lobby::new(db.clone(), info_server.clone(), &config.sockets).start();
+    Cleanup::new(db.clone(), storage.clone(), config.storage.upload_expiry()).start();
     let auth_tokens = Arc::new(Mutex::new(HashMap::new()));
+    let server_config = config.clone();
     HttpServer::new(move || {
+        let config = server_config.clone();
+        let mut cors = Cors::default()
+            .allowed_methods(vec!["GET", "POST", "HEAD", "PATCH", "DELETE"])
+            .allowed_header(actix_web::http::header::ACCEPT)
+            .allowed_header(actix_web::http::header::CONTENT_TYPE)
+            .allowed_header("Upload-Offset")
+            .expose_headers(vec!["Upload-Offset", "Upload-Length", "Upload-Expires", "Location"])
+            .supports_credentials()
+            .max_age(config.cors.max_age_secs);
+        if config.cors.allowed_origins.is_empty() {
+            cors = cors.allow_any_origin();
+        }
+        for origin in &config.cors.allowed_origins {
+            cors = cors.allowed_origin(origin);
+        }
+
         App::new()
             .wrap(
-                SessionMiddleware::builder(CookieSessionStore::default(), Key::from(&[0; 64]))
-                    .cookie_secure(false)
-                    .session_lifecycle(PersistentSession::default().session_ttl(Duration::weeks(2)))
-                    .cookie_name("ssid".into())
-                    .cookie_secure(false)
-                    .cookie_same_site(actix_web::cookie::SameSite::Strict)
-                    .cookie_http_only(true)
+                SessionMiddleware::builder(CookieSessionStore::default(), session_key.clone())
+                    .session_lifecycle(
+                        PersistentSession::default()
+                            .session_ttl(Duration::days(config.session.ttl_days)),
+                    )
+                    .cookie_name(config.session.cookie_name.clone())
+                    .cookie_secure(config.session.cookie_secure)
+                    .cookie_same_site(config.session.cookie_same_site.into())
+                    .cookie_http_only(config.session.cookie_http_only)
                     .build(),
             )
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allowed_methods(vec!["GET", "POST"])
-                    .allowed_header(actix_web::http::header::ACCEPT)
-                    .allowed_header(actix_web::http::header::CONTENT_TYPE)
-                    .supports_credentials()
-                    .max_age(3600),
-            )
+            .wrap(cors)
+            .app_data(JsonConfig::default().limit(config.limits.max_json_payload))
             .app_data(Data::new(AppContext {
                 db: db.clone(),
                 auth_tokens: auth_tokens.clone(),
                 chat_server: chat_server.clone(),
                 info_server: info_server.clone(),
+                storage: storage.clone(),
+                config,
             }))
             // .app_data(Data::new(chat_server.clone()))
             .service(info_route)
@@ -86,8 +138,10 @@ async fn main() -> std::io::Result<()> {
             // .service(base_scope())
             .service(user_scope())
             .service(chat_scope())
+            .service(attachment_scope())
+            .service(avatar_scope())
     })
-    .bind((url_env, 8080))?
+    .bind((config.server.bind.as_str(), config.server.port))?
     .run()
     .await
 }
@@ -1,7 +1,15 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::db::{
+    attachment_db::Attachment,
+    chat_db::ReadReceipt,
+    chat_message_db::{ChatMessage, ReplyPreview},
+    message_reaction_db::Reaction,
+};
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum MessageType {
     JOIN,
     LEAVE,
@@ -11,6 +19,15 @@ pub enum MessageType {
     DISCONNECTED,
     CHAT_UNAVAILABLE,
     CHAT_DELETED,
+    EDITED,
+    DELETED,
+    REACTION_ADDED,
+    REACTION_REMOVED,
+    READ,
+    TYPING_STARTED,
+    TYPING_STOPPED,
+    RESUMED,
+    GAP,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -38,19 +55,556 @@ impl SocketMessage {
         res.unwrap()
     }
     pub fn parse_failable(message: &str) -> Option<Self> {
-        let res = serde_json::from_str(message);
-        if res.is_ok() {
-            let object: SocketMessage = res.unwrap();
-            return Some(Self {
-                message_type: MessageType::TEXT,
-                message: object.message,
-                ..Default::default()
-            });
+        serde_json::from_str(message).ok()
+    }
+}
+
+// Version 1 is the original format, raw text in and SocketMessage out.
+// Version 2 speaks ClientRequest / ServerEvent and reports protocol errors back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProtocolVersion {
+    #[default]
+    V1,
+    V2,
+}
+
+impl ProtocolVersion {
+    pub const SUPPORTED: &'static [u8] = &[1, 2];
+
+    pub fn from_number(version: u8) -> Option<Self> {
+        match version {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self) -> u8 {
+        match self {
+            Self::V1 => 1,
+            Self::V2 => 2,
+        }
+    }
+}
+
+// Frames a client sends, `chat_id` can be left out on sockets bound to a single chat.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientRequest {
+    #[serde(alias = "message")]
+    Send {
+        chat_id: Option<String>,
+        message: String,
+        // Retrying with the same key after a reconnect acks the stored message again
+        client_msg_id: Option<String>,
+        // The message being answered, quoted by clients
+        reply_to: Option<String>,
+        // Posts in the thread started by this message instead of the chat
+        thread_id: Option<String>,
+        // Ids from /attachments/upload, the text can be empty when there are any
+        #[serde(default)]
+        attachments: Vec<String>,
+    },
+    Edit {
+        chat_id: Option<String>,
+        message_id: String,
+        message: String,
+    },
+    Delete {
+        chat_id: Option<String>,
+        message_id: String,
+        // Shown to the chat's moderators
+        reason: Option<String>,
+    },
+    AddReaction {
+        chat_id: Option<String>,
+        message_id: String,
+        emoji: String,
+    },
+    RemoveReaction {
+        chat_id: Option<String>,
+        message_id: String,
+        emoji: String,
+    },
+    // Resent every few seconds while the user types, see sockets.typing_timeout_secs
+    Typing {
+        chat_id: Option<String>,
+    },
+    StopTyping {
+        chat_id: Option<String>,
+    },
+    MarkRead {
+        chat_id: Option<String>,
+        message_id: String,
+    },
+    Subscribe {
+        chat_id: String,
+        // The last message the client saw, everything after it is replayed before live delivery
+        last_seq: Option<i64>,
+        last_message_id: Option<String>,
+    },
+    Unsubscribe {
+        chat_id: String,
+    },
+    Ping {
+        nonce: Option<String>,
+    },
+}
+
+impl ClientRequest {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Send { .. } => "send",
+            Self::Edit { .. } => "edit",
+            Self::Delete { .. } => "delete",
+            Self::AddReaction { .. } => "add_reaction",
+            Self::RemoveReaction { .. } => "remove_reaction",
+            Self::Typing { .. } => "typing",
+            Self::StopTyping { .. } => "stop_typing",
+            Self::MarkRead { .. } => "mark_read",
+            Self::Subscribe { .. } => "subscribe",
+            Self::Unsubscribe { .. } => "unsubscribe",
+            Self::Ping { .. } => "ping",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidFrame,
+    Unsupported,
+    MessageTooLong,
+    MissingChatId,
+    NotSubscribed,
+    ChatUnavailable,
+    MessageNotFound,
+    Forbidden,
+    EditWindowClosed,
+    Internal,
+}
+
+// Everything the server sends to a chat socket
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerEvent {
+    Hello {
+        protocol_version: u8,
+    },
+    Init {
+        chat_id: String,
+        user_id: i64,
+        users: Vec<i64>,
+    },
+    Join {
+        chat_id: String,
+        user_id: i64,
+    },
+    Leave {
+        chat_id: String,
+        user_id: i64,
+    },
+    Message {
+        chat_id: String,
+        message_id: String,
+        seq: i64,
+        user_id: i64,
+        message: String,
+        date: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_msg_id: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        edited_at: Option<String>,
+        // Replayed tombstones have no text
+        #[serde(skip_serializing_if = "Option::is_none")]
+        deleted_at: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_to: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        reply_preview: Option<ReplyPreview>,
+        // Thread replies only go to the thread's followers
+        #[serde(skip_serializing_if = "Option::is_none")]
+        thread_id: Option<String>,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        attachments: Vec<Attachment>,
+    },
+    // count is how many users reacted with the emoji after the change
+    ReactionAdded {
+        chat_id: String,
+        message_id: String,
+        user_id: i64,
+        emoji: String,
+        count: i64,
+    },
+    ReactionRemoved {
+        chat_id: String,
+        message_id: String,
+        user_id: i64,
+        emoji: String,
+        count: i64,
+    },
+    // Never sent back to the connections of the user typing
+    TypingStarted {
+        chat_id: String,
+        user_id: i64,
+        // Clients can drop the indicator after this even without TypingStopped
+        expires_in_ms: u64,
+    },
+    TypingStopped {
+        chat_id: String,
+        user_id: i64,
+    },
+    // A member's read marker moved up to message_id
+    Read {
+        chat_id: String,
+        user_id: i64,
+        message_id: String,
+        seq: i64,
+        read_at: String,
+    },
+    // Someone replied in a thread or a reply was deleted, for the whole room to update the
+    // root's counter
+    ThreadUpdated {
+        chat_id: String,
+        thread_id: String,
+        reply_count: i64,
+        last_reply_at: Option<String>,
+    },
+    // A message got new text, sent to the whole room including the author
+    Edited {
+        chat_id: String,
+        message_id: String,
+        seq: i64,
+        user_id: i64,
+        message: String,
+        edited_at: String,
+    },
+    // The message became a tombstone, deleted_by is the author or a moderator
+    Deleted {
+        chat_id: String,
+        message_id: String,
+        seq: i64,
+        deleted_by: i64,
+        deleted_at: String,
+    },
+    // Only for the sender, the message was stored with this id and date
+    Ack {
+        chat_id: String,
+        message_id: String,
+        seq: i64,
+        date: String,
+        client_msg_id: Option<String>,
+    },
+    // Only for the sender, the message was not stored
+    Nack {
+        chat_id: String,
+        code: ErrorCode,
+        reason: String,
+        client_msg_id: Option<String>,
+    },
+    // Sent after the replayed messages of a resume, live delivery starts after it
+    Resumed {
+        chat_id: String,
+        replayed: usize,
+    },
+    // Too much was missed or the last message is unknown, the client should refetch the history
+    Gap {
+        chat_id: String,
+        reason: String,
+    },
+    ChatUnavailable {
+        chat_id: String,
+        reason: String,
+    },
+    ChatDeleted {
+        chat_id: String,
+    },
+    Pong {
+        nonce: Option<String>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        chat_id: Option<String>,
+    },
+}
+
+impl ServerEvent {
+    pub fn error(code: ErrorCode, message: impl Into<String>, chat_id: Option<&str>) -> Self {
+        Self::Error {
+            code,
+            message: message.into(),
+            chat_id: chat_id.map(str::to_string),
+        }
+    }
+
+    pub fn ack(chat_id: &str, message: &ChatMessage) -> Self {
+        Self::Ack {
+            chat_id: chat_id.to_string(),
+            message_id: message.id.clone(),
+            seq: message.seq,
+            date: message.date_created.clone(),
+            client_msg_id: message.client_msg_id.clone(),
+        }
+    }
+
+    pub fn message(chat_id: &str, message: ChatMessage) -> Self {
+        Self::Message {
+            chat_id: chat_id.to_string(),
+            message_id: message.id,
+            seq: message.seq,
+            user_id: message.user_id,
+            message: message.message,
+            date: message.date_created,
+            client_msg_id: message.client_msg_id,
+            edited_at: message.edited_at,
+            deleted_at: message.deleted_at,
+            reply_to: message.reply_to,
+            reply_preview: message.reply_preview,
+            thread_id: message.thread_id,
+            attachments: message.attachments,
         }
-        None
+    }
+
+    pub fn edited(chat_id: &str, message: ChatMessage) -> Self {
+        Self::Edited {
+            chat_id: chat_id.to_string(),
+            message_id: message.id,
+            seq: message.seq,
+            user_id: message.user_id,
+            message: message.message,
+            edited_at: message.edited_at.unwrap_or(message.date_created),
+        }
+    }
+
+    pub fn deleted(chat_id: &str, message: ChatMessage) -> Self {
+        Self::Deleted {
+            chat_id: chat_id.to_string(),
+            message_id: message.id,
+            seq: message.seq,
+            deleted_by: message.deleted_by.unwrap_or(message.user_id),
+            deleted_at: message.deleted_at.unwrap_or(message.date_created),
+        }
+    }
+
+    pub fn thread_updated(chat_id: &str, root: ChatMessage) -> Self {
+        Self::ThreadUpdated {
+            chat_id: chat_id.to_string(),
+            thread_id: root.id,
+            reply_count: root.reply_count,
+            last_reply_at: root.last_reply_at,
+        }
+    }
+
+    pub fn reaction(reaction: Reaction, added: bool, count: i64) -> Self {
+        let Reaction {
+            chat_id,
+            message_id,
+            user_id,
+            emoji,
+        } = reaction;
+        if added {
+            Self::ReactionAdded {
+                chat_id,
+                message_id,
+                user_id,
+                emoji,
+                count,
+            }
+        } else {
+            Self::ReactionRemoved {
+                chat_id,
+                message_id,
+                user_id,
+                emoji,
+                count,
+            }
+        }
+    }
+
+    pub fn read(receipt: &ReadReceipt) -> Self {
+        Self::Read {
+            chat_id: receipt.chat_id.clone(),
+            user_id: receipt.user_id,
+            message_id: receipt.message_id.clone(),
+            seq: receipt.seq,
+            read_at: receipt.read_at.clone(),
+        }
+    }
+
+    pub fn chat_id(&self) -> Option<&str> {
+        match self {
+            Self::Init { chat_id, .. }
+            | Self::Join { chat_id, .. }
+            | Self::Leave { chat_id, .. }
+            | Self::Message { chat_id, .. }
+            | Self::Edited { chat_id, .. }
+            | Self::ThreadUpdated { chat_id, .. }
+            | Self::ReactionAdded { chat_id, .. }
+            | Self::ReactionRemoved { chat_id, .. }
+            | Self::Read { chat_id, .. }
+            | Self::TypingStarted { chat_id, .. }
+            | Self::TypingStopped { chat_id, .. }
+            | Self::Deleted { chat_id, .. }
+            | Self::Ack { chat_id, .. }
+            | Self::Nack { chat_id, .. }
+            | Self::Resumed { chat_id, .. }
+            | Self::Gap { chat_id, .. }
+            | Self::ChatUnavailable { chat_id, .. }
+            | Self::ChatDeleted { chat_id } => Some(chat_id),
+            Self::Error { chat_id, .. } => chat_id.as_deref(),
+            Self::Hello { .. } | Self::Pong { .. } => None,
+        }
+    }
+
+    // Version 1 clients only know SocketMessage, events without an equivalent are not sent to them
+    pub fn to_legacy(&self) -> Option<SocketMessage> {
+        let (message_type, message, id) = match self {
+            Self::Init { user_id, users, .. } => (
+                MessageType::INIT,
+                serde_json::to_string(users).unwrap(),
+                Some(*user_id),
+            ),
+            Self::Join { user_id, .. } => (MessageType::JOIN, user_id.to_string(), Some(*user_id)),
+            Self::Leave { user_id, .. } => {
+                (MessageType::LEAVE, user_id.to_string(), Some(*user_id))
+            }
+            Self::TypingStarted { user_id, .. } => (
+                MessageType::TYPING_STARTED,
+                user_id.to_string(),
+                Some(*user_id),
+            ),
+            Self::TypingStopped { user_id, .. } => (
+                MessageType::TYPING_STOPPED,
+                user_id.to_string(),
+                Some(*user_id),
+            ),
+            // Version 1 has no threads, a reply would show up in the main timeline
+            Self::Message {
+                thread_id: Some(_), ..
+            } => return None,
+            Self::Message {
+                user_id,
+                message,
+                date,
+                ..
+            } => {
+                return Some(SocketMessage {
+                    message_type: MessageType::TEXT,
+                    message: message.clone(),
+                    id: Some(*user_id),
+                    date: date.clone(),
+                })
+            }
+            // The edited message travels as json in `message`, like INIT's user list
+            Self::Edited {
+                message_id,
+                seq,
+                user_id,
+                message,
+                edited_at,
+                ..
+            } => (
+                MessageType::EDITED,
+                serde_json::json!({
+                    "message_id": message_id,
+                    "seq": seq,
+                    "message": message,
+                    "edited_at": edited_at,
+                })
+                .to_string(),
+                Some(*user_id),
+            ),
+            Self::Deleted {
+                message_id,
+                seq,
+                deleted_by,
+                deleted_at,
+                ..
+            } => (
+                MessageType::DELETED,
+                serde_json::json!({
+                    "message_id": message_id,
+                    "seq": seq,
+                    "deleted_at": deleted_at,
+                })
+                .to_string(),
+                Some(*deleted_by),
+            ),
+            Self::ReactionAdded {
+                message_id,
+                user_id,
+                emoji,
+                count,
+                ..
+            }
+            | Self::ReactionRemoved {
+                message_id,
+                user_id,
+                emoji,
+                count,
+                ..
+            } => (
+                if matches!(self, Self::ReactionAdded { .. }) {
+                    MessageType::REACTION_ADDED
+                } else {
+                    MessageType::REACTION_REMOVED
+                },
+                serde_json::json!({
+                    "message_id": message_id,
+                    "emoji": emoji,
+                    "count": count,
+                })
+                .to_string(),
+                Some(*user_id),
+            ),
+            Self::Read {
+                user_id,
+                message_id,
+                seq,
+                read_at,
+                ..
+            } => (
+                MessageType::READ,
+                serde_json::json!({
+                    "message_id": message_id,
+                    "seq": seq,
+                    "read_at": read_at,
+                })
+                .to_string(),
+                Some(*user_id),
+            ),
+            Self::ChatUnavailable { reason, .. } => {
+                (MessageType::CHAT_UNAVAILABLE, reason.clone(), None)
+            }
+            Self::ChatDeleted { chat_id } => (
+                MessageType::CHAT_DELETED,
+                format!("Chat {:?} deletado.", chat_id),
+                None,
+            ),
+            // Version 1 clients can also send last_seq, they need to know the replay ended
+            Self::Resumed { replayed, .. } => (MessageType::RESUMED, replayed.to_string(), None),
+            Self::Gap { reason, .. } => (MessageType::GAP, reason.clone(), None),
+            Self::Hello { .. }
+            | Self::Pong { .. }
+            | Self::Error { .. }
+            | Self::Ack { .. }
+            | Self::Nack { .. }
+            | Self::ThreadUpdated { .. } => return None,
+        };
+        Some(SocketMessage::new(message, message_type, id))
     }
 }
 
+// What the multiplexed socket receives, a SocketMessage tagged with the chat it came from.
+#[derive(Debug, Serialize)]
+pub struct RoomMessage<'a> {
+    pub chat_id: &'a str,
+    #[serde(flatten)]
+    pub message: &'a SocketMessage,
+}
+
 const DATE_FORMATTING: &str = "%Y-%m-%d %H:%M:%S";
 
 pub fn format_date(date_time: DateTime<Utc>) -> String {
@@ -67,3 +621,39 @@ impl Default for SocketMessage {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(thread_id: Option<&str>) -> ServerEvent {
+        ServerEvent::Message {
+            chat_id: "chat".into(),
+            message_id: "reply".into(),
+            seq: 2,
+            user_id: 1,
+            message: "ola".into(),
+            date: "2024-01-01 00:00:00".into(),
+            client_msg_id: None,
+            edited_at: None,
+            deleted_at: None,
+            reply_to: None,
+            reply_preview: None,
+            thread_id: thread_id.map(String::from),
+            attachments: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn to_legacy_sends_main_timeline_messages_as_text() {
+        let legacy = message(None).to_legacy().unwrap();
+        assert!(matches!(legacy.message_type, MessageType::TEXT));
+        assert_eq!(legacy.message, "ola");
+        assert_eq!(legacy.id, Some(1));
+    }
+
+    #[test]
+    fn to_legacy_leaves_out_thread_replies() {
+        assert!(message(Some("root")).to_legacy().is_none());
+    }
+}
@@ -1,3 +1,6 @@
+pub mod attachment_route;
+pub mod authorization;
+pub mod avatar_route;
 pub mod base_route;
 pub mod chat_route;
 pub mod user_route;
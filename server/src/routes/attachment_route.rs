@@ -0,0 +1,943 @@
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path as FsPath, PathBuf},
+};
+
+use actix_files::NamedFile;
+use actix_multipart::{Field, Multipart};
+use actix_web::{
+    delete, get, head,
+    http::{
+        header::{
+            self, Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue,
+            HeaderValue,
+        },
+        StatusCode,
+    },
+    patch, post,
+    web::{self, Data, Json, Path, Payload, Query},
+    HttpRequest, HttpResponse, HttpResponseBuilder, Scope,
+};
+use chrono::{Duration, NaiveDateTime, Utc};
+use futures_util::TryStreamExt;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    db::{
+        attachment_db::{Attachment, AttachmentsTable, InsertAttachment},
+        chat_db::ChatRole,
+        upload_db::{CreateUpload, ResumableUpload, UploadsTable},
+    },
+    message::format_date,
+    storage::{
+        hash_file,
+        images::{image_format, strip_metadata, thumbnail_mime_type, ImageInfo, THUMBNAIL_SIZES},
+        Storage,
+    },
+    AppContext,
+};
+
+use super::authorization::{authorize_chat, ApiError, SessionUser};
+
+pub fn attachment_scope() -> Scope {
+    web::scope("/attachments")
+        .service(upload_attachment)
+        .service(create_resumable_upload)
+        .service(resumable_upload_progress)
+        .service(resumable_upload_chunk)
+        .service(cancel_resumable_upload)
+        .service(download_attachment)
+}
+
+// Enough of the start of a file for infer to recognize it
+const SNIFF_LEN: usize = 8192;
+const MAX_FILE_NAME_LEN: usize = 255;
+// Content type of the chunks of a resumable upload, same as tus
+const CHUNK_CONTENT_TYPE: &str = "application/offset+octet-stream";
+// What /user/image and /chat/image accept
+const IMAGE_TYPES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp"];
+
+// A file that was received completely and is already in storage
+pub struct Upload {
+    pub sha256: String,
+    pub file_name: String,
+    pub mime_type: String,
+    pub size: i64,
+    pub image: Option<ImageInfo>,
+}
+
+impl Upload {
+    pub fn into_insert(self, user_id: i64, chat_id: Option<String>) -> InsertAttachment {
+        InsertAttachment {
+            user_id,
+            chat_id,
+            sha256: self.sha256,
+            file_name: self.file_name,
+            mime_type: self.mime_type,
+            size: self.size,
+            image: self.image,
+            date_created: format_date(Utc::now()),
+        }
+    }
+}
+
+fn storage_error(err: impl fmt::Debug) -> ApiError {
+    log::error!("Error storing upload: {:?}", err);
+    ApiError::internal("Erro ao salvar arquivo")
+}
+
+// Stores the "file" field of a multipart body, other fields are skipped. The type comes from
+// the bytes themselves, whatever the client declared is ignored.
+pub async fn receive_upload(
+    mut payload: Multipart,
+    storage: &Storage,
+    max_bytes: usize,
+) -> Result<Upload, ApiError> {
+    loop {
+        let field = match payload.try_next().await {
+            Ok(Some(field)) => field,
+            Ok(None) => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    "Envie o arquivo no campo file",
+                ))
+            }
+            Err(err) => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Corpo multipart invalido: {}", err),
+                ))
+            }
+        };
+        if field.name() != "file" {
+            continue;
+        }
+        let file_name = clean_file_name(field.content_disposition().get_filename());
+        let (temp, lock) = storage.temp_path();
+        let (sha256, size, head) = match write_field(field, temp.clone(), max_bytes).await {
+            Ok(received) => received,
+            Err(err) => {
+                let _ = fs::remove_file(&temp);
+                return Err(err);
+            }
+        };
+        if size == 0 {
+            let _ = fs::remove_file(&temp);
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "O arquivo esta vazio",
+            ));
+        }
+
+        let storage = storage.clone();
+        let stored = web::block(move || {
+            let _lock = lock;
+            store_file(&storage, &temp, sha256, &head, file_name)
+        })
+        .await;
+        return match stored {
+            Ok(Ok(upload)) => Ok(upload),
+            Ok(Err(err)) => Err(storage_error(err)),
+            Err(err) => Err(storage_error(err)),
+        };
+    }
+}
+
+// Moves a completely received file into storage, images lose their metadata on the way and get
+// their thumbnails. Runs on the blocking pool, temp is gone once it returns.
+fn store_file(
+    storage: &Storage,
+    temp: &FsPath,
+    sha256: String,
+    head: &[u8],
+    file_name: String,
+) -> io::Result<Upload> {
+    let res = store_file_inner(storage, temp, sha256, head, file_name);
+    if res.is_err() {
+        let _ = fs::remove_file(temp);
+    }
+    res
+}
+
+fn store_file_inner(
+    storage: &Storage,
+    temp: &FsPath,
+    mut sha256: String,
+    head: &[u8],
+    file_name: String,
+) -> io::Result<Upload> {
+    let mut mime_type = sniff(head);
+    let mut image = None;
+    if let Some(format) = image_format(mime_type) {
+        match strip_metadata(fs::read(temp)?, format) {
+            Ok((stripped, stripped_image)) => {
+                fs::write(temp, stripped)?;
+                sha256 = hash_file(temp, 0)?.0;
+                image = Some(stripped_image);
+            }
+            // Kept as a plain file, nothing decodes it anymore so its metadata can't be trusted
+            // to be gone either
+            Err(err) => {
+                log::warn!("Upload {} is not a valid {}: {}", file_name, mime_type, err);
+                mime_type = "application/octet-stream";
+            }
+        }
+    }
+    let size = fs::metadata(temp)?.len();
+    storage.persist(temp, &sha256)?;
+    if let Some(image) = &image {
+        if let Err(err) = image.write_thumbnails(storage, &sha256) {
+            log::error!("Error writing thumbnails of {}: {}", sha256, err);
+        }
+    }
+    Ok(Upload {
+        sha256,
+        file_name,
+        mime_type: mime_type.to_string(),
+        size: size as i64,
+        image: image.map(|image| image.info),
+    })
+}
+
+// receive_upload for user and chat images
+pub async fn receive_image(payload: Multipart, app_ctx: &AppContext) -> Result<Upload, ApiError> {
+    let upload = receive_upload(
+        payload,
+        &app_ctx.storage,
+        app_ctx.config.storage.max_upload_bytes,
+    )
+    .await?;
+    if !IMAGE_TYPES.contains(&upload.mime_type.as_str()) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "A imagem deve ser png, jpeg, gif ou webp",
+        ));
+    }
+    Ok(upload)
+}
+
+// Hashes the field while writing it to path, also keeps its first bytes for sniff
+async fn write_field(
+    mut field: Field,
+    path: PathBuf,
+    max_bytes: usize,
+) -> Result<(String, usize, Vec<u8>), ApiError> {
+    let mut file = web::block(move || File::create(path))
+        .await
+        .map_err(storage_error)?
+        .map_err(storage_error)?;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::new();
+    let mut size = 0;
+    loop {
+        let chunk = match field.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                return Err(ApiError::new(
+                    StatusCode::BAD_REQUEST,
+                    format!("Erro ao receber arquivo: {}", err),
+                ))
+            }
+        };
+        size += chunk.len();
+        if size > max_bytes {
+            return Err(ApiError::new(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Arquivos podem ter no maximo {} bytes", max_bytes),
+            ));
+        }
+        hasher.update(&chunk);
+        if head.len() < SNIFF_LEN {
+            let take = chunk.len().min(SNIFF_LEN - head.len());
+            head.extend_from_slice(&chunk[..take]);
+        }
+        file = web::block(move || file.write_all(&chunk).map(|_| file))
+            .await
+            .map_err(storage_error)?
+            .map_err(storage_error)?;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size, head))
+}
+
+// Only the last path component without control characters, "arquivo" when nothing is left
+fn clean_file_name(file_name: Option<&str>) -> String {
+    let file_name = file_name
+        .unwrap_or_default()
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or_default();
+    let file_name: String = file_name
+        .chars()
+        .filter(|c| !c.is_control())
+        .take(MAX_FILE_NAME_LEN)
+        .collect();
+    match file_name.trim() {
+        "" | "." | ".." => "arquivo".into(),
+        file_name => file_name.to_string(),
+    }
+}
+
+fn sniff(head: &[u8]) -> &'static str {
+    if let Some(kind) = infer::get(head) {
+        return kind.mime_type();
+    }
+    // head may end in the middle of a character
+    match std::str::from_utf8(head) {
+        Ok(_) => "text/plain",
+        Err(err) if err.error_len().is_none() => "text/plain",
+        Err(_) => "application/octet-stream",
+    }
+}
+
+// Only media opens in the browser, anything else is downloaded so it can't run as one of our
+// pages
+fn shown_inline(mime_type: &str) -> bool {
+    ["image/", "video/", "audio/"]
+        .iter()
+        .any(|prefix| mime_type.starts_with(prefix))
+}
+
+// The thumbnail of the given size and its type when the image has one, the original otherwise
+async fn open_variant(
+    storage: &Storage,
+    attachment: &Attachment,
+    size: Option<u32>,
+) -> io::Result<(NamedFile, Option<&'static str>)> {
+    if let (Some(size), Some(width), Some(height)) = (size, attachment.width, attachment.height) {
+        if width.max(height) > size {
+            let path = storage.thumbnail_path(&attachment.sha256, size);
+            match NamedFile::open_async(path).await {
+                Ok(file) => return Ok((file, Some(thumbnail_mime_type(&attachment.mime_type)))),
+                Err(err) => log::error!(
+                    "Thumbnail {} of attachment {} is missing: {}",
+                    size,
+                    attachment.id,
+                    err
+                ),
+            }
+        }
+    }
+    let file = NamedFile::open_async(storage.blob_path(&attachment.sha256)).await?;
+    Ok((file, None))
+}
+
+async fn serve_attachment(
+    req: &HttpRequest,
+    storage: &Storage,
+    attachment: &Attachment,
+    size: Option<u32>,
+) -> Result<HttpResponse, ApiError> {
+    let (file, mime_type) = match open_variant(storage, attachment, size).await {
+        Ok((file, thumbnail)) => (file, thumbnail.unwrap_or(&attachment.mime_type)),
+        Err(err) => {
+            log::error!("File of attachment {} is missing: {}", attachment.id, err);
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Arquivo nao encontrado",
+            ));
+        }
+    };
+    let mut parameters = vec![DispositionParam::Filename(attachment.file_name.clone())];
+    if !attachment.file_name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".into()),
+            language_tag: None,
+            value: attachment.file_name.clone().into_bytes(),
+        }));
+    }
+    let disposition = if shown_inline(mime_type) {
+        DispositionType::Inline
+    } else {
+        DispositionType::Attachment
+    };
+    let mut res = file
+        .set_content_type(mime_type.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM))
+        .set_content_disposition(ContentDisposition {
+            disposition,
+            parameters,
+        })
+        .into_response(req);
+    let headers = res.headers_mut();
+    headers.insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    // The contents behind an id never change
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static("private, max-age=31536000, immutable"),
+    );
+    Ok(res)
+}
+
+// Uploads a file to the chat, it shows up once its id is sent in a message's attachments
+#[post("/upload/{uuid}")]
+async fn upload_attachment(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let chat_id = path.into_inner();
+    let access = authorize_chat(&app_ctx.db, user, &chat_id, ChatRole::MEMBER).await?;
+    let upload = receive_upload(
+        payload,
+        &app_ctx.storage,
+        app_ctx.config.storage.max_upload_bytes,
+    )
+    .await?;
+
+    let insert = upload.into_insert(access.user_id, Some(chat_id));
+    match app_ctx
+        .db
+        .write(move |db| db.insert_attachment(insert))
+        .await
+    {
+        Ok(attachment) => Ok(HttpResponse::Created().json(attachment)),
+        Err(err) => {
+            log::error!("Error saving attachment: {}", err);
+            Err(ApiError::internal("Erro ao salvar anexo"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DownloadQuery {
+    // One of THUMBNAIL_SIZES, files that aren't images are always served whole
+    pub size: Option<u32>,
+}
+
+// Files of a chat are only served to its members, user avatars to anyone logged in
+#[get("/{attachment_id}")]
+async fn download_attachment(
+    req: HttpRequest,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+    query: Query<DownloadQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if let Some(size) = query.size {
+        if !THUMBNAIL_SIZES.contains(&size) {
+            let sizes: Vec<String> = THUMBNAIL_SIZES.iter().map(u32::to_string).collect();
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Tamanho invalido, use {}", sizes.join(", ")),
+            ));
+        }
+    }
+    let attachment_id = path.into_inner();
+    let attachment = match app_ctx
+        .db
+        .read(move |db| db.get_attachment(&attachment_id))
+        .await
+    {
+        Ok(Some(attachment)) => attachment,
+        Ok(None) => return Err(ApiError::new(StatusCode::NOT_FOUND, "Anexo nao encontrado")),
+        Err(err) => {
+            log::error!("Error fetching attachment: {}", err);
+            return Err(ApiError::internal("Erro ao buscar anexo"));
+        }
+    };
+    if let Some(chat_id) = &attachment.chat_id {
+        authorize_chat(&app_ctx.db, user, chat_id, ChatRole::MEMBER).await?;
+    }
+    serve_attachment(&req, &app_ctx.storage, &attachment, query.size).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUploadRoute {
+    pub file_name: String,
+    // Bytes of the whole file
+    pub size: i64,
+}
+
+// Headers shared by every response about a resumable upload, named like tus' ones
+fn upload_headers(res: &mut HttpResponseBuilder, upload: &ResumableUpload) {
+    res.insert_header(("Upload-Offset", upload.offset.to_string()))
+        .insert_header(("Upload-Length", upload.size.to_string()))
+        .insert_header(("Upload-Expires", http_date(&upload.expires_at)))
+        .insert_header((header::CACHE_CONTROL, "no-store"));
+}
+
+fn http_date(date: &str) -> String {
+    match NaiveDateTime::parse_from_str(date, "%Y-%m-%d %H:%M:%S") {
+        Ok(date) => date.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+        Err(_) => date.to_string(),
+    }
+}
+
+fn upload_expires_at(app_ctx: &AppContext) -> String {
+    let expiry = Duration::seconds(app_ctx.config.storage.upload_expiry_secs as i64);
+    format_date(Utc::now() + expiry)
+}
+
+// Uploads are only visible to whoever created them, expired ones are treated as gone even before
+// Cleanup gets to them
+async fn find_upload(
+    app_ctx: &AppContext,
+    user: &SessionUser,
+    upload_id: String,
+) -> Result<ResumableUpload, ApiError> {
+    let upload = match app_ctx.db.read(move |db| db.get_upload(&upload_id)).await {
+        Ok(upload) => upload,
+        Err(err) => {
+            log::error!("Error fetching upload: {:?}", err);
+            return Err(ApiError::internal("Erro ao buscar upload"));
+        }
+    };
+    match upload {
+        Some(upload)
+            if upload.user_id == user.0 && upload.expires_at >= format_date(Utc::now()) =>
+        {
+            Ok(upload)
+        }
+        _ => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Upload nao encontrado",
+        )),
+    }
+}
+
+// Starts a resumable upload to the chat, the file is then sent in chunks with PATCH
+#[post("/uploads/{uuid}")]
+async fn create_resumable_upload(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+    body: Json<CreateUploadRoute>,
+) -> Result<HttpResponse, ApiError> {
+    let chat_id = path.into_inner();
+    let access = authorize_chat(&app_ctx.db, user, &chat_id, ChatRole::MEMBER).await?;
+    let max_bytes = app_ctx.config.storage.max_upload_bytes;
+    if body.size <= 0 {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "O arquivo esta vazio",
+        ));
+    }
+    if body.size as u64 > max_bytes as u64 {
+        return Err(ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("Arquivos podem ter no maximo {} bytes", max_bytes),
+        ));
+    }
+
+    let create = CreateUpload {
+        user_id: access.user_id,
+        chat_id,
+        file_name: clean_file_name(Some(&body.file_name)),
+        size: body.size,
+        date_created: format_date(Utc::now()),
+        expires_at: upload_expires_at(&app_ctx),
+    };
+    let upload = match app_ctx.db.write(move |db| db.create_upload(create)).await {
+        Ok(upload) => upload,
+        Err(err) => {
+            log::error!("Error creating upload: {:?}", err);
+            return Err(ApiError::internal("Erro ao criar upload"));
+        }
+    };
+    let mut res = HttpResponse::Created();
+    upload_headers(&mut res, &upload);
+    res.insert_header((
+        header::LOCATION,
+        format!("/attachments/uploads/{}", upload.id),
+    ));
+    Ok(res.json(upload))
+}
+
+// How much of the upload was received, where an interrupted upload resumes from
+#[head("/uploads/{upload_id}")]
+async fn resumable_upload_progress(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let upload = find_upload(&app_ctx, &user, path.into_inner()).await?;
+    let mut res = HttpResponse::Ok();
+    upload_headers(&mut res, &upload);
+    Ok(res.finish())
+}
+
+// Appends a chunk, Upload-Offset has to be the offset the server has. The chunk that completes
+// the file turns the upload into an attachment, returned like POST /upload does.
+#[patch("/uploads/{upload_id}")]
+async fn resumable_upload_chunk(
+    req: HttpRequest,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+    payload: Payload,
+) -> Result<HttpResponse, ApiError> {
+    let content_type = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    if content_type != Some(CHUNK_CONTENT_TYPE) {
+        return Err(ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("Envie os pedacos como {}", CHUNK_CONTENT_TYPE),
+        ));
+    }
+    let Some(offset) = req
+        .headers()
+        .get("Upload-Offset")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<i64>().ok())
+    else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Header Upload-Offset invalido",
+        ));
+    };
+
+    let upload_id = path.into_inner();
+    // Taken before reading the offset so two requests can't both write from it
+    let Some(_lock) = app_ctx.storage.lock_upload(&upload_id) else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Outro pedaco deste upload esta sendo recebido",
+        ));
+    };
+    let mut upload = find_upload(&app_ctx, &user, upload_id).await?;
+    if offset != upload.offset {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            format!("Upload-Offset deveria ser {}", upload.offset),
+        ));
+    }
+
+    let partial = app_ctx.storage.partial_path(&upload.id);
+    let written = write_chunk(payload, partial.clone(), upload.offset, upload.size).await;
+    // Whatever arrived is kept even when the chunk was cut short, the client resumes from there
+    let received = match &written {
+        Ok(received) => *received,
+        Err((received, _)) => *received,
+    };
+    upload.offset += received as i64;
+    upload.expires_at = upload_expires_at(&app_ctx);
+    let (id, offset, expires_at) = (upload.id.clone(), upload.offset, upload.expires_at.clone());
+    if let Err(err) = app_ctx
+        .db
+        .write(move |db| db.set_upload_offset(&id, offset, &expires_at))
+        .await
+    {
+        log::error!("Error saving upload offset: {:?}", err);
+        return Err(ApiError::internal("Erro ao salvar upload"));
+    }
+    if let Err((_, err)) = written {
+        return Err(err);
+    }
+
+    if upload.offset < upload.size {
+        let mut res = HttpResponse::NoContent();
+        upload_headers(&mut res, &upload);
+        return Ok(res.finish());
+    }
+    let attachment = finish_upload(&app_ctx, user, &upload, partial).await?;
+    let mut res = HttpResponse::Ok();
+    upload_headers(&mut res, &upload);
+    Ok(res.json(attachment))
+}
+
+// Writes the body at offset, returns how many bytes were written, also alongside the error when
+// it fails midway
+async fn write_chunk(
+    mut payload: Payload,
+    path: PathBuf,
+    offset: i64,
+    size: i64,
+) -> Result<usize, (usize, ApiError)> {
+    // Anything past offset is left over from a chunk that failed after being written
+    let opened = web::block(move || {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(false)
+            .open(path)?;
+        if (file.metadata()?.len() as i64) < offset {
+            return Ok(None);
+        }
+        file.set_len(offset as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok::<_, std::io::Error>(Some(file))
+    })
+    .await;
+    let mut file = match opened {
+        Ok(Ok(Some(file))) => file,
+        // Cleanup removed the file of an upload that was about to expire
+        Ok(Ok(None)) => {
+            return Err((
+                0,
+                ApiError::new(StatusCode::GONE, "Upload expirado, comece de novo"),
+            ))
+        }
+        Ok(Err(err)) => return Err((0, storage_error(err))),
+        Err(err) => return Err((0, storage_error(err))),
+    };
+
+    let mut received = 0;
+    loop {
+        let chunk = match payload.try_next().await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(err) => {
+                return Err((
+                    received,
+                    ApiError::new(
+                        StatusCode::BAD_REQUEST,
+                        format!("Erro ao receber arquivo: {}", err),
+                    ),
+                ))
+            }
+        };
+        if offset + (received + chunk.len()) as i64 > size {
+            return Err((
+                received,
+                ApiError::new(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    format!("O upload tem apenas {} bytes", size),
+                ),
+            ));
+        }
+        let len = chunk.len();
+        file = match web::block(move || file.write_all(&chunk).map(|_| file)).await {
+            Ok(Ok(file)) => file,
+            Ok(Err(err)) => return Err((received, storage_error(err))),
+            Err(err) => return Err((received, storage_error(err))),
+        };
+        received += len;
+    }
+    Ok(received)
+}
+
+// Stores a copy of the completed file, the partial file stays until its attachment is saved so
+// finishing can be retried when that fails
+fn store_partial(storage: &Storage, partial: &FsPath, file_name: String) -> io::Result<Upload> {
+    let (temp, _lock) = storage.temp_path();
+    fs::copy(partial, &temp)?;
+    let (sha256, head) = hash_file(&temp, SNIFF_LEN)?;
+    store_file(storage, &temp, sha256, &head, file_name)
+}
+
+// Stores the completed file and replaces the upload with an attachment
+async fn finish_upload(
+    app_ctx: &AppContext,
+    user: SessionUser,
+    upload: &ResumableUpload,
+    partial: PathBuf,
+) -> Result<Attachment, ApiError> {
+    // The uploader may have left the chat since the upload started
+    let access = authorize_chat(&app_ctx.db, user, &upload.chat_id, ChatRole::MEMBER).await?;
+    let storage = app_ctx.storage.clone();
+    let file_name = upload.file_name.clone();
+    let stored = {
+        let partial = partial.clone();
+        web::block(move || store_partial(&storage, &partial, file_name)).await
+    };
+    let insert = match stored {
+        Ok(Ok(stored)) => stored.into_insert(access.user_id, Some(upload.chat_id.clone())),
+        Ok(Err(err)) => return Err(storage_error(err)),
+        Err(err) => return Err(storage_error(err)),
+    };
+    let upload_id = upload.id.clone();
+    let attachment = match app_ctx
+        .db
+        .write(move |db| {
+            let attachment = db.insert_attachment(insert)?;
+            db.delete_upload(&upload_id)?;
+            Ok(attachment)
+        })
+        .await
+    {
+        Ok(attachment) => attachment,
+        Err(err) => {
+            log::error!("Error saving attachment: {:?}", err);
+            return Err(ApiError::internal("Erro ao salvar anexo"));
+        }
+    };
+    match web::block(move || fs::remove_file(partial)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) => log::error!("Error removing upload {}: {}", upload.id, err),
+        Err(err) => log::error!("Error removing upload {}: {}", upload.id, err),
+    }
+    Ok(attachment)
+}
+
+// Gives up on an upload, what was received is removed
+#[delete("/uploads/{upload_id}")]
+async fn cancel_resumable_upload(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let upload_id = path.into_inner();
+    let Some(_lock) = app_ctx.storage.lock_upload(&upload_id) else {
+        return Err(ApiError::new(
+            StatusCode::CONFLICT,
+            "Outro pedaco deste upload esta sendo recebido",
+        ));
+    };
+    let upload = find_upload(&app_ctx, &user, upload_id).await?;
+    let id = upload.id.clone();
+    if let Err(err) = app_ctx.db.write(move |db| db.delete_upload(&id)).await {
+        log::error!("Error deleting upload: {:?}", err);
+        return Err(ApiError::internal("Erro ao cancelar upload"));
+    }
+    let partial = app_ctx.storage.partial_path(&upload.id);
+    match web::block(move || fs::remove_file(partial)).await {
+        Ok(Ok(())) => (),
+        Ok(Err(err)) if err.kind() == std::io::ErrorKind::NotFound => (),
+        Ok(Err(err)) => log::error!("Error removing upload {}: {}", upload.id, err),
+        Err(err) => log::error!("Error removing upload {}: {}", upload.id, err),
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_file_name_keeps_only_the_base_name() {
+        assert_eq!(clean_file_name(Some("foto.jpg")), "foto.jpg");
+        assert_eq!(clean_file_name(Some("../../etc/passwd")), "passwd");
+        assert_eq!(clean_file_name(Some("C:\\Users\\ana\\doc.pdf")), "doc.pdf");
+        assert_eq!(clean_file_name(Some("a\u{0}b\nc.txt")), "abc.txt");
+        assert_eq!(clean_file_name(Some("  nome.txt ")), "nome.txt");
+    }
+
+    #[test]
+    fn clean_file_name_replaces_empty_names() {
+        assert_eq!(clean_file_name(None), "arquivo");
+        assert_eq!(clean_file_name(Some("")), "arquivo");
+        assert_eq!(clean_file_name(Some("dir/")), "arquivo");
+        assert_eq!(clean_file_name(Some("..")), "arquivo");
+        assert_eq!(clean_file_name(Some("a/.")), "arquivo");
+    }
+
+    #[test]
+    fn clean_file_name_limits_the_length() {
+        let long = "é".repeat(MAX_FILE_NAME_LEN + 10);
+        assert_eq!(
+            clean_file_name(Some(&long)).chars().count(),
+            MAX_FILE_NAME_LEN
+        );
+    }
+
+    #[test]
+    fn sniff_uses_the_contents() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff("ola, tudo bem?".as_bytes()), "text/plain");
+        assert_eq!(sniff(b""), "text/plain");
+        assert_eq!(sniff(b"\x80\x81\x82abc"), "application/octet-stream");
+    }
+
+    #[test]
+    fn sniff_accepts_text_cut_in_the_middle_of_a_character() {
+        let text = "ação".as_bytes();
+        assert_eq!(sniff(&text[..2]), "text/plain");
+    }
+
+    // A partial file holding contents, removed when the test ends
+    struct PartialFile(PathBuf);
+
+    impl PartialFile {
+        fn new(name: &str, contents: &[u8]) -> PartialFile {
+            let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+            fs::write(&path, contents).unwrap();
+            PartialFile(path)
+        }
+
+        fn contents(&self) -> Vec<u8> {
+            fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for PartialFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    async fn chunk(body: &'static [u8]) -> Payload {
+        let (req, mut payload) = actix_web::test::TestRequest::default()
+            .set_payload(body)
+            .to_http_parts();
+        <Payload as actix_web::FromRequest>::from_request(&req, &mut payload)
+            .await
+            .unwrap()
+    }
+
+    fn status(result: Result<usize, (usize, ApiError)>) -> (usize, StatusCode) {
+        let (received, err) = result.unwrap_err();
+        (received, actix_web::ResponseError::status_code(&err))
+    }
+
+    #[actix_web::test]
+    async fn write_chunk_appends_at_the_offset() {
+        let file = PartialFile::new("write-chunk-append", b"abcd");
+        let written = write_chunk(chunk(b"ef").await, file.0.clone(), 4, 10).await;
+        assert_eq!(written.unwrap(), 2);
+        assert_eq!(file.contents(), b"abcdef");
+    }
+
+    #[actix_web::test]
+    async fn write_chunk_drops_what_is_past_the_offset() {
+        // The end of a chunk that was written but never recorded
+        let file = PartialFile::new("write-chunk-leftover", b"abcdXY");
+        let written = write_chunk(chunk(b"ef").await, file.0.clone(), 4, 10).await;
+        assert_eq!(written.unwrap(), 2);
+        assert_eq!(file.contents(), b"abcdef");
+    }
+
+    #[actix_web::test]
+    async fn write_chunk_refuses_a_file_shorter_than_the_offset() {
+        let file = PartialFile::new("write-chunk-short", b"ab");
+        let written = write_chunk(chunk(b"ef").await, file.0.clone(), 4, 10).await;
+        assert_eq!(status(written), (0, StatusCode::GONE));
+        assert_eq!(file.contents(), b"ab");
+    }
+
+    #[actix_web::test]
+    async fn write_chunk_refuses_more_than_the_size() {
+        let file = PartialFile::new("write-chunk-too-large", b"abcd");
+        let written = write_chunk(chunk(b"efgh").await, file.0.clone(), 4, 6).await;
+        assert_eq!(status(written), (0, StatusCode::PAYLOAD_TOO_LARGE));
+        assert_eq!(file.contents(), b"abcd");
+
+        let written = write_chunk(chunk(b"ef").await, file.0.clone(), 4, 6).await;
+        assert_eq!(written.unwrap(), 2);
+        assert_eq!(file.contents(), b"abcdef");
+    }
+
+    #[test]
+    fn store_partial_can_be_retried_after_saving_fails() {
+        let root = std::env::temp_dir().join(format!("store-partial-{}", std::process::id()));
+        let storage = Storage::open(&crate::config::StorageConfig {
+            root: root.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let partial = storage.partial_path("upload");
+        fs::write(&partial, "conteudo do arquivo").unwrap();
+
+        // The attachment was never saved, so the upload is finished a second time
+        let first = store_partial(&storage, &partial, "a.txt".into()).unwrap();
+        assert!(partial.exists());
+        let second = store_partial(&storage, &partial, "a.txt".into()).unwrap();
+        assert_eq!(first.sha256, second.sha256);
+        assert_eq!(second.size, 19);
+        assert_eq!(second.mime_type, "text/plain");
+        assert_eq!(
+            fs::read(storage.blob_path(&second.sha256)).unwrap(),
+            b"conteudo do arquivo"
+        );
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    fmt,
+    future::{ready, Ready},
+};
+
+use actix_session::SessionExt;
+use actix_web::{
+    dev::Payload, http::StatusCode, FromRequest, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::Serialize;
+
+use crate::db::{
+    chat_db::{ChatRole, ChatTable},
+    DatabasePool,
+};
+
+use super::user_route::UserSession;
+
+// Errors of the authorization layer are always answered with a JSON body: {"error": "..."}
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct ApiErrorBody<'a> {
+    error: &'a str,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, message)
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.status, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status).json(ApiErrorBody {
+            error: &self.message,
+        })
+    }
+}
+
+// Extracting it rejects the request with a 401 when nobody is logged in.
+#[derive(Debug, Clone, Copy)]
+pub struct SessionUser(pub i64);
+
+impl FromRequest for SessionUser {
+    type Error = ApiError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.get_session().get_user_id() {
+            Ok(Some(user_id)) => Ok(SessionUser(user_id)),
+            Ok(None) => Err(ApiError::unauthorized("Usuario nao logado")),
+            Err(_) => Err(ApiError::internal(
+                "Erro ao adquirir id do usuario de sessao",
+            )),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatAccess {
+    pub user_id: i64,
+    pub chat_id: String,
+    pub role: ChatRole,
+}
+
+// Every chat endpoint goes through here. Chats the user is not part of answer 403 just like
+// missing ones so chat ids can't be probed.
+pub async fn authorize_chat(
+    db: &DatabasePool,
+    user: SessionUser,
+    chat_id: &str,
+    required: ChatRole,
+) -> Result<ChatAccess, ApiError> {
+    let SessionUser(user_id) = user;
+    let id = chat_id.to_string();
+    let role = match db.read(move |db| db.get_chat_role(&id, user_id)).await {
+        Ok(role) => role,
+        Err(err) => {
+            log::error!("Error fetching role of {} in chat {}: {}", user_id, chat_id, err);
+            return Err(ApiError::internal("Erro verificando membros do chat"));
+        }
+    };
+
+    let Some(role) = role else {
+        return Err(ApiError::forbidden("Voce nao faz parte desse chat"));
+    };
+    if role < required {
+        return Err(ApiError::forbidden(format!(
+            "Essa acao requer o cargo {} no chat",
+            required.as_str()
+        )));
+    }
+
+    Ok(ChatAccess {
+        user_id,
+        chat_id: chat_id.to_string(),
+        role,
+    })
+}
@@ -0,0 +1,109 @@
+use actix_web::{
+    get,
+    http::{
+        header::{self, EntityTag, IfNoneMatch},
+        StatusCode,
+    },
+    web::{self, Data, Path},
+    HttpMessage, HttpRequest, HttpResponse, Scope,
+};
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    avatar::render_avatar,
+    db::{
+        chat_db::{ChatRole, ChatTable},
+        user_db::UserTable,
+    },
+    AppContext,
+};
+
+use super::authorization::{authorize_chat, ApiError, SessionUser};
+
+// Generated avatars, the user_image and chat_image of whoever didn't upload one
+pub fn avatar_scope() -> Scope {
+    web::scope("/avatars")
+        .service(user_avatar)
+        .service(chat_avatar)
+}
+
+// The URL stays the same when the name changes, so clients always revalidate and get a 304
+// while the initials are still the same
+fn avatar_response(req: &HttpRequest, svg: String) -> HttpResponse {
+    let etag = EntityTag::new_strong(format!("{:x}", Sha256::digest(svg.as_bytes())));
+    let unchanged = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+    let mut res = if unchanged {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    res.insert_header(header::ETag(etag))
+        .insert_header((header::CACHE_CONTROL, "private, no-cache"))
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        // Only ever shown as an image, nothing in it should run when it is opened directly
+        .insert_header((header::CONTENT_SECURITY_POLICY, "default-src 'none'"));
+    if unchanged {
+        return res.finish();
+    }
+    res.content_type("image/svg+xml").body(svg)
+}
+
+// Anyone logged in can see any user's avatar, same as uploaded ones
+#[get("/users/{user_id}")]
+async fn user_avatar(
+    req: HttpRequest,
+    _user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<i64>,
+) -> Result<HttpResponse, ApiError> {
+    let user_id = path.into_inner();
+    let user = match app_ctx
+        .db
+        .read(move |db| db.get_user(user_id).optional())
+        .await
+    {
+        Ok(Some(user)) => user,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Usuario nao encontrado",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error fetching user {} for avatar: {:?}", user_id, err);
+            return Err(ApiError::internal("Erro ao buscar usuario"));
+        }
+    };
+    let svg = render_avatar(&format!("user:{}", user.user_id), &user.user_nick);
+    Ok(avatar_response(&req, svg))
+}
+
+// The chat's name is only known to its members
+#[get("/chats/{chat_id}")]
+async fn chat_avatar(
+    req: HttpRequest,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let chat_id = path.into_inner();
+    let access = authorize_chat(&app_ctx.db, user, &chat_id, ChatRole::MEMBER).await?;
+    let chat = match app_ctx
+        .db
+        .read(move |db| db.get_chat(&access.chat_id, access.user_id))
+        .await
+    {
+        Ok(chat) => chat,
+        Err(err) => {
+            log::error!("Error fetching chat {} for avatar: {:?}", chat_id, err);
+            return Err(ApiError::internal("Erro ao buscar chat"));
+        }
+    };
+    let svg = render_avatar(&format!("chat:{}", chat.chat_id), &chat.chat_name);
+    Ok(avatar_response(&req, svg))
+}
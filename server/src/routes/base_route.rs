@@ -1,4 +1,3 @@
-use actix_session::Session;
 use actix_web::{
     get,
     web::{Data, Payload},
@@ -8,7 +7,7 @@ use actix_web_actors::ws;
 
 use crate::{sockets::info::info_socket::InfoWS, AppContext};
 
-use super::user_route::{get_user_id, RespostaAdquirirIdSessao};
+use super::authorization::SessionUser;
 
 pub fn base_scope() -> Scope {
     Scope::new("/").service(info_route)
@@ -21,18 +20,18 @@ pub async fn index_route() -> impl Responder {
 
 #[get("/info")]
 pub async fn info_route(
-    session: Session,
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     req: HttpRequest,
     stream: Payload,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let user_id = get_user_id(&session);
-    let RespostaAdquirirIdSessao::Id(user_id) = user_id else {
-        let RespostaAdquirirIdSessao::Erro(err) = user_id else {
-            panic!("")
-        };
-        return Ok(err);
-    };
-    let actor = InfoWS::new(user_id, app_ctx.info_server.clone());
-    ws::start(actor, &req, stream)
+    let SessionUser(user_id) = user;
+    let actor = InfoWS::new(
+        user_id,
+        app_ctx.info_server.clone(),
+        app_ctx.config.sockets,
+    );
+    ws::WsResponseBuilder::new(actor, &req, stream)
+        .frame_size(app_ctx.config.limits.max_ws_frame)
+        .start()
 }
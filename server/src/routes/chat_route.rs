@@ -1,38 +1,82 @@
+use actix_multipart::Multipart;
 use actix_session::Session;
 use actix_web::{
-    get, post,
+    get,
+    http::StatusCode,
+    post,
     web::{self, Data, Json, Path, Payload, Query},
     HttpRequest, HttpResponse, Responder, Scope,
 };
 use actix_web_actors::ws;
-use serde::Deserialize;
+use chrono::Utc;
+use rusqlite::ErrorCode;
+use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use crate::{
     db::{
-        chat_db::{Chat, ChatTable, ChatTypes},
-        chat_message_db::ChatMessagesTable,
+        attachment_db::{Attachment, AttachmentsTable},
+        chat_db::{Chat, ChatRole, ChatTable, ChatTypes},
+        chat_message_db::{
+            ChatMessage, ChatMessagesTable, DeleteChatMessage, DeleteOutcome, EditChatMessage,
+            EditOutcome, MessageCursor, MessagePage, MAX_DELETE_REASON_LEN,
+        },
+        message_reaction_db::{
+            valid_emoji, MessageReactionsTable, Reaction, ReactionCount, ReactionOutcome,
+            MAX_EMOJI_LEN,
+        },
+        message_search_db::{MessageSearchTable, SearchQuery},
     },
+    message::{format_date, ProtocolVersion},
     routes::user_route::RespostaAdquirirIdSessao,
     sockets::{
-        chat::{lobby_actor::ChatDeleted, lobby_socket::ChatWs},
+        chat::{
+            lobby_actor::{
+                ChatDeleted, MemberRemoved, MessageDeleted, MessageEdited, ReactionChanged,
+                ResumeFrom,
+            },
+            lobby_socket::ChatWs,
+            multiplex_socket::MultiplexWs,
+        },
         info::info_actor::{self, ChatUpdate},
     },
     AppContext,
 };
 
-use super::user_route::{get_user_id, is_logged_in};
+use super::{
+    attachment_route::{receive_image, Upload},
+    authorization::{authorize_chat, ApiError, ChatAccess, SessionUser},
+    user_route::get_user_id,
+};
 
 pub fn chat_scope() -> Scope {
     web::scope("/chat")
         // .service(chat_auth_route)
         .service(connect_to_chat)
+        .service(connect_multiplexed)
         .service(create_chat_route)
         .service(get_chats_router)
         .service(get_messages)
+        .service(edit_message)
+        .service(delete_message)
+        .service(get_message_edits)
+        .service(get_thread_messages)
+        .service(follow_thread)
+        .service(unfollow_thread)
+        .service(add_reaction)
+        .service(remove_reaction)
+        .service(search_messages)
         .service(remove_chat)
         .service(get_chat_router)
         .service(rota_update)
+        .service(set_chat_image)
+        .service(remove_chat_image)
+        .service(join_chat)
+        .service(leave_chat)
+        .service(kick_member)
+        .service(set_member_role)
+        .service(get_members)
+        .service(open_direct_chat)
 }
 
 #[get("/auth")]
@@ -79,14 +123,14 @@ pub fn get_auth_token(app_ctx: Data<AppContext>, uuid: Uuid) -> AuthTokenRespons
 
         let auth_token: i64;
         {
-            let Some(auth_token_local) = auth_tokens.get(&uuid).clone() else {
+            let Some(auth_token_local) = auth_tokens.get(&uuid) else {
                 return AuthTokenResponse::Ok(None);
             };
             auth_token = *auth_token_local;
         }
         (*auth_tokens).remove(&uuid);
 
-        AuthTokenResponse::Ok(Some(auth_token.clone()))
+        AuthTokenResponse::Ok(Some(auth_token))
     }
 }
 
@@ -96,11 +140,9 @@ pub struct CreateChatRoute {
 }
 
 #[get("/")]
-pub async fn get_chats_router(app_ctx: Data<AppContext>) -> impl Responder {
-    let Ok(db) = app_ctx.db.lock() else {
-        return HttpResponse::InternalServerError().body("Erro adquirindo db");
-    };
-    let chats = db.get_chats();
+pub async fn get_chats_router(user: SessionUser, app_ctx: Data<AppContext>) -> impl Responder {
+    let SessionUser(user_id) = user;
+    let chats = app_ctx.db.read(move |db| db.get_chats(user_id)).await;
     let Ok(chats) = chats else {
         println!("{:?}", chats.unwrap_err());
         return HttpResponse::InternalServerError().body("Erro adquirindo chats");
@@ -114,43 +156,47 @@ pub struct GetChatQuery {
 }
 #[get("/get")]
 pub async fn get_chat_router(
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     query: Query<GetChatQuery>,
-) -> impl Responder {
-    let Ok(db) = app_ctx.db.lock() else {
-        return HttpResponse::InternalServerError().body("Erro adquirindo db");
-    };
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &query.id, ChatRole::MEMBER).await?;
 
-    let chat = db.get_chat(&query.id, ChatTypes::GROUP);
+    let (chat_id, user_id) = (access.chat_id, access.user_id);
+    let chat = app_ctx
+        .db
+        .read(move |db| db.get_chat(&chat_id, user_id))
+        .await;
     let Ok(chat) = chat else {
         log::error!("{:?}", chat.unwrap_err());
-        return HttpResponse::InternalServerError().body("Erro adquirindo chat");
+        return Err(ApiError::internal("Erro adquirindo chat"));
     };
 
-    HttpResponse::Ok().json(chat)
+    Ok(HttpResponse::Ok().json(chat))
 }
 
 #[post("/create")]
 pub async fn create_chat_route(
-    session: Session,
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     body: Json<CreateChatRoute>,
 ) -> impl Responder {
-    let is_logged_in = is_logged_in(&session);
-    let Ok(user_id) = is_logged_in else {
-        return is_logged_in.unwrap_err();
-    };
-    let Ok(db) = app_ctx.db.lock() else {
-        return HttpResponse::InternalServerError().body("Error fetching db from context");
-    };
-
-    let res = db.create_chat(&body.nome, user_id);
+    let SessionUser(user_id) = user;
+    let nome = body.nome.clone();
+    let res = app_ctx
+        .db
+        .write(move |db| db.create_chat(&nome, user_id))
+        .await;
     let Ok(chat_id) = res else {
         log::error!("Error creating chat, {:?}", res.unwrap_err());
         return HttpResponse::InternalServerError().body("Erro ao criar grupo de chat.")
     };
 
-    let chat = db.get_chat(&chat_id, ChatTypes::GROUP);
+    let id = chat_id.clone();
+    let chat = app_ctx
+        .db
+        .read(move |db| db.get_chat(&id, user_id))
+        .await;
     let Ok(chat) = chat else {
         let err = chat.unwrap_err();
         return HttpResponse::InternalServerError().body(err.to_string())
@@ -160,7 +206,8 @@ pub async fn create_chat_route(
         .info_server
         .send(info_actor::ChatCreated {
             room_id: chat_id.clone(),
-            user_id: user_id,
+            user_id,
+            members: vec![user_id],
         })
         .await
     {
@@ -173,6 +220,32 @@ pub async fn create_chat_route(
 #[derive(Debug, Deserialize)]
 pub struct QueryConnectChat {
     pub t: ChatTypes,
+    pub protocol_version: Option<u8>,
+    // Reconnecting clients send the last message they saw to get the ones they missed
+    pub last_seq: Option<i64>,
+    pub last_message_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryProtocol {
+    pub protocol_version: Option<u8>,
+}
+
+// Clients that don't ask for a version get version 1, the original format
+fn negotiate_protocol(requested: Option<u8>) -> Result<ProtocolVersion, ApiError> {
+    let Some(requested) = requested else {
+        return Ok(ProtocolVersion::default());
+    };
+    ProtocolVersion::from_number(requested).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Versao de protocolo {} nao suportada, use uma de {:?}",
+                requested,
+                ProtocolVersion::SUPPORTED
+            ),
+        )
+    })
 }
 
 #[get("/connect/{uuid}")]
@@ -181,37 +254,125 @@ pub async fn connect_to_chat(
     stream: Payload,
     // srv: Data<Addr<Lobby>>,
     info: Path<ConnectChatInfo>,
-    session: Session,
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     query: Query<QueryConnectChat>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let res = get_user_id(&session);
-    let RespostaAdquirirIdSessao::Id(user_id) = res else {
-        let RespostaAdquirirIdSessao::Erro(err) =  res else {
-            todo!();
-        };
-        return Ok(err);
-    };
+    let protocol = negotiate_protocol(query.protocol_version)?;
+    let access = authorize_chat(&app_ctx.db, user, &info.uuid, ChatRole::MEMBER).await?;
 
+    let (chat_id, user_id) = (access.chat_id.clone(), access.user_id);
+    let chat = match app_ctx
+        .db
+        .read(move |db| db.get_chat(&chat_id, user_id))
+        .await
     {
-        let Ok(db) = app_ctx.db.lock() else {
-            return Ok(HttpResponse::InternalServerError().body("Erro adquirindo db do contexto"));
-        };
-
-        if db.get_chat(&info.uuid, query.t).is_err() {
-            return Ok(
-                HttpResponse::BadRequest().body(format!("Chat {} nao encontrado", &info.uuid))
-            );
-        };
+        Ok(chat) => chat,
+        Err(err) => {
+            if !err.is_not_found() {
+                log::error!("Error fetching chat {}: {}", &info.uuid, err);
+                return Err(ApiError::internal("Erro adquirindo chat").into());
+            }
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                format!("Chat {} nao encontrado", &info.uuid),
+            )
+            .into());
+        }
+    };
+    if chat.chat_type != query.t {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("Chat {} e do tipo {}", &info.uuid, chat.chat_type.as_str()),
+        )
+        .into());
     }
 
-    let ws = ChatWs::new(info.uuid.clone(), app_ctx.chat_server.clone(), user_id);
-    ws::start(ws, &req, stream)
+    let ws = ChatWs::new(
+        access.chat_id,
+        app_ctx.chat_server.clone(),
+        access.user_id,
+        app_ctx.config.sockets,
+        app_ctx.config.limits,
+        protocol,
+        ResumeFrom::new(query.last_seq, query.last_message_id.clone()),
+    );
+    ws::WsResponseBuilder::new(ws, &req, stream)
+        .frame_size(app_ctx.config.limits.max_ws_frame)
+        .start()
+}
+
+// One socket for all chats, the client subscribes to each chat it wants to follow
+#[get("/ws")]
+pub async fn connect_multiplexed(
+    req: HttpRequest,
+    stream: Payload,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    query: Query<QueryProtocol>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let protocol = negotiate_protocol(query.protocol_version)?;
+    let SessionUser(user_id) = user;
+    let ws = MultiplexWs::new(
+        app_ctx.chat_server.clone(),
+        user_id,
+        app_ctx.config.sockets,
+        app_ctx.config.limits,
+        protocol,
+    );
+    ws::WsResponseBuilder::new(ws, &req, stream)
+        .frame_size(app_ctx.config.limits.max_ws_frame)
+        .start()
 }
 
+// offset alone is the original paging, 10 messages as an array. Any of the cursors (seqs) or
+// limit answers with a MessagePage instead.
 #[derive(Debug, Deserialize)]
 pub struct GetMessagesQuery {
-    offset: usize,
+    offset: Option<usize>,
+    before: Option<i64>,
+    after: Option<i64>,
+    around: Option<i64>,
+    limit: Option<usize>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 50;
+
+impl GetMessagesQuery {
+    fn cursor(&self) -> Result<Option<MessageCursor>, ApiError> {
+        let cursors = [
+            self.before.map(MessageCursor::Before),
+            self.after.map(MessageCursor::After),
+            self.around.map(MessageCursor::Around),
+        ];
+        let mut given = cursors.into_iter().flatten();
+        let cursor = given.next();
+        if given.next().is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "Use apenas um de before, after ou around",
+            ));
+        }
+        if cursor.is_some() && self.offset.is_some() {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "offset nao pode ser usado junto com cursores",
+            ));
+        }
+        Ok(cursor)
+    }
+
+    // Larger pages than the configured maximum are cut down to it
+    fn page_size(&self, max_page_size: usize) -> Result<usize, ApiError> {
+        match self.limit {
+            Some(0) => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "limit deve ser pelo menos 1",
+            )),
+            Some(limit) => Ok(limit.min(max_page_size)),
+            None => Ok(DEFAULT_PAGE_SIZE.min(max_page_size)),
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -221,16 +382,485 @@ pub struct GetMessagesPath {
 
 #[get("/messages/{uuid}")]
 pub async fn get_messages(
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     query: Query<GetMessagesQuery>,
     path: Path<GetMessagesPath>,
-) -> impl Responder {
-    let db = app_ctx.db.lock().unwrap();
-    let res = db.get_chat_messages(path.uuid.to_string().clone(), query.offset);
-    let Ok(messages) = res else {
-        return HttpResponse::InternalServerError().body("Undocumented error getting messages");
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &path.uuid.to_string(), ChatRole::MEMBER).await?;
+
+    let (chat_id, user_id) = (access.chat_id, access.user_id);
+    let cursor = query.cursor()?;
+    if let (None, None, Some(offset)) = (cursor, query.limit, query.offset) {
+        let res = app_ctx
+            .db
+            .read(move |db| {
+                let mut messages = db.get_chat_messages(chat_id, offset)?;
+                db.attach_reactions(&mut messages, user_id)?;
+                Ok(messages)
+            })
+            .await;
+        let Ok(mut messages) = res else {
+            log::error!("Error getting messages {:?}", res.unwrap_err());
+            return Err(ApiError::internal("Undocumented error getting messages"));
+        };
+        if access.role < ChatRole::ADMIN {
+            messages
+                .iter_mut()
+                .for_each(ChatMessage::hide_delete_reason);
+        }
+        return Ok(HttpResponse::Ok().json(messages));
+    }
+
+    let limit = query.page_size(app_ctx.config.limits.max_page_size)?;
+    let cursor = cursor.unwrap_or(MessageCursor::Latest);
+    let res = app_ctx
+        .db
+        .read(move |db| {
+            let mut page = db.get_message_page(&chat_id, None, cursor, limit)?;
+            db.attach_reactions(&mut page.messages, user_id)?;
+            Ok(page)
+        })
+        .await;
+    let Ok(mut page) = res else {
+        log::error!("Error getting messages {:?}", res.unwrap_err());
+        return Err(ApiError::internal("Undocumented error getting messages"));
+    };
+    // Why a message was removed is only for moderators
+    if access.role < ChatRole::ADMIN {
+        page.messages
+            .iter_mut()
+            .for_each(ChatMessage::hide_delete_reason);
+    }
+    Ok(HttpResponse::Ok().json(page))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchMessagesQuery {
+    q: String,
+    cursor: Option<String>,
+    limit: Option<usize>,
+}
+
+// Searches every chat the user is in, q takes words, "phrases" and from:nick, in:chat,
+// before:/after:AAAA-MM-DD and has:attachment
+#[get("/search")]
+pub async fn search_messages(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    query: Query<SearchMessagesQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let SessionUser(user_id) = user;
+    let search = SearchQuery::parse(&query.q)
+        .map_err(|reason| ApiError::new(StatusCode::BAD_REQUEST, reason))?;
+    let limit = match query.limit {
+        Some(0) => {
+            return Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "limit deve ser pelo menos 1",
+            ))
+        }
+        Some(limit) => limit.min(app_ctx.config.limits.max_page_size),
+        None => DEFAULT_PAGE_SIZE.min(app_ctx.config.limits.max_page_size),
+    };
+    let cursor = query.cursor.clone();
+    match app_ctx
+        .db
+        .read(move |db| db.search_messages(user_id, &search, cursor.as_deref(), limit))
+        .await
+    {
+        Ok(page) => Ok(HttpResponse::Ok().json(page)),
+        Err(err) => {
+            log::error!("Error searching messages {:?}", err);
+            Err(ApiError::internal("Erro ao buscar mensagens"))
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadPath {
+    uuid: Uuid,
+    message_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThreadPage {
+    root: ChatMessage,
+    #[serde(flatten)]
+    page: MessagePage,
+}
+
+// Same cursors as /messages/{uuid}, over the replies of the thread started by message_id
+#[get("/messages/{uuid}/{message_id}/thread")]
+pub async fn get_thread_messages(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    query: Query<GetMessagesQuery>,
+    path: Path<ThreadPath>,
+) -> Result<HttpResponse, ApiError> {
+    let access =
+        authorize_chat(&app_ctx.db, user, &path.uuid.to_string(), ChatRole::MEMBER).await?;
+
+    let cursor = query.cursor()?.unwrap_or(MessageCursor::Latest);
+    let limit = query.page_size(app_ctx.config.limits.max_page_size)?;
+    let (chat_id, thread_id, user_id) = (access.chat_id, path.message_id.clone(), access.user_id);
+    let res = app_ctx
+        .db
+        .read(move |db| {
+            let Some(root) = db.get_chat_message(&chat_id, &thread_id)? else {
+                return Ok(None);
+            };
+            if root.thread_id.is_some() {
+                return Ok(None);
+            }
+            let mut page = db.get_message_page(&chat_id, Some(&thread_id), cursor, limit)?;
+            db.attach_reactions(&mut page.messages, user_id)?;
+            let mut root = [root];
+            db.attach_reactions(&mut root, user_id)?;
+            db.attach_files(&mut root)?;
+            let [root] = root;
+            Ok(Some(ThreadPage { root, page }))
+        })
+        .await;
+    let mut thread = match res {
+        Ok(Some(thread)) => thread,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Thread nao encontrada",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error getting thread {}: {:?}", path.message_id, err);
+            return Err(ApiError::internal("Erro ao buscar mensagens da thread"));
+        }
+    };
+    if access.role < ChatRole::ADMIN {
+        thread.root.hide_delete_reason();
+        thread
+            .page
+            .messages
+            .iter_mut()
+            .for_each(ChatMessage::hide_delete_reason);
+    }
+    Ok(HttpResponse::Ok().json(thread))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ThreadBody {
+    chat_id: String,
+    thread_id: String,
+}
+
+// Followers get the thread's replies live, replying or starting it follows automatically
+async fn set_following(
+    body: Json<ThreadBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    follow: bool,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::MEMBER).await?;
+
+    let (chat_id, thread_id, user_id) = (access.chat_id, body.thread_id.clone(), access.user_id);
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            let Some(root) = db.get_chat_message(&chat_id, &thread_id)? else {
+                return Ok(false);
+            };
+            if root.thread_id.is_some() {
+                return Ok(false);
+            }
+            if follow {
+                db.follow_thread(&thread_id, user_id)?;
+            } else {
+                db.unfollow_thread(&thread_id, user_id)?;
+            }
+            Ok(true)
+        })
+        .await;
+    match res {
+        Ok(true) => Ok(HttpResponse::Ok().finish()),
+        Ok(false) => Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Thread nao encontrada",
+        )),
+        Err(err) => {
+            log::error!("Error following thread {}: {:?}", body.thread_id, err);
+            Err(ApiError::internal("Erro ao seguir thread"))
+        }
+    }
+}
+
+#[post("/messages/thread/follow")]
+pub async fn follow_thread(
+    body: Json<ThreadBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    set_following(body, user, app_ctx, true).await
+}
+
+#[post("/messages/thread/unfollow")]
+pub async fn unfollow_thread(
+    body: Json<ThreadBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    set_following(body, user, app_ctx, false).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ReactionBody {
+    chat_id: String,
+    message_id: String,
+    emoji: String,
+}
+
+// Answers with the emoji's count after the change, members get REACTION_ADDED/REMOVED
+async fn change_reaction(
+    body: Json<ReactionBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    add: bool,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::MEMBER).await?;
+
+    if !valid_emoji(&body.emoji) {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("O emoji deve ter entre 1 e {} bytes", MAX_EMOJI_LEN),
+        ));
+    }
+
+    let reaction = Reaction {
+        chat_id: access.chat_id,
+        message_id: body.message_id.clone(),
+        user_id: access.user_id,
+        emoji: body.emoji.clone(),
+    };
+    let change = reaction.clone();
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            if add {
+                db.add_reaction(&change, &format_date(Utc::now()))
+            } else {
+                db.remove_reaction(&change)
+            }
+        })
+        .await;
+    let count = match res {
+        Ok(ReactionOutcome::Changed(count)) => {
+            app_ctx.chat_server.do_send(ReactionChanged {
+                reaction: reaction.clone(),
+                added: add,
+                count,
+            });
+            count
+        }
+        Ok(ReactionOutcome::Unchanged(count)) => count,
+        Ok(ReactionOutcome::NotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Mensagem nao encontrada",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error changing reaction on {}: {:?}", body.message_id, err);
+            return Err(ApiError::internal("Erro ao reagir"));
+        }
+    };
+    Ok(HttpResponse::Ok().json(ReactionCount {
+        emoji: reaction.emoji,
+        count,
+        me: add,
+    }))
+}
+
+#[post("/messages/reactions/add")]
+pub async fn add_reaction(
+    body: Json<ReactionBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    change_reaction(body, user, app_ctx, true).await
+}
+
+#[post("/messages/reactions/remove")]
+pub async fn remove_reaction(
+    body: Json<ReactionBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    change_reaction(body, user, app_ctx, false).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditMessageBody {
+    chat_id: String,
+    message_id: String,
+    message: String,
+}
+
+#[post("/messages/edit")]
+pub async fn edit_message(
+    body: Json<EditMessageBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::MEMBER).await?;
+
+    let limits = &app_ctx.config.limits;
+    if body.message.chars().count() > limits.max_message_len {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "Mensagens podem ter no maximo {} caracteres",
+                limits.max_message_len
+            ),
+        ));
+    }
+
+    let edit = EditChatMessage::new(
+        access.chat_id.clone(),
+        body.message_id.clone(),
+        access.user_id,
+        body.message.clone(),
+        limits.edit_window(),
+    );
+    let message = match app_ctx.db.write(move |db| db.edit_message(edit)).await {
+        Ok(EditOutcome::Edited(message)) => *message,
+        Ok(EditOutcome::NotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Mensagem nao encontrada",
+            ))
+        }
+        Ok(EditOutcome::NotAuthor) => {
+            return Err(ApiError::forbidden("Apenas o autor pode editar a mensagem"))
+        }
+        Ok(EditOutcome::WindowClosed) => {
+            return Err(ApiError::forbidden(
+                "O prazo para editar essa mensagem acabou",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error editing message {}: {:?}", body.message_id, err);
+            return Err(ApiError::internal("Erro ao editar mensagem"));
+        }
+    };
+
+    app_ctx.chat_server.do_send(MessageEdited {
+        chat_id: access.chat_id,
+        message: message.clone(),
+    });
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DeleteMessageBody {
+    chat_id: String,
+    message_id: String,
+    reason: Option<String>,
+}
+
+// Deletes for everyone, the author can always do it and admins/owners for any message
+#[post("/messages/delete")]
+pub async fn delete_message(
+    body: Json<DeleteMessageBody>,
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::MEMBER).await?;
+
+    if body
+        .reason
+        .as_ref()
+        .is_some_and(|reason| reason.chars().count() > MAX_DELETE_REASON_LEN)
+    {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "O motivo pode ter no maximo {} caracteres",
+                MAX_DELETE_REASON_LEN
+            ),
+        ));
+    }
+
+    let delete = DeleteChatMessage::new(
+        access.chat_id.clone(),
+        body.message_id.clone(),
+        access.user_id,
+        body.reason.clone(),
+    );
+    let (message, thread_root) = match app_ctx.db.write(move |db| db.delete_message(delete)).await {
+        Ok(DeleteOutcome::Deleted(message, thread_root)) => {
+            (*message, thread_root.map(|root| *root))
+        }
+        Ok(DeleteOutcome::NotFound) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Mensagem nao encontrada",
+            ))
+        }
+        Ok(DeleteOutcome::Forbidden) => {
+            return Err(ApiError::forbidden(
+                "Apenas o autor ou moderadores podem apagar a mensagem",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error deleting message {}: {:?}", body.message_id, err);
+            return Err(ApiError::internal("Erro ao apagar mensagem"));
+        }
     };
-    HttpResponse::Ok().json(messages)
+
+    app_ctx.chat_server.do_send(MessageDeleted {
+        chat_id: access.chat_id,
+        message: message.clone(),
+        thread_root,
+    });
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageEditsPath {
+    uuid: Uuid,
+    message_id: String,
+}
+
+// Previous texts of a message, only for whoever moderates the chat
+#[get("/messages/{uuid}/{message_id}/edits")]
+pub async fn get_message_edits(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<MessageEditsPath>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &path.uuid.to_string(), ChatRole::ADMIN).await?;
+
+    let message_id = path.message_id.clone();
+    let edits = match app_ctx
+        .db
+        .read(move |db| db.get_message_edits(&access.chat_id, &message_id))
+        .await
+    {
+        Ok(Some(edits)) => edits,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Mensagem nao encontrada",
+            ))
+        }
+        Err(err) => {
+            log::error!(
+                "Error getting edits of message {}: {:?}",
+                path.message_id,
+                err
+            );
+            return Err(ApiError::internal("Erro ao buscar edicoes da mensagem"));
+        }
+    };
+    Ok(HttpResponse::Ok().json(edits))
 }
 
 #[derive(Debug, Deserialize)]
@@ -241,24 +871,26 @@ pub struct DeleteBody {
 #[post("/remove")]
 pub async fn remove_chat(
     body: Json<DeleteBody>,
-    session: Session,
+    user: SessionUser,
     app_ctx: Data<AppContext>,
-) -> impl Responder {
-    let user_id = get_user_id(&session);
-    let RespostaAdquirirIdSessao::Id(user_id) = user_id else {
-        let RespostaAdquirirIdSessao::Erro(err) = user_id else {
-            panic!()
-        };
-        return err;
-    };
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::OWNER).await?;
 
-    let Ok(db) = app_ctx.db.lock() else {
-        return HttpResponse::InternalServerError().body("Falha ao adquirir db");
-    };
-
-    if let Err(err) = db.remove_chat(&body.chat_id) {
-        log::error!("{:?}", err);
-        return HttpResponse::InternalServerError().body("Erro ao deletar chat");
+    let chat_id = body.chat_id.clone();
+    let members = match app_ctx
+        .db
+        .write(move |db| {
+            let members = db.get_chat_members(&chat_id)?;
+            db.remove_chat(&chat_id)?;
+            Ok(members)
+        })
+        .await
+    {
+        Ok(members) => members.into_iter().map(|member| member.user_id).collect(),
+        Err(err) => {
+            log::error!("{:?}", err);
+            return Err(ApiError::internal("Erro ao deletar chat"));
+        }
     };
 
     if let Err(err) = app_ctx
@@ -275,39 +907,26 @@ pub async fn remove_chat(
         .info_server
         .send(info_actor::ChatDeleted {
             room_id: body.chat_id.clone(),
-            user_id: user_id,
+            user_id: access.user_id,
+            members,
         })
         .await
     {
         log::error!("Error sending message to user {:?}", err)
     };
 
-    HttpResponse::Ok().body(format!("Chat {} deletado", body.chat_id))
+    Ok(HttpResponse::Ok().body(format!("Chat {} deletado", body.chat_id)))
 }
 
 #[post("/update")]
 async fn rota_update(
-    session: Session,
+    user: SessionUser,
     app_ctx: Data<AppContext>,
     chat: Json<Chat>,
-) -> impl Responder {
+) -> Result<HttpResponse, ApiError> {
     println!("{:?}", chat);
 
-    let user_id = get_user_id(&session);
-    let RespostaAdquirirIdSessao::Id(user_id) = user_id else {
-        let RespostaAdquirirIdSessao::Erro(err) = user_id else {
-            panic!();
-        };
-        return err;
-    };
-    let Ok(db) = app_ctx.db.lock() else {
-        log::error!("Error getting db, maybe it's poisoned?");
-        return HttpResponse::InternalServerError().body("Erro adquirindo db.");
-    };
-
-    if chat.creator_id != user_id {
-        return HttpResponse::Unauthorized().body("Você só pode modificar suas informações.");
-    }
+    let access = authorize_chat(&app_ctx.db, user, &chat.chat_id, ChatRole::ADMIN).await?;
     let new_chat = Chat {
         chat_desc: chat.chat_desc.clone(),
         chat_id: chat.chat_id.clone(),
@@ -317,26 +936,400 @@ async fn rota_update(
         chat_type: chat.chat_type,
         creator_id: chat.creator_id,
         last_message: None,
+        direct_user_id: None,
+        read_state: None,
     };
 
-    let res = db.update_chat(new_chat.clone());
+    let update = new_chat.clone();
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            let modified = db.update_chat(update.clone())?;
+            let members = db.get_chat_members(&update.chat_id)?;
+            let chat_image = db.get_chat(&update.chat_id, access.user_id)?.chat_image;
+            Ok((modified, members, chat_image))
+        })
+        .await;
+    let (modified, members, chat_image) = match res {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!("{:?}", err);
+            return Err(ApiError::internal("Erro ao atualizar chat"));
+        }
+    };
+    if modified < 1 {
+        return Ok(HttpResponse::NotModified().body("Nada modificado"));
+    }
+
+    // Only what was saved reaches the members
     if let Err(err) = app_ctx
         .info_server
-        .send(ChatUpdate { chat: new_chat })
+        .send(ChatUpdate {
+            // The image only changes through /chat/image
+            chat: Chat {
+                chat_image,
+                ..new_chat
+            },
+            members: members.iter().map(|member| member.user_id).collect(),
+        })
         .await
     {
         log::error!("{:?}", err);
     }
 
-    let Ok(modified) = res else {
+    Ok(HttpResponse::Ok().body(format!("{:?}", modified)))
+}
+
+// Sets or clears chats.chat_image and sends the chat with its new image to the members
+async fn change_chat_image(
+    app_ctx: &AppContext,
+    access: ChatAccess,
+    upload: Option<Upload>,
+) -> Result<Option<Attachment>, ApiError> {
+    let chat_id = access.chat_id.clone();
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            let attachment = match upload {
+                Some(upload) => Some(db.insert_attachment(
+                    upload.into_insert(access.user_id, Some(access.chat_id.clone())),
+                )?),
+                None => None,
+            };
+            let image = attachment
+                .as_ref()
+                .map(|attachment| attachment.url.as_str());
+            db.set_chat_image(&access.chat_id, image)?;
+            let chat = db.get_chat(&access.chat_id, access.user_id)?;
+            let members = db.get_chat_members(&access.chat_id)?;
+            Ok((attachment, chat, members))
+        })
+        .await;
+    let (attachment, chat, members) = match res {
+        Ok(res) => res,
+        Err(err) => {
+            log::error!("Error changing image of chat {}: {:?}", chat_id, err);
+            return Err(ApiError::internal("Erro ao salvar imagem"));
+        }
+    };
+    app_ctx.info_server.do_send(ChatUpdate {
+        chat: Chat {
+            read_state: None,
+            ..chat
+        },
+        members: members.iter().map(|member| member.user_id).collect(),
+    });
+    Ok(attachment)
+}
+
+#[post("/image/{uuid}")]
+async fn set_chat_image(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &path, ChatRole::ADMIN).await?;
+    let upload = receive_image(payload, &app_ctx).await?;
+    let attachment = change_chat_image(&app_ctx, access, Some(upload)).await?;
+    Ok(HttpResponse::Ok().json(attachment))
+}
+
+#[post("/image/{uuid}/remove")]
+async fn remove_chat_image(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    path: Path<String>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &path, ChatRole::ADMIN).await?;
+    change_chat_image(&app_ctx, access, None).await?;
+    Ok(HttpResponse::Ok().body(""))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct JoinChatBody {
+    chat_id: String,
+    user_id: i64,
+}
+
+// Admins add other users to the chat, nobody can join a chat on their own.
+#[post("/join")]
+async fn join_chat(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    body: Json<JoinChatBody>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::ADMIN).await?;
+
+    let (chat_id, new_member) = (body.chat_id.clone(), body.user_id);
+    let res = app_ctx
+        .db
+        .write(move |db| db.add_chat_member(&chat_id, new_member, ChatRole::MEMBER))
+        .await;
+    let Ok(added) = res else {
         let err = res.unwrap_err();
-        log::error!("{:?}", err);
-        return HttpResponse::InternalServerError().body("Erro ao atualizar usuario.");
+        if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Usuario nao encontrado",
+            ));
+        }
+        log::error!("Error adding chat member {:?}", err);
+        return Err(ApiError::internal("Erro ao adicionar membro"));
     };
+    if added < 1 {
+        return Ok(HttpResponse::NotModified().body("Usuario ja faz parte do chat"));
+    }
 
+    if let Err(err) = app_ctx
+        .info_server
+        .send(info_actor::ChatCreated {
+            room_id: body.chat_id.clone(),
+            user_id: access.user_id,
+            members: vec![body.user_id],
+        })
+        .await
+    {
+        log::error!("Error sending message to user {:?}", err)
+    };
+
+    Ok(HttpResponse::Ok().body(format!("Usuario {} adicionado", body.user_id)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LeaveChatBody {
+    chat_id: String,
+}
+
+#[post("/leave")]
+async fn leave_chat(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    body: Json<LeaveChatBody>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::MEMBER).await?;
+    if access.role == ChatRole::OWNER {
+        return Err(ApiError::forbidden(
+            "O dono nao pode sair do chat, transfira o chat ou delete-o",
+        ));
+    }
+    let (chat_id, user_id) = (access.chat_id.clone(), access.user_id);
+    match app_ctx
+        .db
+        .read(move |db| db.get_chat(&chat_id, user_id))
+        .await
+    {
+        Ok(chat) if chat.chat_type == ChatTypes::USER => {
+            return Err(ApiError::forbidden("Nao e possivel sair de uma conversa direta"));
+        }
+        Ok(_) => (),
+        Err(err) => {
+            log::error!("Error fetching chat {:?}", err);
+            return Err(ApiError::internal("Erro ao sair do chat"));
+        }
+    }
+
+    remove_member(&app_ctx, access.chat_id, access.user_id, access.user_id).await
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberBody {
+    chat_id: String,
+    user_id: i64,
+}
+
+#[post("/kick")]
+async fn kick_member(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    body: Json<MemberBody>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::ADMIN).await?;
+    let (chat_id, target) = (body.chat_id.clone(), body.user_id);
+    let target_role = match app_ctx
+        .db
+        .read(move |db| db.get_chat_role(&chat_id, target))
+        .await
+    {
+        Ok(Some(role)) => role,
+        Ok(None) => {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Usuario nao faz parte desse chat",
+            ))
+        }
+        Err(err) => {
+            log::error!("Error fetching chat role {:?}", err);
+            return Err(ApiError::internal("Erro ao remover membro"));
+        }
+    };
+    if target_role >= access.role {
+        return Err(ApiError::forbidden(
+            "Voce so pode remover membros com cargo menor que o seu",
+        ));
+    }
+
+    remove_member(&app_ctx, access.chat_id, target, access.user_id).await
+}
+
+async fn remove_member(
+    app_ctx: &AppContext,
+    chat_id: String,
+    user_id: i64,
+    removed_by: i64,
+) -> Result<HttpResponse, ApiError> {
+    let id = chat_id.clone();
+    let res = app_ctx
+        .db
+        .write(move |db| db.remove_chat_member(&id, user_id))
+        .await;
+    let Ok(removed) = res else {
+        log::error!("Error removing chat member {:?}", res.unwrap_err());
+        return Err(ApiError::internal("Erro ao sair do chat"));
+    };
+    if removed < 1 {
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Usuario nao faz parte desse chat",
+        ));
+    }
+
+    app_ctx.chat_server.do_send(MemberRemoved {
+        chat_id: chat_id.clone(),
+        user_id,
+    });
+    app_ctx.info_server.do_send(info_actor::ChatDeleted {
+        room_id: chat_id.clone(),
+        user_id: removed_by,
+        members: vec![user_id],
+    });
+
+    Ok(HttpResponse::Ok().body(format!("Usuario {} removido do chat {}", user_id, chat_id)))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MemberRoleBody {
+    chat_id: String,
+    user_id: i64,
+    role: ChatRole,
+}
+
+// Only the owner hands out roles, giving OWNER away transfers the chat and makes the old
+// owner an admin.
+#[post("/role")]
+async fn set_member_role(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    body: Json<MemberRoleBody>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &body.chat_id, ChatRole::OWNER).await?;
+    if body.user_id == access.user_id {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Voce nao pode mudar o proprio cargo",
+        ));
+    }
+
+    let (chat_id, target, role, owner) =
+        (body.chat_id.clone(), body.user_id, body.role, access.user_id);
+    let res = app_ctx
+        .db
+        .write(move |db| match role {
+            ChatRole::OWNER => db.transfer_chat(&chat_id, owner, target),
+            role => db.set_chat_role(&chat_id, target, role),
+        })
+        .await;
+    let Ok(modified) = res else {
+        log::error!("Error setting chat role {:?}", res.unwrap_err());
+        return Err(ApiError::internal("Erro ao mudar cargo"));
+    };
     if modified < 1 {
-        return HttpResponse::NotModified().body("Nada modificado");
+        return Err(ApiError::new(
+            StatusCode::NOT_FOUND,
+            "Usuario nao faz parte desse chat",
+        ));
+    }
+
+    Ok(HttpResponse::Ok().body(format!(
+        "Usuario {} agora e {}",
+        body.user_id,
+        body.role.as_str()
+    )))
+}
+
+#[get("/members")]
+async fn get_members(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    query: Query<GetChatQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let access = authorize_chat(&app_ctx.db, user, &query.id, ChatRole::MEMBER).await?;
+
+    let chat_id = access.chat_id;
+    let res = app_ctx
+        .db
+        .read(move |db| db.get_chat_members(&chat_id))
+        .await;
+    let Ok(members) = res else {
+        log::error!("Error getting chat members {:?}", res.unwrap_err());
+        return Err(ApiError::internal("Erro adquirindo membros do chat"));
+    };
+    Ok(HttpResponse::Ok().json(members))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DirectChatBody {
+    user_id: i64,
+}
+
+// Opens the DM with another user, the same chat is returned every time for the same pair.
+#[post("/direct")]
+async fn open_direct_chat(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    body: Json<DirectChatBody>,
+) -> Result<HttpResponse, ApiError> {
+    let SessionUser(user_id) = user;
+    let other_user_id = body.user_id;
+    if other_user_id == user_id {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "Nao e possivel abrir uma conversa com voce mesmo",
+        ));
+    }
+
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            let (chat_id, created) = db.open_direct_chat(user_id, other_user_id)?;
+            Ok((db.get_chat(&chat_id, user_id)?, created))
+        })
+        .await;
+    let Ok((chat, created)) = res else {
+        let err = res.unwrap_err();
+        if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) {
+            return Err(ApiError::new(
+                StatusCode::NOT_FOUND,
+                "Usuario nao encontrado",
+            ));
+        }
+        log::error!("Error opening direct chat {:?}", err);
+        return Err(ApiError::internal("Erro ao abrir conversa"));
+    };
+
+    if created {
+        if let Err(err) = app_ctx
+            .info_server
+            .send(info_actor::ChatCreated {
+                room_id: chat.chat_id.clone(),
+                user_id,
+                members: vec![other_user_id],
+            })
+            .await
+        {
+            log::error!("Error sending message to user {:?}", err)
+        };
     }
 
-    HttpResponse::Ok().body(format!("{:?}", modified))
+    Ok(HttpResponse::Ok().json(chat))
 }
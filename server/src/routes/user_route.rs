@@ -1,6 +1,9 @@
+use actix_multipart::Multipart;
 use actix_session::Session;
 use actix_web::{
-    get, post,
+    get,
+    http::StatusCode,
+    post,
     web::{self, Data},
     web::{Json, Query},
     HttpResponse, Responder, Scope,
@@ -9,10 +12,19 @@ use rusqlite::ErrorCode;
 use serde::Deserialize;
 
 use crate::{
-    db::user_db::{User, UserTable},
+    db::{
+        attachment_db::AttachmentsTable,
+        user_db::{User, UserTable},
+    },
+    sockets::info::info_actor::{GetPresence, MAX_PRESENCE_IDS},
     AppContext,
 };
 
+use super::{
+    attachment_route::receive_image,
+    authorization::{ApiError, SessionUser},
+};
+
 pub trait UserSession {
     fn insert_user_id(&self, user_id: i64) -> Result<(), HttpResponse>;
     fn get_user_id(&self) -> Result<Option<i64>, HttpResponse>;
@@ -22,7 +34,7 @@ impl UserSession for Session {
     fn insert_user_id(&self, user_id: i64) -> Result<(), HttpResponse> {
         if let Err(err) = self.insert(USER_ID_KEY, user_id) {
             return Err(HttpResponse::InternalServerError()
-                .body(format!("Erro ao salvar sessão: {}", err.to_string())));
+                .body(format!("Erro ao salvar sessão: {}", err)));
         };
         Ok(())
     }
@@ -42,6 +54,9 @@ pub fn user_scope() -> Scope {
         .service(rota_sair)
         .service(user_info)
         .service(rota_update)
+        .service(set_user_image)
+        .service(remove_user_image)
+        .service(get_presence)
 }
 
 #[derive(Debug, Deserialize)]
@@ -56,15 +71,16 @@ async fn create_user(
     body: web::Json<AuthUserBody>,
     session: Session,
 ) -> impl Responder {
-    let db_ref = app_ctx.db.try_lock().unwrap();
-    let res = db_ref.create_user(body.usuario.clone(), body.senha.clone());
+    let (usuario, senha) = (body.usuario.clone(), body.senha.clone());
+    let res = app_ctx
+        .db
+        .write(move |db| db.create_user(usuario, senha))
+        .await;
     let Ok(user_id) = res else {
         let err = res.unwrap_err();
-        if let Some(sqlite_err) = err.sqlite_error() {
-            if sqlite_err.code == ErrorCode::ConstraintViolation {
-                return HttpResponse::Conflict()
-                    .body(format!("Usuario \"{}\" já existe", body.usuario));
-            }
+        if err.sqlite_error_code() == Some(ErrorCode::ConstraintViolation) {
+            return HttpResponse::Conflict()
+                .body(format!("Usuario \"{}\" já existe", body.usuario));
         }
         return HttpResponse::InternalServerError().body(err.to_string());
     };
@@ -72,7 +88,7 @@ async fn create_user(
     if let Err(err) = session.insert_user_id(user_id) {
         return err;
     };
-    HttpResponse::Ok().body(format!("Usuario {} criado", res.unwrap()))
+    HttpResponse::Ok().body(format!("Usuario {} criado", user_id))
 }
 
 const USER_ID_KEY: &str = "user_id";
@@ -83,8 +99,11 @@ async fn login_user(
     body: web::Json<AuthUserBody>,
     session: Session,
 ) -> impl Responder {
-    let db = app_ctx.db.lock().unwrap();
-    let login_res = db.login_user(body.usuario.clone(), body.senha.clone());
+    let (usuario, senha) = (body.usuario.clone(), body.senha.clone());
+    let login_res = app_ctx
+        .db
+        .read(move |db| db.login_user(usuario, senha))
+        .await;
     let Ok(user_id) = login_res else {
         return HttpResponse::NotFound().body("Usuario não encontrado");
     };
@@ -94,12 +113,12 @@ async fn login_user(
             return err;
         }
 
-        if let Ok(user) = db.get_user(user_id) {
+        if let Ok(user) = app_ctx.db.read(move |db| db.get_user(user_id)).await {
             return HttpResponse::Ok().json(user);
         }
         return HttpResponse::InternalServerError().body("Error fetching user");
     }
-    return HttpResponse::Unauthorized().body("Senha incorreta");
+    HttpResponse::Unauthorized().body("Senha incorreta")
 }
 
 #[derive(Debug, Deserialize)]
@@ -117,11 +136,8 @@ async fn user_info(
         return HttpResponse::Unauthorized().body("Precisa estar logado para acessar informacao de outros usuarios");
     };
 
-    let Ok(db) = app_ctx.db.lock() else {
-        return HttpResponse::InternalServerError().body("Erro ao adquirir db");
-    };
-
-    let Ok(user) = db.get_user(query.id) else {
+    let id = query.id;
+    let Ok(user) = app_ctx.db.read(move |db| db.get_user(id)).await else {
         return HttpResponse::NotFound().body("Usuario nao encontrado");
     };
     HttpResponse::Ok().json(user)
@@ -137,7 +153,8 @@ async fn my_user_info(app_ctx: Data<AppContext>, session: Session) -> impl Respo
     if user_id.is_none() {
         return HttpResponse::Unauthorized().body("Usuario nao logado");
     }
-    let user = app_ctx.db.lock().unwrap().get_user(user_id.unwrap());
+    let user_id = user_id.unwrap();
+    let user = app_ctx.db.read(move |db| db.get_user(user_id)).await;
     let Ok(user) = user else {
         return HttpResponse::NotFound().body(user.unwrap_err().to_string());
     };
@@ -195,23 +212,12 @@ async fn rota_update(
         };
         return err;
     };
-    let Ok(db) = app_ctx.db.lock() else {
-        log::error!("Error getting db, maybe it's poisoned?");
-        return HttpResponse::InternalServerError().body("Erro adquirindo db.");
-    };
-
-    if (user.user_id != user_id) {
+    if user.user_id != user_id {
         return HttpResponse::Unauthorized().body("Você só pode modificar suas informações.");
     }
 
-    let res = db.update_user(User {
-        user_id: user.user_id,
-        user_nick: user.user_nick.clone(),
-        user_name: user.user_name.clone(),
-        user_status: user.user_status.clone(),
-        user_email: user.user_email.clone(),
-        user_image: user.user_image.clone(),
-    });
+    let user = user.into_inner();
+    let res = app_ctx.db.write(move |db| db.update_user(user)).await;
     let Ok(modified) = res else {
         let err = res.unwrap_err();
         log::error!("{:?}", err);
@@ -224,3 +230,99 @@ async fn rota_update(
 
     HttpResponse::Ok().body("")
 }
+
+#[derive(Debug, Deserialize)]
+struct PresenceQuery {
+    // Comma separated user ids
+    ids: String,
+}
+
+// online/idle/offline of each user, unknown ids are left out
+#[get("/presence")]
+async fn get_presence(
+    _user: SessionUser,
+    app_ctx: Data<AppContext>,
+    query: Query<PresenceQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let Ok(mut user_ids) = query
+        .ids
+        .split(',')
+        .filter(|id| !id.trim().is_empty())
+        .map(|id| id.trim().parse::<i64>())
+        .collect::<Result<Vec<i64>, _>>()
+    else {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            "ids deve ser uma lista de ids separados por virgula",
+        ));
+    };
+    user_ids.sort_unstable();
+    user_ids.dedup();
+    if user_ids.len() > MAX_PRESENCE_IDS {
+        return Err(ApiError::new(
+            StatusCode::BAD_REQUEST,
+            format!("No maximo {} ids por consulta", MAX_PRESENCE_IDS),
+        ));
+    }
+
+    let last_seen = match app_ctx.db.read(move |db| db.get_last_seen(&user_ids)).await {
+        Ok(last_seen) => last_seen,
+        Err(err) => {
+            log::error!("Error getting last_seen {:?}", err);
+            return Err(ApiError::internal("Erro ao buscar presenca"));
+        }
+    };
+    match app_ctx.info_server.send(GetPresence { last_seen }).await {
+        Ok(presence) => Ok(HttpResponse::Ok().json(presence)),
+        Err(err) => {
+            log::error!("Error getting presence from info server {:?}", err);
+            Err(ApiError::internal("Erro ao buscar presenca"))
+        }
+    }
+}
+
+// The picture becomes the user's user_image, every logged in user can download it
+#[post("/image")]
+async fn set_user_image(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+    payload: Multipart,
+) -> Result<HttpResponse, ApiError> {
+    let SessionUser(user_id) = user;
+    let upload = receive_image(payload, &app_ctx).await?;
+    let insert = upload.into_insert(user_id, None);
+    let res = app_ctx
+        .db
+        .write(move |db| {
+            let attachment = db.insert_attachment(insert)?;
+            db.set_user_image(user_id, Some(&attachment.url))?;
+            Ok(attachment)
+        })
+        .await;
+    match res {
+        Ok(attachment) => Ok(HttpResponse::Ok().json(attachment)),
+        Err(err) => {
+            log::error!("Error setting image of user {}: {:?}", user_id, err);
+            Err(ApiError::internal("Erro ao salvar imagem"))
+        }
+    }
+}
+
+#[post("/image/remove")]
+async fn remove_user_image(
+    user: SessionUser,
+    app_ctx: Data<AppContext>,
+) -> Result<HttpResponse, ApiError> {
+    let SessionUser(user_id) = user;
+    match app_ctx
+        .db
+        .write(move |db| db.set_user_image(user_id, None))
+        .await
+    {
+        Ok(_) => Ok(HttpResponse::Ok().body("")),
+        Err(err) => {
+            log::error!("Error removing image of user {}: {:?}", user_id, err);
+            Err(ApiError::internal("Erro ao remover imagem"))
+        }
+    }
+}
@@ -5,6 +5,7 @@ use actix::Message;
 pub mod chat;
 pub mod info;
 
+// Defaults, both can be changed through the [sockets] section of the config.
 pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 pub const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
@@ -1,2 +1,4 @@
 pub mod lobby_actor;
 pub mod lobby_socket;
+pub mod multiplex_socket;
+mod socket;
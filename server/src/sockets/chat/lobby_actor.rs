@@ -1,32 +1,128 @@
 use crate::{
+    config::SocketConfig,
     db::{
-        chat_message_db::{ChatMessagesTable, InsertChatMessage},
-        Database,
+        attachment_db::AttachmentsTable,
+        chat_db::{ChatTable, ReadOutcome},
+        chat_message_db::{
+            ChatMessage, ChatMessagesTable, DeleteChatMessage, DeleteOutcome, EditChatMessage,
+            EditOutcome, InsertChatMessage,
+        },
+        message_reaction_db::{MessageReactionsTable, Reaction, ReactionOutcome},
+        Database, DatabasePool,
+    },
+    message::{format_date, ErrorCode, ProtocolVersion, RoomMessage, ServerEvent},
+    sockets::{
+        info::info_actor::{ChatConnection, ChatRead, Info, UserActive},
+        WsMessage,
     },
-    message::{format_date, SocketMessage},
-    sockets::WsMessage,
 };
 use std::{
-    collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
 };
 
 use actix::{
-    prelude::{Message, Recipient},
-    Actor, Handler,
+    prelude::{ContextFutureSpawner, Message, Recipient},
+    Actor, ActorFutureExt, Addr, AsyncContext, Handler, ResponseActFuture, WrapFuture,
 };
 use chrono::Utc;
+use uuid::Uuid;
 
 type Socket = Recipient<WsMessage>;
 
+// Database work for one room, None when it ended before reaching the database
+type RoomJob = Box<dyn FnOnce(&mut Lobby) -> Option<ResponseActFuture<Lobby, ()>>>;
+
+// Every socket gets its own id, a user with two tabs or devices has two connections.
+pub type ConnId = Uuid;
+
+struct Connection {
+    user_id: i64,
+    addr: Socket,
+    rooms: HashSet<String>,
+    // Multiplexed connections can be in many rooms, so their frames carry the chat_id
+    multiplexed: bool,
+    protocol: ProtocolVersion,
+}
+
+enum Replay {
+    Messages(Vec<ChatMessage>),
+    Gap(String),
+}
+
+// Where a reconnecting client left off
+#[derive(Debug, Clone)]
+pub enum ResumeFrom {
+    Seq(i64),
+    MessageId(String),
+}
+
+impl ResumeFrom {
+    // The sequence number wins when a client sends both
+    pub fn new(last_seq: Option<i64>, last_message_id: Option<String>) -> Option<Self> {
+        match (last_seq, last_message_id) {
+            (Some(seq), _) => Some(Self::Seq(seq)),
+            (None, Some(message_id)) => Some(Self::MessageId(message_id)),
+            (None, None) => None,
+        }
+    }
+}
+
+// Runs on the db pool, one extra row is fetched to know when the cap was passed.
+// Only the main history is replayed, threads are read through their own history.
+fn load_replay(
+    db: &Database,
+    chat_id: &str,
+    resume: &ResumeFrom,
+    max_replay: usize,
+) -> Result<Replay, rusqlite::Error> {
+    let seq = match resume {
+        ResumeFrom::Seq(seq) => *seq,
+        ResumeFrom::MessageId(message_id) => {
+            let Some(seq) = db.get_message_seq(chat_id, message_id)? else {
+                return Ok(Replay::Gap(format!(
+                    "Mensagem {} nao encontrada no chat",
+                    message_id
+                )));
+            };
+            seq
+        }
+    };
+    let messages = db.get_messages_after(chat_id, None, seq, max_replay + 1)?;
+    if messages.len() > max_replay {
+        return Ok(Replay::Gap(format!(
+            "Mais de {} mensagens perdidas",
+            max_replay
+        )));
+    }
+    Ok(Replay::Messages(messages))
+}
+
 pub struct Lobby {
-    sessions: HashMap<i64, Socket>,       //self id to self
-    rooms: HashMap<String, HashSet<i64>>, //room id  to list of users id
-    db: Arc<Mutex<Database>>,
+    sessions: HashMap<ConnId, Connection>, //connection id to its socket
+    users: HashMap<i64, HashSet<ConnId>>,  //user id to all of their connections
+    rooms: HashMap<String, HashSet<ConnId>>, //room id to the connections in it
+    db: DatabasePool,
+    // Read receipts also go to the reader's other devices
+    info: Addr<Info>,
+    max_replay: usize,
+    // room id to who is typing in it and when they last said so, never stored in the database
+    typing: HashMap<String, HashMap<i64, Instant>>,
+    typing_timeout: Duration,
+    typing_min_interval: Duration,
+    // room id to the jobs waiting for the one running in it, a room without jobs has no entry
+    room_jobs: HashMap<String, VecDeque<RoomJob>>,
 }
 
+// How often expired typing indicators are looked for
+const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
 impl Actor for Lobby {
     type Context = actix::Context<Lobby>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(TYPING_SWEEP_INTERVAL, |act, _| act.expire_typing());
+    }
 }
 
 //WsConn sends this to the lobby to say "put me in please"
@@ -36,14 +132,44 @@ pub struct Connect {
     pub addr: Recipient<WsMessage>,
     pub room_id: String,
     pub id: i64,
+    pub conn_id: ConnId,
+    pub protocol: ProtocolVersion,
+    pub resume: Option<ResumeFrom>,
 }
 
-//WsConn sends this to a lobby to say "take me out please"
+// A multiplexed socket registers without a room and subscribes to them later
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Disconnect {
+pub struct Register {
+    pub addr: Recipient<WsMessage>,
+    pub id: i64,
+    pub conn_id: ConnId,
+    pub protocol: ProtocolVersion,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Subscribe {
+    pub id: i64,
+    pub conn_id: ConnId,
     pub room_id: String,
+    pub resume: Option<ResumeFrom>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Unsubscribe {
     pub id: i64,
+    pub conn_id: ConnId,
+    pub room_id: String,
+}
+
+//WsConn sends this to a lobby to say "take me out please", the connection leaves all of its rooms
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Disconnect {
+    pub id: i64,
+    pub conn_id: ConnId,
 }
 
 //client sends this to the lobby for the lobby to echo out.
@@ -51,37 +177,435 @@ pub struct Disconnect {
 #[rtype(result = "()")]
 pub struct ClientActorMessage {
     pub id: i64,
+    pub conn_id: ConnId,
     pub msg: String,
     pub room_id: String,
+    pub client_msg_id: Option<String>,
+    pub reply_to: Option<String>,
+    pub thread_id: Option<String>,
+    pub attachments: Vec<String>,
 }
-impl ClientActorMessage {
-    pub fn new_message(&self, msg: String) -> SocketMessage {
-        SocketMessage {
-            message_type: crate::message::MessageType::TEXT,
-            message: msg,
-            id: Some(self.id),
-            ..Default::default()
-        }
-    }
+
+enum Stored {
+    New(ChatMessage, Option<Box<ThreadReply>>),
+    // A retry of a message that was already stored
+    Duplicate(ChatMessage),
+    // reply_to / thread_id don't point to something usable
+    Rejected(&'static str),
+}
+
+// A reply in a thread goes to its followers, the updated root to the whole room
+struct ThreadReply {
+    root: ChatMessage,
+    followers: HashSet<i64>,
+}
+
+// Sent by a socket, the lobby stores the edit and tells the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct EditMessage {
+    pub conn_id: ConnId,
+    pub room_id: String,
+    pub edit: EditChatMessage,
+}
+
+// The message was already edited through the api, only the room needs to know
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MessageEdited {
+    pub chat_id: String,
+    pub message: ChatMessage,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct DeleteMessage {
+    pub conn_id: ConnId,
+    pub room_id: String,
+    pub delete: DeleteChatMessage,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MessageDeleted {
+    pub chat_id: String,
+    pub message: ChatMessage,
+    pub thread_root: Option<ChatMessage>,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChangeReaction {
+    pub conn_id: ConnId,
+    pub reaction: Reaction,
+    pub add: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ReactionChanged {
+    pub reaction: Reaction,
+    pub added: bool,
+    pub count: i64,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetTyping {
+    pub id: i64,
+    pub conn_id: ConnId,
+    pub room_id: String,
+    pub typing: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MarkRead {
+    pub id: i64,
+    pub conn_id: ConnId,
+    pub room_id: String,
+    pub message_id: String,
 }
 
 impl Lobby {
-    pub fn new(db: Arc<Mutex<Database>>) -> Self {
+    pub fn new(db: DatabasePool, info: Addr<Info>, config: &SocketConfig) -> Self {
         Self {
             db,
+            info,
+            max_replay: config.max_replay_messages,
+            typing: HashMap::new(),
+            typing_timeout: config.typing_timeout(),
+            typing_min_interval: config.typing_min_interval(),
             rooms: HashMap::new(),
             sessions: HashMap::new(),
+            users: HashMap::new(),
+            room_jobs: HashMap::new(),
+        }
+    }
+
+    // Rooms don't wait on each other's database work, inside a room jobs run one at a time in
+    // the order the lobby received them
+    fn run_in_room(&mut self, room_id: &str, job: RoomJob, ctx: &mut actix::Context<Self>) {
+        if let Some(waiting) = self.room_jobs.get_mut(room_id) {
+            waiting.push_back(job);
+            return;
+        }
+        self.room_jobs.insert(room_id.to_string(), VecDeque::new());
+        self.start_room_job(room_id.to_string(), job, ctx);
+    }
+
+    fn start_room_job(&mut self, room_id: String, job: RoomJob, ctx: &mut actix::Context<Self>) {
+        let Some(future) = job(self) else {
+            self.next_room_job(room_id, ctx);
+            return;
+        };
+        future
+            .map(move |_, act, ctx| act.next_room_job(room_id, ctx))
+            .spawn(ctx);
+    }
+
+    fn next_room_job(&mut self, room_id: String, ctx: &mut actix::Context<Self>) {
+        let next = self
+            .room_jobs
+            .get_mut(&room_id)
+            .and_then(|waiting| waiting.pop_front());
+        match next {
+            Some(job) => self.start_room_job(room_id, job, ctx),
+            None => {
+                self.room_jobs.remove(&room_id);
+            }
         }
     }
-    fn send_message(&self, message: SocketMessage, target_id: &i64) {
-        if let Some(scoket_recipient) = self.sessions.get(target_id) {
-            let _ = scoket_recipient.do_send(WsMessage(serde_json::to_string(&message).unwrap()));
+
+    // Renders the event in the protocol the connection negotiated
+    fn send_event(&self, event: &ServerEvent, conn_id: &ConnId) {
+        let Some(connection) = self.sessions.get(conn_id) else {
+            log::warn!(
+                "Attempting to send {:?} but couldn't find connection {}",
+                event,
+                conn_id
+            );
+            return;
+        };
+        let frame = match connection.protocol {
+            ProtocolVersion::V2 => serde_json::to_string(event),
+            ProtocolVersion::V1 => {
+                let Some(message) = event.to_legacy() else {
+                    return;
+                };
+                match event.chat_id() {
+                    Some(chat_id) if connection.multiplexed => {
+                        serde_json::to_string(&RoomMessage {
+                            chat_id,
+                            message: &message,
+                        })
+                    }
+                    _ => serde_json::to_string(&message),
+                }
+            }
+        };
+        connection.addr.do_send(WsMessage(frame.unwrap()));
+    }
+
+    // Sends to every connection in the room, except `skip` when given
+    fn broadcast(&self, event: &ServerEvent, room_id: &str, skip: Option<&ConnId>) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+        room.iter()
+            .filter(|conn_id| Some(*conn_id) != skip)
+            .for_each(|conn_id| self.send_event(event, conn_id));
+    }
+
+    // Like broadcast, for the connections of the given users only
+    fn broadcast_to_users(
+        &self,
+        event: &ServerEvent,
+        room_id: &str,
+        users: &HashSet<i64>,
+        skip: Option<&ConnId>,
+    ) {
+        let Some(room) = self.rooms.get(room_id) else {
+            return;
+        };
+        room.iter()
+            .filter(|conn_id| Some(*conn_id) != skip)
+            .filter(|conn_id| {
+                self.sessions
+                    .get(conn_id)
+                    .is_some_and(|connection| users.contains(&connection.user_id))
+            })
+            .for_each(|conn_id| self.send_event(event, conn_id));
+    }
+
+    // Like broadcast, leaving out every connection of user_id
+    fn broadcast_to_others(&self, event: &ServerEvent, room_id: &str, user_id: i64) {
+        let Some(room) = self.rooms.get(room_id) else {
             return;
+        };
+        room.iter()
+            .filter(|conn_id| {
+                self.sessions
+                    .get(conn_id)
+                    .is_some_and(|connection| connection.user_id != user_id)
+            })
+            .for_each(|conn_id| self.send_event(event, conn_id));
+    }
+
+    // The counts of the thread a deleted reply was in change with it
+    fn broadcast_deleted(
+        &self,
+        room_id: &str,
+        message: ChatMessage,
+        thread_root: Option<ChatMessage>,
+    ) {
+        self.broadcast(&ServerEvent::deleted(room_id, message), room_id, None);
+        if let Some(root) = thread_root {
+            self.broadcast(&ServerEvent::thread_updated(room_id, root), room_id, None);
         }
-        println!(
-            "Attempting to send {:?} but couldn't find user {}",
-            message,
-            target_id.to_string()
+    }
+
+    // Each accepted refresh is sent again so the others can push their own expiry back
+    fn start_typing(&mut self, room_id: &str, user_id: i64) {
+        let now = Instant::now();
+        let room = self.typing.entry(room_id.to_string()).or_default();
+        if room
+            .get(&user_id)
+            .is_some_and(|refreshed| now.duration_since(*refreshed) < self.typing_min_interval)
+        {
+            return;
+        }
+        room.insert(user_id, now);
+        self.broadcast_to_others(
+            &ServerEvent::TypingStarted {
+                chat_id: room_id.to_string(),
+                user_id,
+                expires_in_ms: self.typing_timeout.as_millis() as u64,
+            },
+            room_id,
+            user_id,
+        );
+    }
+
+    fn stop_typing(&mut self, room_id: &str, user_id: i64) {
+        let Some(room) = self.typing.get_mut(room_id) else {
+            return;
+        };
+        if room.remove(&user_id).is_none() {
+            return;
+        }
+        if room.is_empty() {
+            self.typing.remove(room_id);
+        }
+        self.broadcast_to_others(
+            &ServerEvent::TypingStopped {
+                chat_id: room_id.to_string(),
+                user_id,
+            },
+            room_id,
+            user_id,
+        );
+    }
+
+    // A client that crashed or lost its connection never sends stop_typing
+    fn expire_typing(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(String, i64)> = self
+            .typing
+            .iter()
+            .flat_map(|(room_id, users)| {
+                users
+                    .iter()
+                    .filter(|(_, refreshed)| now.duration_since(**refreshed) >= self.typing_timeout)
+                    .map(|(user_id, _)| (room_id.clone(), *user_id))
+            })
+            .collect();
+        for (room_id, user_id) in expired {
+            self.stop_typing(&room_id, user_id);
+        }
+    }
+
+    fn conn_in_room(&self, room_id: &str, conn_id: &ConnId) -> bool {
+        self.rooms
+            .get(room_id)
+            .is_some_and(|room| room.contains(conn_id))
+    }
+
+    fn user_in_room(&self, room_id: &str, user_id: i64) -> bool {
+        self.rooms.get(room_id).is_some_and(|room| {
+            room.iter().any(|conn_id| {
+                self.sessions
+                    .get(conn_id)
+                    .is_some_and(|connection| connection.user_id == user_id)
+            })
+        })
+    }
+
+    fn room_users(&self, room_id: &str) -> Vec<i64> {
+        let Some(room) = self.rooms.get(room_id) else {
+            return Vec::new();
+        };
+        let users: HashSet<i64> = room
+            .iter()
+            .filter_map(|conn_id| self.sessions.get(conn_id))
+            .map(|connection| connection.user_id)
+            .collect();
+        users.into_iter().collect()
+    }
+
+    fn register(
+        &mut self,
+        addr: Socket,
+        user_id: i64,
+        conn_id: ConnId,
+        multiplexed: bool,
+        protocol: ProtocolVersion,
+    ) {
+        self.sessions.insert(
+            conn_id,
+            Connection {
+                user_id,
+                addr,
+                rooms: HashSet::new(),
+                multiplexed,
+                protocol,
+            },
+        );
+        self.users.entry(user_id).or_default().insert(conn_id);
+        self.info.do_send(ChatConnection {
+            user_id,
+            conn_id,
+            connected: true,
+        });
+    }
+
+    // Puts the connection in the room, JOIN only goes out for the user's first connection in it.
+    // A resuming connection gets what it missed right after INIT, before any live message.
+    fn join_room(&mut self, room_id: &str, conn_id: &ConnId, replay: Option<Replay>) {
+        let Some(connection) = self.sessions.get_mut(conn_id) else {
+            return;
+        };
+        let user_id = connection.user_id;
+        connection.rooms.insert(room_id.to_string());
+        let already_in_room = self.user_in_room(room_id, user_id);
+        self.rooms
+            .entry(room_id.to_string())
+            .or_default()
+            .insert(*conn_id);
+
+        if !already_in_room {
+            self.broadcast(
+                &ServerEvent::Join {
+                    chat_id: room_id.to_string(),
+                    user_id,
+                },
+                room_id,
+                Some(conn_id),
+            );
+        }
+
+        self.send_event(
+            &ServerEvent::Init {
+                chat_id: room_id.to_string(),
+                user_id,
+                users: self.room_users(room_id),
+            },
+            conn_id,
+        );
+
+        match replay {
+            Some(Replay::Messages(messages)) => {
+                let replayed = messages.len();
+                for message in messages {
+                    self.send_event(&ServerEvent::message(room_id, message), conn_id);
+                }
+                self.send_event(
+                    &ServerEvent::Resumed {
+                        chat_id: room_id.to_string(),
+                        replayed,
+                    },
+                    conn_id,
+                );
+            }
+            Some(Replay::Gap(reason)) => self.send_event(
+                &ServerEvent::Gap {
+                    chat_id: room_id.to_string(),
+                    reason,
+                },
+                conn_id,
+            ),
+            None => (),
+        }
+    }
+
+    // Takes the connection out of the room, the room is dropped once empty
+    fn remove_from_room(&mut self, room_id: &str, conn_id: &ConnId) -> bool {
+        if let Some(connection) = self.sessions.get_mut(conn_id) {
+            connection.rooms.remove(room_id);
+        }
+        let Some(room) = self.rooms.get_mut(room_id) else {
+            return false;
+        };
+        let removed = room.remove(conn_id);
+        if room.is_empty() {
+            self.rooms.remove(room_id);
+        }
+        removed
+    }
+
+    // Same as remove_from_room, LEAVE goes out once the user's last connection is gone
+    fn leave_room(&mut self, room_id: &str, conn_id: &ConnId, user_id: i64) {
+        if !self.remove_from_room(room_id, conn_id) || self.user_in_room(room_id, user_id) {
+            return;
+        }
+        self.stop_typing(room_id, user_id);
+        self.broadcast(
+            &ServerEvent::Leave {
+                chat_id: room_id.to_string(),
+                user_id,
+            },
+            room_id,
+            None,
         );
     }
 }
@@ -96,156 +620,675 @@ impl Handler<ChatDeleted> for Lobby {
     type Result = ();
 
     fn handle(&mut self, msg: ChatDeleted, _: &mut Self::Context) -> Self::Result {
-        let Some(room) = self.rooms.get(&msg.chat_id) else {
+        if !self.rooms.contains_key(&msg.chat_id) {
             log::error!("Chat não encontrado.");
-            return ();
-        };
-        room.iter().for_each(|conn_id| {
-            self.send_message(
-                SocketMessage {
-                    message_type: crate::message::MessageType::CHAT_DELETED,
-                    message: format!("Chat {:?} deletado.", msg.chat_id),
-                    id: None,
-                    date: format_date(Utc::now()),
-                },
-                conn_id,
-            )
-        });
-        self.rooms.remove(&msg.chat_id);
-        ()
+            return;
+        };
+        self.broadcast(
+            &ServerEvent::ChatDeleted {
+                chat_id: msg.chat_id.clone(),
+            },
+            &msg.chat_id,
+            None,
+        );
+        self.typing.remove(&msg.chat_id);
+        let Some(room) = self.rooms.remove(&msg.chat_id) else {
+            return;
+        };
+        for conn_id in room {
+            if let Some(connection) = self.sessions.get_mut(&conn_id) {
+                connection.rooms.remove(&msg.chat_id);
+            }
+        }
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct MemberRemoved {
+    pub chat_id: String,
+    pub user_id: i64,
+}
+
+impl Handler<MemberRemoved> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: MemberRemoved, _: &mut Self::Context) -> Self::Result {
+        let Some(room) = self.rooms.get(&msg.chat_id) else {
+            return;
+        };
+        let removed: Vec<ConnId> = room
+            .iter()
+            .filter(|conn_id| {
+                self.sessions
+                    .get(conn_id)
+                    .is_some_and(|connection| connection.user_id == msg.user_id)
+            })
+            .copied()
+            .collect();
+        if removed.is_empty() {
+            return;
+        }
+
+        let unavailable = ServerEvent::ChatUnavailable {
+            chat_id: msg.chat_id.clone(),
+            reason: format!("Voce nao faz mais parte do chat {:?}.", msg.chat_id),
+        };
+        for conn_id in &removed {
+            self.send_event(&unavailable, conn_id);
+            self.leave_room(&msg.chat_id, conn_id, msg.user_id);
+        }
     }
 }
 
 impl Handler<ClientActorMessage> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientActorMessage, _: &mut Self::Context) -> Self::Result {
-        let db = self.db.lock().unwrap();
-        if let Err(err) = db.insert_message(InsertChatMessage {
+    fn handle(&mut self, msg: ClientActorMessage, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.room_id.clone();
+        // Queued behind the room's earlier messages so they are stored in the order received
+        self.run_in_room(&room_id, Box::new(move |act| act.store_message(msg)), ctx);
+    }
+}
+
+impl Lobby {
+    fn store_message(&mut self, msg: ClientActorMessage) -> Option<ResponseActFuture<Self, ()>> {
+        if !self.conn_in_room(&msg.room_id, &msg.conn_id) {
+            log::warn!("User {} is not part of room {}", msg.id, msg.room_id);
+            self.send_event(
+                &ServerEvent::Nack {
+                    chat_id: msg.room_id.clone(),
+                    code: ErrorCode::NotSubscribed,
+                    reason: "Inscreva-se no chat antes de enviar mensagens".into(),
+                    client_msg_id: msg.client_msg_id.clone(),
+                },
+                &msg.conn_id,
+            );
+            return None;
+        }
+        // Sending ends the indicator, the client doesn't have to stop it first
+        self.stop_typing(&msg.room_id, msg.id);
+        self.info.do_send(UserActive { user_id: msg.id });
+        let db = self.db.clone();
+        let insert = InsertChatMessage {
             chat_id: msg.room_id.to_string(),
             date_created: format_date(Utc::now()),
-            message: &msg.msg,
+            message: msg.msg.clone(),
             user_id: msg.id,
-        }) {
-            log::error!("Error sending message to db {:?}", err);
-            if let Some(_) = err.sqlite_error_code() {
-                self.send_message(
-                    SocketMessage {
-                        message_type: crate::message::MessageType::CHAT_DELETED,
-                        message: format!("Chat {:?} foi deletado!", msg.room_id),
-                        id: None,
-                        date: format_date(Utc::now()),
-                    },
-                    &msg.id,
-                );
-            }
-            // self.send_message(ChannelDeleted, target_id)
-            return ();
+            client_msg_id: msg.client_msg_id.clone(),
+            reply_to: msg.reply_to.clone(),
+            thread_id: msg.thread_id.clone(),
+            attachments: msg.attachments.clone(),
         };
-        self.rooms
-            .get(&msg.room_id)
-            .unwrap()
-            .iter()
-            .filter(|conn_id| *conn_id.to_owned() != msg.id)
-            .for_each(|conn_id| {
-                self.send_message(
-                    SocketMessage {
-                        message_type: crate::message::MessageType::TEXT,
-                        message: msg.msg.clone(),
-                        id: Some(msg.id),
-                        ..Default::default()
-                    },
-                    conn_id,
-                )
+        let future = async move {
+            db.write(move |db| {
+                // A retry of a message that was already stored, ack it again without a new row
+                if let Some(client_msg_id) = &insert.client_msg_id {
+                    let existing =
+                        db.get_client_message(&insert.chat_id, insert.user_id, client_msg_id)?;
+                    if let Some(existing) = existing {
+                        return Ok(Stored::Duplicate(existing));
+                    }
+                }
+                if let Some(reply_to) = &insert.reply_to {
+                    if db.get_chat_message(&insert.chat_id, reply_to)?.is_none() {
+                        return Ok(Stored::Rejected("Mensagem respondida nao encontrada"));
+                    }
+                }
+                // Only the sender's own uploads to this chat, each one sent once
+                let unsent = db.count_unsent_attachments(
+                    &insert.chat_id,
+                    insert.user_id,
+                    &insert.attachments,
+                )?;
+                if unsent != insert.attachments.len() {
+                    return Ok(Stored::Rejected("Anexo nao encontrado"));
+                }
+                // Threads start from main history messages, there are no threads in threads
+                let Some(thread_id) = insert.thread_id.clone() else {
+                    return Ok(Stored::New(db.insert_message(insert)?, None));
+                };
+                let root = db.get_chat_message(&insert.chat_id, &thread_id)?;
+                if !root.is_some_and(|root| root.thread_id.is_none() && root.deleted_at.is_none()) {
+                    return Ok(Stored::Rejected("Thread nao encontrada"));
+                }
+                let chat_id = insert.chat_id.clone();
+                let stored = db.insert_message(insert)?;
+                let Some(root) = db.get_chat_message(&chat_id, &thread_id)? else {
+                    return Err(rusqlite::Error::QueryReturnedNoRows);
+                };
+                let followers = db.get_thread_followers(&thread_id)?.into_iter().collect();
+                Ok(Stored::New(
+                    stored,
+                    Some(Box::new(ThreadReply { root, followers })),
+                ))
+            })
+            .await
+        }
+        .into_actor(self)
+        .map(move |res, act, _| {
+            let (stored, thread) = match res {
+                Ok(Stored::New(stored, thread)) => (stored, thread),
+                Ok(Stored::Duplicate(stored)) => {
+                    act.send_event(&ServerEvent::ack(&msg.room_id, &stored), &msg.conn_id);
+                    return;
+                }
+                Ok(Stored::Rejected(reason)) => {
+                    act.send_event(
+                        &ServerEvent::Nack {
+                            chat_id: msg.room_id.clone(),
+                            code: ErrorCode::MessageNotFound,
+                            reason: reason.into(),
+                            client_msg_id: msg.client_msg_id.clone(),
+                        },
+                        &msg.conn_id,
+                    );
+                    return;
+                }
+                Err(err) => {
+                    log::error!("Error sending message to db {:?}", err);
+                    // The chat was deleted meanwhile, there was no chats row to take a seq from
+                    let chat_gone = err.is_not_found()
+                        || err.sqlite_error_code()
+                            == Some(rusqlite::ErrorCode::ConstraintViolation);
+                    act.send_event(
+                        &ServerEvent::Nack {
+                            chat_id: msg.room_id.clone(),
+                            code: if chat_gone {
+                                ErrorCode::ChatUnavailable
+                            } else {
+                                ErrorCode::Internal
+                            },
+                            reason: "Erro ao salvar mensagem".into(),
+                            client_msg_id: msg.client_msg_id.clone(),
+                        },
+                        &msg.conn_id,
+                    );
+                    if chat_gone {
+                        act.send_event(
+                            &ServerEvent::ChatDeleted {
+                                chat_id: msg.room_id.clone(),
+                            },
+                            &msg.conn_id,
+                        );
+                    }
+                    return;
+                }
+            };
+
+            act.send_event(&ServerEvent::ack(&msg.room_id, &stored), &msg.conn_id);
+            // The sender's other devices get it too, only the sending socket is skipped
+            let Some(thread) = thread else {
+                act.broadcast(
+                    &ServerEvent::message(&msg.room_id, stored),
+                    &msg.room_id,
+                    Some(&msg.conn_id),
+                );
+                return;
+            };
+            act.broadcast_to_users(
+                &ServerEvent::message(&msg.room_id, stored),
+                &msg.room_id,
+                &thread.followers,
+                Some(&msg.conn_id),
+            );
+            act.broadcast(
+                &ServerEvent::thread_updated(&msg.room_id, thread.root),
+                &msg.room_id,
+                None,
+            );
+        });
+        Some(Box::pin(future))
+    }
+}
+
+impl Handler<EditMessage> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: EditMessage, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.room_id.clone();
+        // Queued so the edit can't overtake a message sent right before it
+        self.run_in_room(&room_id, Box::new(move |act| act.store_edit(msg)), ctx);
+    }
+}
+
+impl Lobby {
+    fn store_edit(&mut self, msg: EditMessage) -> Option<ResponseActFuture<Self, ()>> {
+        if !self.conn_in_room(&msg.room_id, &msg.conn_id) {
+            self.send_event(
+                &ServerEvent::error(
+                    ErrorCode::NotSubscribed,
+                    "Inscreva-se no chat antes de editar mensagens",
+                    Some(&msg.room_id),
+                ),
+                &msg.conn_id,
+            );
+            return None;
+        }
+        let db = self.db.clone();
+        let edit = msg.edit;
+        let future = async move { db.write(move |db| db.edit_message(edit)).await }
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let (code, reason) = match res {
+                    Ok(EditOutcome::Edited(message)) => {
+                        act.broadcast(
+                            &ServerEvent::edited(&msg.room_id, *message),
+                            &msg.room_id,
+                            None,
+                        );
+                        return;
+                    }
+                    Ok(EditOutcome::NotFound) => {
+                        (ErrorCode::MessageNotFound, "Mensagem nao encontrada")
+                    }
+                    Ok(EditOutcome::NotAuthor) => (
+                        ErrorCode::Forbidden,
+                        "Apenas o autor pode editar a mensagem",
+                    ),
+                    Ok(EditOutcome::WindowClosed) => (
+                        ErrorCode::EditWindowClosed,
+                        "O prazo para editar essa mensagem acabou",
+                    ),
+                    Err(err) => {
+                        log::error!("Error editing message in chat {}: {:?}", msg.room_id, err);
+                        (ErrorCode::Internal, "Erro ao editar mensagem")
+                    }
+                };
+                act.send_event(
+                    &ServerEvent::error(code, reason, Some(&msg.room_id)),
+                    &msg.conn_id,
+                );
             });
+        Some(Box::pin(future))
     }
 }
 
-impl Handler<Disconnect> for Lobby {
+impl Handler<MessageEdited> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
-        println!("Disconnecting user");
-        if self.sessions.remove(&msg.id).is_some() {
-            let Some(room) =  self.rooms.get(&msg.room_id) else {
-                    println!("Could not find lobby {}", &msg.room_id);
-                    return ();
-            };
-            println!("Disconnecting");
-
-            room.iter()
-                .filter(|conn_id| *conn_id.to_owned() != msg.id)
-                .for_each(|conn_id| {
-                    self.send_message(
-                        SocketMessage {
-                            message_type: crate::message::MessageType::LEAVE,
-                            message: msg.id.to_string(),
-                            id: Some(msg.id),
-                            ..Default::default()
-                        },
-                        conn_id,
-                    );
-                });
-            if let Some(lobby) = self.rooms.get_mut(&msg.room_id) {
-                if lobby.len() > 1 {
-                    lobby.remove(&msg.id);
-                    return;
+    fn handle(&mut self, msg: MessageEdited, _: &mut Self::Context) -> Self::Result {
+        self.broadcast(
+            &ServerEvent::edited(&msg.chat_id, msg.message),
+            &msg.chat_id,
+            None,
+        );
+    }
+}
+
+impl Handler<DeleteMessage> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: DeleteMessage, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.room_id.clone();
+        self.run_in_room(&room_id, Box::new(move |act| act.store_delete(msg)), ctx);
+    }
+}
+
+impl Lobby {
+    fn store_delete(&mut self, msg: DeleteMessage) -> Option<ResponseActFuture<Self, ()>> {
+        if !self.conn_in_room(&msg.room_id, &msg.conn_id) {
+            self.send_event(
+                &ServerEvent::error(
+                    ErrorCode::NotSubscribed,
+                    "Inscreva-se no chat antes de apagar mensagens",
+                    Some(&msg.room_id),
+                ),
+                &msg.conn_id,
+            );
+            return None;
+        }
+        let db = self.db.clone();
+        let delete = msg.delete;
+        let future = async move { db.write(move |db| db.delete_message(delete)).await }
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let (code, reason) = match res {
+                    Ok(DeleteOutcome::Deleted(message, thread_root)) => {
+                        act.broadcast_deleted(
+                            &msg.room_id,
+                            *message,
+                            thread_root.map(|root| *root),
+                        );
+                        return;
+                    }
+                    Ok(DeleteOutcome::NotFound) => {
+                        (ErrorCode::MessageNotFound, "Mensagem nao encontrada")
+                    }
+                    Ok(DeleteOutcome::Forbidden) => (
+                        ErrorCode::Forbidden,
+                        "Apenas o autor ou moderadores podem apagar a mensagem",
+                    ),
+                    Err(err) => {
+                        log::error!("Error deleting message in chat {}: {:?}", msg.room_id, err);
+                        (ErrorCode::Internal, "Erro ao apagar mensagem")
+                    }
+                };
+                act.send_event(
+                    &ServerEvent::error(code, reason, Some(&msg.room_id)),
+                    &msg.conn_id,
+                );
+            });
+        Some(Box::pin(future))
+    }
+}
+
+impl Handler<MessageDeleted> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: MessageDeleted, _: &mut Self::Context) -> Self::Result {
+        self.broadcast_deleted(&msg.chat_id, msg.message, msg.thread_root);
+    }
+}
+
+impl Handler<ChangeReaction> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChangeReaction, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.reaction.chat_id.clone();
+        self.run_in_room(&room_id, Box::new(move |act| act.store_reaction(msg)), ctx);
+    }
+}
+
+impl Lobby {
+    fn store_reaction(&mut self, msg: ChangeReaction) -> Option<ResponseActFuture<Self, ()>> {
+        let room_id = msg.reaction.chat_id.clone();
+        if !self.conn_in_room(&room_id, &msg.conn_id) {
+            self.send_event(
+                &ServerEvent::error(
+                    ErrorCode::NotSubscribed,
+                    "Inscreva-se no chat antes de reagir a mensagens",
+                    Some(&room_id),
+                ),
+                &msg.conn_id,
+            );
+            return None;
+        }
+        let db = self.db.clone();
+        let (reaction, add) = (msg.reaction.clone(), msg.add);
+        let future = async move {
+            db.write(move |db| {
+                if add {
+                    db.add_reaction(&reaction, &format_date(Utc::now()))
+                } else {
+                    db.remove_reaction(&reaction)
                 }
-                self.rooms.remove(&msg.room_id);
+            })
+            .await
+        }
+        .into_actor(self)
+        .map(move |res, act, _| match res {
+            Ok(ReactionOutcome::Changed(count)) => act.broadcast(
+                &ServerEvent::reaction(msg.reaction, msg.add, count),
+                &room_id,
+                None,
+            ),
+            Ok(ReactionOutcome::Unchanged(_)) => (),
+            Ok(ReactionOutcome::NotFound) => act.send_event(
+                &ServerEvent::error(
+                    ErrorCode::MessageNotFound,
+                    "Mensagem nao encontrada",
+                    Some(&room_id),
+                ),
+                &msg.conn_id,
+            ),
+            Err(err) => {
+                log::error!("Error changing reaction in chat {}: {:?}", room_id, err);
+                act.send_event(
+                    &ServerEvent::error(ErrorCode::Internal, "Erro ao reagir", Some(&room_id)),
+                    &msg.conn_id,
+                );
             }
+        });
+        Some(Box::pin(future))
+    }
+}
+
+impl Handler<ReactionChanged> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: ReactionChanged, _: &mut Self::Context) -> Self::Result {
+        let room_id = msg.reaction.chat_id.clone();
+        self.broadcast(
+            &ServerEvent::reaction(msg.reaction, msg.added, msg.count),
+            &room_id,
+            None,
+        );
+    }
+}
+
+impl Handler<SetTyping> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetTyping, _: &mut Self::Context) -> Self::Result {
+        if !self.conn_in_room(&msg.room_id, &msg.conn_id) {
+            self.send_event(
+                &ServerEvent::error(
+                    ErrorCode::NotSubscribed,
+                    "Inscreva-se no chat antes de digitar",
+                    Some(&msg.room_id),
+                ),
+                &msg.conn_id,
+            );
+            return;
+        }
+        self.info.do_send(UserActive { user_id: msg.id });
+        if msg.typing {
+            self.start_typing(&msg.room_id, msg.id);
+        } else {
+            self.stop_typing(&msg.room_id, msg.id);
         }
     }
 }
 
-impl Handler<Connect> for Lobby {
+impl Handler<MarkRead> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        println!("conectando {} ao lobby {}", msg.id, msg.room_id);
-        self.rooms
-            .entry(msg.room_id.clone())
-            .or_insert_with(HashSet::new)
-            .insert(msg.id);
+    fn handle(&mut self, msg: MarkRead, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.room_id.clone();
+        self.run_in_room(&room_id, Box::new(move |act| act.store_read(msg)), ctx);
+    }
+}
 
-        self.rooms
-            .get(&msg.room_id)
-            .unwrap()
-            .iter()
-            .filter(|conn_id| *conn_id.to_owned() != msg.id)
-            .for_each(|conn_id| {
-                println!("curr conn: {}, msgid: {}", conn_id, &msg.id);
-                self.send_message(
-                    SocketMessage {
-                        message_type: crate::message::MessageType::JOIN,
-                        message: msg.id.to_string(),
-                        id: Some(msg.id),
-                        ..Default::default()
-                    },
-                    conn_id,
-                )
-            });
+impl Lobby {
+    fn store_read(&mut self, msg: MarkRead) -> Option<ResponseActFuture<Self, ()>> {
+        if !self.conn_in_room(&msg.room_id, &msg.conn_id) {
+            self.send_event(
+                &ServerEvent::error(
+                    ErrorCode::NotSubscribed,
+                    "Inscreva-se no chat antes de marcar mensagens como lidas",
+                    Some(&msg.room_id),
+                ),
+                &msg.conn_id,
+            );
+            return None;
+        }
+        self.info.do_send(UserActive { user_id: msg.id });
+        let db = self.db.clone();
+        let (room_id, message_id) = (msg.room_id.clone(), msg.message_id.clone());
+        let future = async move {
+            db.write(move |db| db.mark_read(&room_id, msg.id, &message_id))
+                .await
+        }
+        .into_actor(self)
+        .map(move |res, act, _| match res {
+            Ok(ReadOutcome::Advanced(receipt)) => {
+                act.broadcast(&ServerEvent::read(&receipt), &msg.room_id, None);
+                act.info.do_send(ChatRead { receipt });
+            }
+            Ok(ReadOutcome::Unchanged) => (),
+            Ok(ReadOutcome::NotFound) => act.send_event(
+                &ServerEvent::error(
+                    ErrorCode::MessageNotFound,
+                    "Mensagem nao encontrada",
+                    Some(&msg.room_id),
+                ),
+                &msg.conn_id,
+            ),
+            Err(err) => {
+                log::error!("Error marking chat {} as read: {:?}", msg.room_id, err);
+                act.send_event(
+                    &ServerEvent::error(
+                        ErrorCode::Internal,
+                        "Erro ao marcar como lida",
+                        Some(&msg.room_id),
+                    ),
+                    &msg.conn_id,
+                );
+            }
+        });
+        Some(Box::pin(future))
+    }
+}
 
-        self.sessions.insert(msg.id, msg.addr);
+impl Handler<Disconnect> for Lobby {
+    type Result = ();
 
-        println!("{:?}", self.sessions.keys());
-        let Some(room) = self.rooms.get(&msg.room_id) else {
-            return ();
+    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
+        log::debug!(
+            "Disconnecting connection {} of user {}",
+            msg.conn_id,
+            msg.id
+        );
+        let Some(connection) = self.sessions.get(&msg.conn_id) else {
+            return;
         };
+        let rooms: Vec<String> = connection.rooms.iter().cloned().collect();
+        for room_id in rooms {
+            self.leave_room(&room_id, &msg.conn_id, msg.id);
+        }
 
-        let mut users_in_room: Vec<i64> = Vec::new();
-        for user_id in room {
-            users_in_room.push(*user_id);
+        self.sessions.remove(&msg.conn_id);
+        if let Some(connections) = self.users.get_mut(&msg.id) {
+            connections.remove(&msg.conn_id);
+            if connections.is_empty() {
+                self.users.remove(&msg.id);
+            }
         }
-        println!("{:?}", users_in_room);
+        self.info.do_send(ChatConnection {
+            user_id: msg.id,
+            conn_id: msg.conn_id,
+            connected: false,
+        });
+    }
+}
 
-        self.send_message(
-            SocketMessage {
-                message_type: crate::message::MessageType::INIT,
-                message: serde_json::to_string(&users_in_room).unwrap(),
-                id: Some(msg.id),
-                ..Default::default()
-            },
-            &msg.id,
+impl Handler<Connect> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Connect, ctx: &mut Self::Context) -> Self::Result {
+        log::debug!(
+            "conectando {} ({}) ao lobby {}",
+            msg.id,
+            msg.conn_id,
+            msg.room_id
         );
+        self.register(msg.addr, msg.id, msg.conn_id, false, msg.protocol);
+        let Some(resume) = msg.resume else {
+            self.join_room(&msg.room_id, &msg.conn_id, None);
+            return;
+        };
+
+        // As a room job no message is stored between loading the replay and joining the room
+        let room_id = msg.room_id.clone();
+        self.run_in_room(
+            &room_id,
+            Box::new(move |act| {
+                let (db, max_replay) = (act.db.clone(), act.max_replay);
+                let room_id = msg.room_id.clone();
+                let future = async move {
+                    db.read(move |db| load_replay(db, &room_id, &resume, max_replay))
+                        .await
+                }
+                .into_actor(act)
+                .map(move |res, act, _| {
+                    let replay = res.unwrap_or_else(|err| {
+                        log::error!("Error loading replay of chat {}: {}", msg.room_id, err);
+                        Replay::Gap("Erro ao carregar mensagens perdidas".into())
+                    });
+                    act.join_room(&msg.room_id, &msg.conn_id, Some(replay));
+                });
+                Some(Box::pin(future))
+            }),
+            ctx,
+        );
+    }
+}
+
+impl Handler<Register> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Register, _: &mut Self::Context) -> Self::Result {
+        self.register(msg.addr, msg.id, msg.conn_id, true, msg.protocol);
+    }
+}
+
+impl Handler<Subscribe> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, ctx: &mut Self::Context) -> Self::Result {
+        let room_id = msg.room_id.clone();
+        // Messages sent right after subscribing are queued behind it and find the room
+        self.run_in_room(&room_id, Box::new(move |act| act.subscribe(msg)), ctx);
+    }
+}
+
+impl Lobby {
+    fn subscribe(&mut self, msg: Subscribe) -> Option<ResponseActFuture<Self, ()>> {
+        let (db, max_replay) = (self.db.clone(), self.max_replay);
+        let (room_id, user_id) = (msg.room_id.clone(), msg.id);
+        let resume = msg.resume.clone();
+        // Membership is checked here since the socket was authorized for no chat in particular
+        let future = async move {
+            db.read(move |db| {
+                if db.get_chat_role(&room_id, user_id)?.is_none() {
+                    return Ok(None);
+                }
+                let replay = match resume {
+                    Some(resume) => Some(load_replay(db, &room_id, &resume, max_replay)?),
+                    None => None,
+                };
+                Ok(Some(replay))
+            })
+            .await
+        }
+        .into_actor(self)
+        .map(move |res, act, _| {
+            match res {
+                Ok(Some(replay)) => act.join_room(&msg.room_id, &msg.conn_id, replay),
+                Ok(None) => act.send_event(
+                    &ServerEvent::ChatUnavailable {
+                        chat_id: msg.room_id.clone(),
+                        reason: format!("Voce nao faz parte do chat {:?}.", msg.room_id),
+                    },
+                    &msg.conn_id,
+                ),
+                Err(err) => {
+                    log::error!(
+                        "Error checking membership of {} in chat {}: {}",
+                        msg.id,
+                        msg.room_id,
+                        err
+                    );
+                    act.send_event(
+                        &ServerEvent::error(
+                            ErrorCode::Internal,
+                            "Erro verificando membros do chat",
+                            Some(&msg.room_id),
+                        ),
+                        &msg.conn_id,
+                    );
+                }
+            };
+        });
+        Some(Box::pin(future))
+    }
+}
+
+impl Handler<Unsubscribe> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unsubscribe, _: &mut Self::Context) -> Self::Result {
+        self.leave_room(&msg.room_id, &msg.conn_id, msg.id);
     }
 }
@@ -1,43 +1,361 @@
-use std::time::Instant;
-
-use actix::{
-    fut, prelude::ContextFutureSpawner, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext,
-    Handler, Running, StreamHandler, WrapFuture,
-};
+use actix::{Actor, Addr, AsyncContext, Handler, Running, StreamHandler};
 use actix_web_actors::ws;
 
-use crate::sockets::{WsMessage, CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+use crate::{
+    config::{LimitsConfig, SocketConfig},
+    db::{
+        attachment_db::MAX_ATTACHMENTS_PER_MESSAGE,
+        chat_message_db::{DeleteChatMessage, EditChatMessage, MAX_DELETE_REASON_LEN},
+        message_reaction_db::{valid_emoji, Reaction, MAX_EMOJI_LEN},
+    },
+    message::{ClientRequest, ErrorCode, ProtocolVersion, ServerEvent},
+    sockets::WsMessage,
+};
 
-use super::lobby_actor::{ClientActorMessage, Connect, Disconnect, Lobby};
+use super::{
+    lobby_actor::{
+        ChangeReaction, ClientActorMessage, ConnId, Connect, DeleteMessage, EditMessage, Lobby,
+        MarkRead, ResumeFrom, SetTyping, Subscribe, Unsubscribe,
+    },
+    socket::{self, ChatSocket, SocketState},
+};
 
 #[derive(Debug)]
 pub struct ChatWs {
-    id: i64,
-    lobby_addr: Addr<Lobby>,
-    hb: Instant,
+    socket: SocketState,
     room: String,
+    // Set when reconnecting, what came after it is replayed once
+    resume: Option<ResumeFrom>,
 }
 
 impl ChatWs {
-    pub fn new(room: String, lobby_addr: Addr<Lobby>, id: i64) -> ChatWs {
+    pub fn new(
+        room: String,
+        lobby_addr: Addr<Lobby>,
+        id: i64,
+        config: SocketConfig,
+        limits: LimitsConfig,
+        protocol: ProtocolVersion,
+        resume: Option<ResumeFrom>,
+    ) -> ChatWs {
         ChatWs {
-            id,
-            lobby_addr,
-            hb: Instant::now(),
+            socket: SocketState::new(lobby_addr, id, config, limits, protocol),
             room,
+            resume,
         }
     }
-    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                println!("Disconnecting failed heartbeat");
-                ctx.stop();
-                return;
+}
+
+// Same size as the client_msg_id column
+const MAX_CLIENT_MSG_ID_LEN: usize = 64;
+
+// Everything a chat socket needs to hand a request over to the lobby
+pub(super) struct RequestContext<'a> {
+    pub lobby_addr: &'a Addr<Lobby>,
+    pub id: i64,
+    pub conn_id: ConnId,
+    pub limits: &'a LimitsConfig,
+    // Set for sockets bound to a single chat
+    pub room: Option<&'a str>,
+}
+
+impl RequestContext<'_> {
+    // Boxed since ServerEvent is large and this is returned on every request
+    fn target_room(&self, chat_id: Option<String>) -> Result<String, Box<ServerEvent>> {
+        match (chat_id, self.room) {
+            (Some(chat_id), Some(room)) if chat_id != room => Err(Box::new(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                format!("Esse socket so envia para o chat {}", room),
+                Some(&chat_id),
+            ))),
+            (Some(chat_id), _) => Ok(chat_id),
+            (None, Some(room)) => Ok(room.to_string()),
+            (None, None) => Err(Box::new(ServerEvent::error(
+                ErrorCode::MissingChatId,
+                "chat_id e obrigatorio nesse socket",
+                None,
+            ))),
+        }
+    }
+
+    // Returns the frame the client should get back right away, if any
+    pub fn dispatch(&self, request: ClientRequest) -> Option<ServerEvent> {
+        let name = request.name();
+        match request {
+            ClientRequest::Send {
+                chat_id,
+                message,
+                client_msg_id,
+                reply_to,
+                thread_id,
+                attachments,
+            } => self.send(
+                chat_id,
+                message,
+                client_msg_id,
+                reply_to,
+                thread_id,
+                attachments,
+            ),
+            ClientRequest::Subscribe { chat_id, .. } | ClientRequest::Unsubscribe { chat_id }
+                if self.room.is_some() =>
+            {
+                Some(ServerEvent::error(
+                    ErrorCode::Unsupported,
+                    format!("{} so existe no socket multiplexado", name),
+                    Some(&chat_id),
+                ))
+            }
+            ClientRequest::Subscribe {
+                chat_id,
+                last_seq,
+                last_message_id,
+            } => {
+                self.lobby_addr.do_send(Subscribe {
+                    id: self.id,
+                    conn_id: self.conn_id,
+                    room_id: chat_id,
+                    resume: ResumeFrom::new(last_seq, last_message_id),
+                });
+                None
+            }
+            ClientRequest::Unsubscribe { chat_id } => {
+                self.lobby_addr.do_send(Unsubscribe {
+                    id: self.id,
+                    conn_id: self.conn_id,
+                    room_id: chat_id,
+                });
+                None
+            }
+            ClientRequest::Ping { nonce } => Some(ServerEvent::Pong { nonce }),
+            ClientRequest::Edit {
+                chat_id,
+                message_id,
+                message,
+            } => self.edit(chat_id, message_id, message),
+            ClientRequest::Delete {
+                chat_id,
+                message_id,
+                reason,
+            } => self.delete(chat_id, message_id, reason),
+            ClientRequest::AddReaction {
+                chat_id,
+                message_id,
+                emoji,
+            } => self.react(chat_id, message_id, emoji, true),
+            ClientRequest::RemoveReaction {
+                chat_id,
+                message_id,
+                emoji,
+            } => self.react(chat_id, message_id, emoji, false),
+            ClientRequest::MarkRead {
+                chat_id,
+                message_id,
+            } => {
+                let room_id = match self.target_room(chat_id) {
+                    Ok(room_id) => room_id,
+                    Err(event) => return Some(*event),
+                };
+                self.lobby_addr.do_send(MarkRead {
+                    id: self.id,
+                    conn_id: self.conn_id,
+                    room_id,
+                    message_id,
+                });
+                None
             }
+            ClientRequest::Typing { chat_id } => self.typing(chat_id, true),
+            ClientRequest::StopTyping { chat_id } => self.typing(chat_id, false),
+        }
+    }
+
+    fn send(
+        &self,
+        chat_id: Option<String>,
+        message: String,
+        client_msg_id: Option<String>,
+        reply_to: Option<String>,
+        thread_id: Option<String>,
+        mut attachments: Vec<String>,
+    ) -> Option<ServerEvent> {
+        let room_id = match self.target_room(chat_id) {
+            Ok(room_id) => room_id,
+            Err(event) => return Some(*event),
+        };
+        if client_msg_id
+            .as_ref()
+            .is_some_and(|key| key.is_empty() || key.len() > MAX_CLIENT_MSG_ID_LEN)
+        {
+            return Some(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                format!(
+                    "client_msg_id deve ter entre 1 e {} bytes",
+                    MAX_CLIENT_MSG_ID_LEN
+                ),
+                Some(&room_id),
+            ));
+        }
+        if message.chars().count() > self.limits.max_message_len {
+            return Some(ServerEvent::Nack {
+                chat_id: room_id,
+                code: ErrorCode::MessageTooLong,
+                reason: format!(
+                    "Mensagens podem ter no maximo {} caracteres",
+                    self.limits.max_message_len
+                ),
+                client_msg_id,
+            });
+        }
+        attachments.sort_unstable();
+        attachments.dedup();
+        if attachments.len() > MAX_ATTACHMENTS_PER_MESSAGE {
+            return Some(ServerEvent::Nack {
+                chat_id: room_id,
+                code: ErrorCode::InvalidFrame,
+                reason: format!(
+                    "Mensagens podem ter no maximo {} anexos",
+                    MAX_ATTACHMENTS_PER_MESSAGE
+                ),
+                client_msg_id,
+            });
+        }
+        self.lobby_addr.do_send(ClientActorMessage {
+            id: self.id,
+            conn_id: self.conn_id,
+            msg: message,
+            room_id,
+            client_msg_id,
+            reply_to,
+            thread_id,
+            attachments,
+        });
+        None
+    }
+
+    fn edit(
+        &self,
+        chat_id: Option<String>,
+        message_id: String,
+        message: String,
+    ) -> Option<ServerEvent> {
+        let room_id = match self.target_room(chat_id) {
+            Ok(room_id) => room_id,
+            Err(event) => return Some(*event),
+        };
+        if message.chars().count() > self.limits.max_message_len {
+            return Some(ServerEvent::error(
+                ErrorCode::MessageTooLong,
+                format!(
+                    "Mensagens podem ter no maximo {} caracteres",
+                    self.limits.max_message_len
+                ),
+                Some(&room_id),
+            ));
+        }
+        self.lobby_addr.do_send(EditMessage {
+            conn_id: self.conn_id,
+            edit: EditChatMessage::new(
+                room_id.clone(),
+                message_id,
+                self.id,
+                message,
+                self.limits.edit_window(),
+            ),
+            room_id,
+        });
+        None
+    }
 
-            ctx.ping(b"PING");
+    fn delete(
+        &self,
+        chat_id: Option<String>,
+        message_id: String,
+        reason: Option<String>,
+    ) -> Option<ServerEvent> {
+        let room_id = match self.target_room(chat_id) {
+            Ok(room_id) => room_id,
+            Err(event) => return Some(*event),
+        };
+        if reason
+            .as_ref()
+            .is_some_and(|reason| reason.chars().count() > MAX_DELETE_REASON_LEN)
+        {
+            return Some(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                format!(
+                    "O motivo pode ter no maximo {} caracteres",
+                    MAX_DELETE_REASON_LEN
+                ),
+                Some(&room_id),
+            ));
+        }
+        self.lobby_addr.do_send(DeleteMessage {
+            conn_id: self.conn_id,
+            delete: DeleteChatMessage::new(room_id.clone(), message_id, self.id, reason),
+            room_id,
         });
+        None
     }
+
+    fn typing(&self, chat_id: Option<String>, typing: bool) -> Option<ServerEvent> {
+        let room_id = match self.target_room(chat_id) {
+            Ok(room_id) => room_id,
+            Err(event) => return Some(*event),
+        };
+        self.lobby_addr.do_send(SetTyping {
+            id: self.id,
+            conn_id: self.conn_id,
+            room_id,
+            typing,
+        });
+        None
+    }
+
+    fn react(
+        &self,
+        chat_id: Option<String>,
+        message_id: String,
+        emoji: String,
+        add: bool,
+    ) -> Option<ServerEvent> {
+        let room_id = match self.target_room(chat_id) {
+            Ok(room_id) => room_id,
+            Err(event) => return Some(*event),
+        };
+        if !valid_emoji(&emoji) {
+            return Some(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                format!("O emoji deve ter entre 1 e {} bytes", MAX_EMOJI_LEN),
+                Some(&room_id),
+            ));
+        }
+        self.lobby_addr.do_send(ChangeReaction {
+            conn_id: self.conn_id,
+            reaction: Reaction {
+                chat_id: room_id,
+                message_id,
+                user_id: self.id,
+                emoji,
+            },
+            add,
+        });
+        None
+    }
+}
+
+// Protocol errors are only reported to version 2 clients, version 1 keeps dropping them
+pub(super) fn reply<A>(
+    ctx: &mut ws::WebsocketContext<A>,
+    protocol: ProtocolVersion,
+    event: &ServerEvent,
+) where
+    A: Actor<Context = ws::WebsocketContext<A>>,
+{
+    if protocol == ProtocolVersion::V1 {
+        log::warn!("Dropping {:?} for a version 1 client", event);
+        return;
+    }
+    ctx.text(serde_json::to_string(event).unwrap());
 }
 
 impl Handler<WsMessage> for ChatWs {
@@ -48,62 +366,69 @@ impl Handler<WsMessage> for ChatWs {
     }
 }
 
+impl ChatSocket for ChatWs {
+    fn socket(&mut self) -> &mut SocketState {
+        &mut self.socket
+    }
+
+    fn text(&mut self, text: &str, ctx: &mut Self::Context) {
+        let socket = &self.socket;
+        // Version 1 clients send the chat text as is
+        if socket.protocol == ProtocolVersion::V1 {
+            if text.chars().count() > socket.limits.max_message_len {
+                log::warn!(
+                    "Dropping message from user {} longer than {} characters",
+                    socket.id,
+                    socket.limits.max_message_len
+                );
+                return;
+            }
+            socket.lobby_addr.do_send(ClientActorMessage {
+                id: socket.id,
+                conn_id: socket.conn_id,
+                msg: text.to_string(),
+                room_id: self.room.clone(),
+                client_msg_id: None,
+                reply_to: None,
+                thread_id: None,
+                attachments: Vec::new(),
+            });
+            return;
+        }
+        let reply_event = match serde_json::from_str::<ClientRequest>(text) {
+            Ok(request) => socket.request_context(Some(&self.room)).dispatch(request),
+            Err(err) => Some(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                err.to_string(),
+                None,
+            )),
+        };
+        if let Some(event) = reply_event {
+            reply(ctx, socket.protocol, &event);
+        }
+    }
+}
+
 impl Actor for ChatWs {
     type Context = ws::WebsocketContext<Self>;
     fn started(&mut self, ctx: &mut Self::Context) {
-        self.hb(ctx);
-
-        let addr = ctx.address();
-        self.lobby_addr
-            .send(Connect {
-                addr: addr.recipient(),
-                room_id: self.room.clone(),
-                id: self.id,
-            })
-            .into_actor(self)
-            .then(|res, _, ctx| {
-                match res {
-                    Ok(_res) => (),
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        let connect = Connect {
+            addr: ctx.address().recipient(),
+            room_id: self.room.clone(),
+            id: self.socket.id,
+            conn_id: self.socket.conn_id,
+            protocol: self.socket.protocol,
+            resume: self.resume.take(),
+        };
+        socket::start(self, ctx, connect);
     }
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.lobby_addr.do_send(Disconnect {
-            id: self.id,
-            room_id: self.room.clone(),
-        });
-        Running::Stop
+        socket::stop(self)
     }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for ChatWs {
     fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        match item {
-            Ok(ws::Message::Ping(msg)) => {
-                self.hb = Instant::now();
-                ctx.pong(&msg);
-            }
-            Ok(ws::Message::Pong(_)) => {
-                self.hb = Instant::now();
-            }
-            Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
-            Ok(ws::Message::Close(reason)) => {
-                ctx.close(reason);
-                ctx.stop();
-            }
-            Ok(ws::Message::Continuation(_)) => {
-                ctx.stop();
-            }
-            Ok(ws::Message::Nop) => (),
-            Ok(ws::Message::Text(s)) => self.lobby_addr.do_send(ClientActorMessage {
-                id: self.id,
-                msg: s.to_string(),
-                room_id: self.room.clone(),
-            }),
-            Err(e) => panic!("{}", e),
-        }
+        socket::handle_frame(self, item, ctx);
     }
 }
@@ -0,0 +1,85 @@
+use actix::{Actor, Addr, AsyncContext, Handler, Running, StreamHandler};
+use actix_web_actors::ws;
+
+use crate::{
+    config::{LimitsConfig, SocketConfig},
+    message::{ClientRequest, ErrorCode, ProtocolVersion, ServerEvent},
+    sockets::WsMessage,
+};
+
+use super::{
+    lobby_actor::{Lobby, Register},
+    lobby_socket::reply,
+    socket::{self, ChatSocket, SocketState},
+};
+
+// One socket for every chat of the user, rooms are joined with subscribe frames
+// instead of being fixed by the url like in ChatWs.
+#[derive(Debug)]
+pub struct MultiplexWs {
+    socket: SocketState,
+}
+
+impl MultiplexWs {
+    pub fn new(
+        lobby_addr: Addr<Lobby>,
+        id: i64,
+        config: SocketConfig,
+        limits: LimitsConfig,
+        protocol: ProtocolVersion,
+    ) -> MultiplexWs {
+        MultiplexWs {
+            socket: SocketState::new(lobby_addr, id, config, limits, protocol),
+        }
+    }
+}
+
+impl ChatSocket for MultiplexWs {
+    fn socket(&mut self) -> &mut SocketState {
+        &mut self.socket
+    }
+
+    fn text(&mut self, text: &str, ctx: &mut Self::Context) {
+        let reply_event = match serde_json::from_str::<ClientRequest>(text) {
+            Ok(request) => self.socket.request_context(None).dispatch(request),
+            Err(err) => Some(ServerEvent::error(
+                ErrorCode::InvalidFrame,
+                err.to_string(),
+                None,
+            )),
+        };
+        if let Some(event) = reply_event {
+            reply(ctx, self.socket.protocol, &event);
+        }
+    }
+}
+
+impl Handler<WsMessage> for MultiplexWs {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
+        ctx.text(msg.0)
+    }
+}
+
+impl Actor for MultiplexWs {
+    type Context = ws::WebsocketContext<Self>;
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let register = Register {
+            addr: ctx.address().recipient(),
+            id: self.socket.id,
+            conn_id: self.socket.conn_id,
+            protocol: self.socket.protocol,
+        };
+        socket::start(self, ctx, register);
+    }
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        socket::stop(self)
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for MultiplexWs {
+    fn handle(&mut self, item: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        socket::handle_frame(self, item, ctx);
+    }
+}
@@ -0,0 +1,155 @@
+use std::time::Instant;
+
+use actix::{
+    fut, prelude::ContextFutureSpawner, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext,
+    Handler, Message, Running, WrapFuture,
+};
+use actix_web_actors::ws;
+use uuid::Uuid;
+
+use crate::{
+    config::{LimitsConfig, SocketConfig},
+    message::{ProtocolVersion, ServerEvent},
+};
+
+use super::{
+    lobby_actor::{ConnId, Disconnect, Lobby},
+    lobby_socket::{reply, RequestContext},
+};
+
+// What ChatWs and MultiplexWs both keep about their connection
+#[derive(Debug)]
+pub(super) struct SocketState {
+    pub id: i64,
+    pub conn_id: ConnId,
+    pub lobby_addr: Addr<Lobby>,
+    hb: Instant,
+    config: SocketConfig,
+    pub limits: LimitsConfig,
+    pub protocol: ProtocolVersion,
+}
+
+impl SocketState {
+    pub fn new(
+        lobby_addr: Addr<Lobby>,
+        id: i64,
+        config: SocketConfig,
+        limits: LimitsConfig,
+        protocol: ProtocolVersion,
+    ) -> SocketState {
+        SocketState {
+            id,
+            conn_id: Uuid::new_v4(),
+            lobby_addr,
+            hb: Instant::now(),
+            config,
+            limits,
+            protocol,
+        }
+    }
+
+    pub fn request_context<'a>(&'a self, room: Option<&'a str>) -> RequestContext<'a> {
+        RequestContext {
+            lobby_addr: &self.lobby_addr,
+            id: self.id,
+            conn_id: self.conn_id,
+            limits: &self.limits,
+            room,
+        }
+    }
+}
+
+// The heartbeat, joining and leaving the lobby and every frame but text are the same for both
+// chat sockets, they only differ in how they join and what text frames mean
+pub(super) trait ChatSocket: Actor<Context = ws::WebsocketContext<Self>> {
+    fn socket(&mut self) -> &mut SocketState;
+
+    fn text(&mut self, text: &str, ctx: &mut Self::Context);
+}
+
+// For Actor::started, join is the lobby message that adds the connection
+pub(super) fn start<A, M>(act: &mut A, ctx: &mut A::Context, join: M)
+where
+    A: ChatSocket,
+    M: Message<Result = ()> + Send + 'static,
+    Lobby: Handler<M>,
+{
+    let interval = act.socket().config.heartbeat_interval();
+    ctx.run_interval(interval, |act, ctx| {
+        let socket = act.socket();
+        if Instant::now().duration_since(socket.hb) > socket.config.client_timeout() {
+            log::debug!("Disconnecting failed heartbeat");
+            ctx.stop();
+            return;
+        }
+
+        ctx.ping(b"PING");
+    });
+    let protocol = act.socket().protocol;
+    if protocol != ProtocolVersion::V1 {
+        reply(
+            ctx,
+            protocol,
+            &ServerEvent::Hello {
+                protocol_version: protocol.number(),
+            },
+        );
+    }
+
+    act.socket()
+        .lobby_addr
+        .send(join)
+        .into_actor(act)
+        .then(|res, _, ctx| {
+            if res.is_err() {
+                ctx.stop();
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+}
+
+// For Actor::stopping
+pub(super) fn stop<A: ChatSocket>(act: &mut A) -> Running {
+    let socket = act.socket();
+    socket.lobby_addr.do_send(Disconnect {
+        id: socket.id,
+        conn_id: socket.conn_id,
+    });
+    Running::Stop
+}
+
+// For StreamHandler::handle, a broken connection is logged and closed
+pub(super) fn handle_frame<A: ChatSocket>(
+    act: &mut A,
+    item: Result<ws::Message, ws::ProtocolError>,
+    ctx: &mut A::Context,
+) {
+    match item {
+        Ok(ws::Message::Ping(msg)) => {
+            act.socket().hb = Instant::now();
+            ctx.pong(&msg);
+        }
+        Ok(ws::Message::Pong(_)) => {
+            act.socket().hb = Instant::now();
+        }
+        Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
+        Ok(ws::Message::Close(reason)) => {
+            ctx.close(reason);
+            ctx.stop();
+        }
+        Ok(ws::Message::Continuation(_)) => {
+            ctx.stop();
+        }
+        Ok(ws::Message::Nop) => (),
+        Ok(ws::Message::Text(text)) => act.text(&text, ctx),
+        Err(err) => {
+            log::error!(
+                "Protocol error on connection {}: {}",
+                act.socket().conn_id,
+                err
+            );
+            ctx.stop();
+        }
+    }
+}
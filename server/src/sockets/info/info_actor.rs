@@ -1,27 +1,93 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
-use actix::{Actor, Handler, Message, Recipient};
+use actix::{
+    prelude::ContextFutureSpawner, Actor, ActorFutureExt, AsyncContext, Handler, Message,
+    MessageResult, Recipient, WrapFuture,
+};
 use chrono::Utc;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::{db::chat_db::Chat, message::format_date, sockets::WsMessage};
+use crate::{
+    config::SocketConfig,
+    db::{
+        chat_db::{Chat, ReadReceipt},
+        user_db::UserTable,
+        DatabasePool,
+    },
+    message::format_date,
+    sockets::WsMessage,
+};
 
 type Socket = Recipient<WsMessage>;
 
+// Same as the lobby, every socket has its own id so each device of a user gets notified.
+pub type ConnId = Uuid;
+
+// Most user ids a single presence query can ask about, and a connection can watch at once
+pub const MAX_PRESENCE_IDS: usize = 100;
+
+// How often connected users are checked for having gone idle
+const IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 pub struct Info {
-    sessions: HashMap<i64, Socket>,
+    sessions: HashMap<ConnId, Socket>,    //connection id to its socket
+    users: HashMap<i64, HashSet<ConnId>>, //user id to all of their connections
+    // Chat sockets live in the lobby, they are only counted here for presence
+    chat_connections: HashMap<i64, HashSet<ConnId>>,
+    // Every connected user and when they last did something
+    last_active: HashMap<i64, Instant>,
+    idle: HashSet<i64>,
+    // user id to the info connections watching their presence
+    watchers: HashMap<i64, HashSet<ConnId>>,
+    // info connection to the user ids it watches, at most MAX_PRESENCE_IDS
+    watching: HashMap<ConnId, HashSet<i64>>,
+    db: DatabasePool,
+    idle_after: Duration,
 }
+
 impl Info {
-    pub fn new() -> Self {
+    pub fn new(db: DatabasePool, config: &SocketConfig) -> Self {
         Self {
             sessions: HashMap::new(),
+            users: HashMap::new(),
+            chat_connections: HashMap::new(),
+            last_active: HashMap::new(),
+            idle: HashSet::new(),
+            watchers: HashMap::new(),
+            watching: HashMap::new(),
+            db,
+            idle_after: config.idle_after(),
         }
     }
 }
 
 impl Actor for Info {
     type Context = actix::Context<Info>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(IDLE_SWEEP_INTERVAL, |act, _| act.mark_idle());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PresenceStatus {
+    Online,
+    Idle,
+    Offline,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct UserPresence {
+    pub user_id: i64,
+    pub status: PresenceStatus,
+    // Only for offline users, None when they were never seen
+    pub last_seen: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,6 +95,20 @@ pub enum MessageType {
     ChatCreated,
     ChatRemoved,
     ChatUpdated,
+    ChatRead,
+    PresenceChanged,
+    // Answer to subscribe_presence, the current presence of every user subscribed to
+    Presence,
+}
+
+// What clients can send over the info socket
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum InfoRequest {
+    SubscribePresence { user_ids: Vec<i64> },
+    UnsubscribePresence { user_ids: Vec<i64> },
+    // Keeps a user that only has the info socket open from showing as idle
+    Active,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -40,16 +120,183 @@ pub struct InfoMessage {
 }
 
 impl Info {
-    fn send_message(&self, message: InfoMessage, target_id: &i64) {
-        if let Some(scoket_recipient) = self.sessions.get(target_id) {
-            let _ = scoket_recipient.do_send(WsMessage(serde_json::to_string(&message).unwrap()));
+    // Sends to every connection of the user
+    fn send_message(&self, message: &InfoMessage, target_id: &i64) {
+        let Some(connections) = self.users.get(target_id) else {
+            log::error!(
+                "Attempting to send {:?} but couldn't find user {}",
+                message,
+                target_id.to_string()
+            );
+            return;
+        };
+        let message = serde_json::to_string(message).unwrap();
+        connections
+            .iter()
+            .filter_map(|conn_id| self.sessions.get(conn_id))
+            .for_each(|socket| socket.do_send(WsMessage(message.clone())));
+    }
+
+    fn connected(&self, user_id: i64) -> bool {
+        self.users.contains_key(&user_id) || self.chat_connections.contains_key(&user_id)
+    }
+
+    fn status(&self, user_id: i64) -> PresenceStatus {
+        if !self.connected(user_id) {
+            PresenceStatus::Offline
+        } else if self.idle.contains(&user_id) {
+            PresenceStatus::Idle
+        } else {
+            PresenceStatus::Online
+        }
+    }
+
+    // Fills in who is connected, last_seen has the users that exist as read from the database
+    fn presence_of(&self, last_seen: Vec<(i64, Option<String>)>) -> Vec<UserPresence> {
+        last_seen
+            .into_iter()
+            .map(|(user_id, last_seen)| {
+                let status = self.status(user_id);
+                UserPresence {
+                    user_id,
+                    status,
+                    last_seen: last_seen.filter(|_| status == PresenceStatus::Offline),
+                }
+            })
+            .collect()
+    }
+
+    // Adds the ids the connection has room for, returns every requested id it now watches
+    fn watch(&mut self, conn_id: ConnId, user_ids: Vec<i64>) -> Vec<i64> {
+        let watching = self.watching.entry(conn_id).or_default();
+        let mut watched = Vec::new();
+        for user_id in user_ids {
+            if !watching.contains(&user_id) {
+                if watching.len() >= MAX_PRESENCE_IDS {
+                    continue;
+                }
+                watching.insert(user_id);
+                self.watchers.entry(user_id).or_default().insert(conn_id);
+            }
+            if !watched.contains(&user_id) {
+                watched.push(user_id);
+            }
+        }
+        if watching.is_empty() {
+            self.watching.remove(&conn_id);
+        }
+        watched
+    }
+
+    fn unwatch(&mut self, conn_id: ConnId, user_ids: &[i64]) {
+        let Some(watching) = self.watching.get_mut(&conn_id) else {
+            return;
+        };
+        for user_id in user_ids {
+            if !watching.remove(user_id) {
+                continue;
+            }
+            if let Some(watchers) = self.watchers.get_mut(user_id) {
+                watchers.remove(&conn_id);
+                if watchers.is_empty() {
+                    self.watchers.remove(user_id);
+                }
+            }
+        }
+        if watching.is_empty() {
+            self.watching.remove(&conn_id);
+        }
+    }
+
+    fn publish_presence(&self, presence: &UserPresence) {
+        let Some(watchers) = self.watchers.get(&presence.user_id) else {
+            return;
+        };
+        let message = serde_json::to_string(&InfoMessage {
+            message_type: MessageType::PresenceChanged,
+            message: serde_json::to_string(presence).unwrap(),
+            id: Some(presence.user_id),
+            date: format_date(Utc::now()),
+        })
+        .unwrap();
+        watchers
+            .iter()
+            .filter_map(|conn_id| self.sessions.get(conn_id))
+            .for_each(|socket| socket.do_send(WsMessage(message.clone())));
+    }
+
+    // Call before the connection is added
+    fn connecting(&mut self, user_id: i64) {
+        let was_connected = self.connected(user_id);
+        self.last_active.insert(user_id, Instant::now());
+        if !was_connected || self.idle.remove(&user_id) {
+            self.publish_presence(&UserPresence {
+                user_id,
+                status: PresenceStatus::Online,
+                last_seen: None,
+            });
+        }
+    }
+
+    // Call after the connection was removed, the last one going away saves last_seen
+    fn disconnected(&mut self, user_id: i64, ctx: &mut <Self as Actor>::Context) {
+        if self.connected(user_id) {
+            return;
+        }
+        self.last_active.remove(&user_id);
+        self.idle.remove(&user_id);
+        let last_seen = format_date(Utc::now());
+        self.publish_presence(&UserPresence {
+            user_id,
+            status: PresenceStatus::Offline,
+            last_seen: Some(last_seen.clone()),
+        });
+        let db = self.db.clone();
+        async move {
+            db.write(move |db| db.set_last_seen(user_id, &last_seen))
+                .await
+        }
+        .into_actor(self)
+        .map(move |res, _, _| {
+            if let Err(err) = res {
+                log::error!("Error saving last_seen of user {}: {:?}", user_id, err);
+            }
+        })
+        .spawn(ctx);
+    }
+
+    fn active(&mut self, user_id: i64) {
+        let Some(last_active) = self.last_active.get_mut(&user_id) else {
             return;
+        };
+        *last_active = Instant::now();
+        if self.idle.remove(&user_id) {
+            self.publish_presence(&UserPresence {
+                user_id,
+                status: PresenceStatus::Online,
+                last_seen: None,
+            });
+        }
+    }
+
+    fn mark_idle(&mut self) {
+        let now = Instant::now();
+        let idle: Vec<i64> = self
+            .last_active
+            .iter()
+            .filter(|(user_id, last_active)| {
+                !self.idle.contains(user_id) && now.duration_since(**last_active) >= self.idle_after
+            })
+            .map(|(user_id, _)| *user_id)
+            .collect();
+        for user_id in idle {
+            self.idle.insert(user_id);
+            self.publish_presence(&UserPresence {
+                user_id,
+                status: PresenceStatus::Idle,
+                last_seen: None,
+            });
         }
-        log::error!(
-            "Attempting to send {:?} but couldn't find user {}",
-            message,
-            target_id.to_string()
-        );
     }
 }
 
@@ -58,24 +305,26 @@ impl Info {
 pub struct ChatCreated {
     pub user_id: i64,
     pub room_id: String,
+    // Only these users are told about the chat
+    pub members: Vec<i64>,
 }
 
 impl Handler<ChatCreated> for Info {
     type Result = ();
 
     fn handle(&mut self, msg: ChatCreated, _: &mut Self::Context) -> Self::Result {
-        self.sessions
-            .iter()
-            .filter(|(user_id, _)| (*user_id).to_owned() != msg.user_id)
-            .for_each(|(user_id, _)| {
+        self.users
+            .keys()
+            .filter(|user_id| **user_id != msg.user_id && msg.members.contains(user_id))
+            .for_each(|user_id| {
                 self.send_message(
-                    InfoMessage {
+                    &InfoMessage {
                         message_type: MessageType::ChatCreated,
                         message: msg.room_id.clone(),
                         id: None,
                         date: format_date(Utc::now()),
                     },
-                    &user_id,
+                    user_id,
                 )
             })
     }
@@ -86,24 +335,25 @@ impl Handler<ChatCreated> for Info {
 pub struct ChatDeleted {
     pub user_id: i64,
     pub room_id: String,
+    pub members: Vec<i64>,
 }
 
 impl Handler<ChatDeleted> for Info {
     type Result = ();
 
     fn handle(&mut self, msg: ChatDeleted, _: &mut Self::Context) -> Self::Result {
-        self.sessions
-            .iter()
-            .filter(|(user_id, _)| (*user_id).to_owned() != msg.user_id)
-            .for_each(|(user_id, _)| {
+        self.users
+            .keys()
+            .filter(|user_id| **user_id != msg.user_id && msg.members.contains(user_id))
+            .for_each(|user_id| {
                 self.send_message(
-                    InfoMessage {
+                    &InfoMessage {
                         message_type: MessageType::ChatRemoved,
                         message: msg.room_id.clone(),
                         id: None,
                         date: format_date(Utc::now()),
                     },
-                    &user_id,
+                    user_id,
                 )
             });
     }
@@ -113,13 +363,19 @@ impl Handler<ChatDeleted> for Info {
 #[rtype(result = "()")]
 pub struct Connect {
     pub user_id: i64,
+    pub conn_id: ConnId,
     pub addr: Recipient<WsMessage>,
 }
 impl Handler<Connect> for Info {
     type Result = ();
 
     fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
-        self.sessions.insert(msg.user_id, msg.addr);
+        self.connecting(msg.user_id);
+        self.sessions.insert(msg.conn_id, msg.addr);
+        self.users
+            .entry(msg.user_id)
+            .or_default()
+            .insert(msg.conn_id);
     }
 }
 
@@ -127,11 +383,24 @@ impl Handler<Connect> for Info {
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub user_id: i64,
+    pub conn_id: ConnId,
 }
 impl Handler<Disconnect> for Info {
     type Result = ();
-    fn handle(&mut self, msg: Disconnect, _: &mut Self::Context) -> Self::Result {
-        self.sessions.remove(&msg.user_id);
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) -> Self::Result {
+        // Only this connection goes away, the user's other devices stay connected
+        self.sessions.remove(&msg.conn_id);
+        if let Some(connections) = self.users.get_mut(&msg.user_id) {
+            connections.remove(&msg.conn_id);
+            if connections.is_empty() {
+                self.users.remove(&msg.user_id);
+            }
+        }
+        if let Some(watching) = self.watching.get(&msg.conn_id) {
+            let watching: Vec<i64> = watching.iter().copied().collect();
+            self.unwatch(msg.conn_id, &watching);
+        }
+        self.disconnected(msg.user_id, ctx);
     }
 }
 
@@ -139,22 +408,164 @@ impl Handler<Disconnect> for Info {
 #[rtype(result = "()")]
 pub struct ChatUpdate {
     pub chat: Chat,
+    #[serde(skip)]
+    pub members: Vec<i64>,
 }
 
 impl Handler<ChatUpdate> for Info {
     type Result = ();
 
-    fn handle(&mut self, msg: ChatUpdate, ctx: &mut Self::Context) -> Self::Result {
-        self.sessions.iter().for_each(|(conn_id, recipient)| {
-            self.send_message(
-                InfoMessage {
-                    message_type: MessageType::ChatUpdated,
-                    message: serde_json::to_string(&msg).unwrap(),
+    fn handle(&mut self, msg: ChatUpdate, _: &mut Self::Context) -> Self::Result {
+        self.users
+            .keys()
+            .filter(|user_id| msg.members.contains(user_id))
+            .for_each(|user_id| {
+                self.send_message(
+                    &InfoMessage {
+                        message_type: MessageType::ChatUpdated,
+                        message: serde_json::to_string(&msg).unwrap(),
+                        id: None,
+                        date: format_date(Utc::now()),
+                    },
+                    user_id,
+                )
+            })
+    }
+}
+
+// The user read a chat on one device, the others update their unread badge
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct ChatRead {
+    pub receipt: ReadReceipt,
+}
+
+impl Handler<ChatRead> for Info {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChatRead, _: &mut Self::Context) -> Self::Result {
+        if !self.users.contains_key(&msg.receipt.user_id) {
+            return;
+        }
+        self.send_message(
+            &InfoMessage {
+                message_type: MessageType::ChatRead,
+                message: serde_json::to_string(&msg.receipt).unwrap(),
+                id: Some(msg.receipt.user_id),
+                date: format_date(Utc::now()),
+            },
+            &msg.receipt.user_id,
+        )
+    }
+}
+
+// The lobby reports chat sockets opening and closing so they count for presence
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ChatConnection {
+    pub user_id: i64,
+    pub conn_id: ConnId,
+    pub connected: bool,
+}
+
+impl Handler<ChatConnection> for Info {
+    type Result = ();
+
+    fn handle(&mut self, msg: ChatConnection, ctx: &mut Self::Context) -> Self::Result {
+        if msg.connected {
+            self.connecting(msg.user_id);
+            self.chat_connections
+                .entry(msg.user_id)
+                .or_default()
+                .insert(msg.conn_id);
+            return;
+        }
+        if let Some(connections) = self.chat_connections.get_mut(&msg.user_id) {
+            connections.remove(&msg.conn_id);
+            if connections.is_empty() {
+                self.chat_connections.remove(&msg.user_id);
+            }
+        }
+        self.disconnected(msg.user_id, ctx);
+    }
+}
+
+// Sending, typing or reading in a chat
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct UserActive {
+    pub user_id: i64,
+}
+
+impl Handler<UserActive> for Info {
+    type Result = ();
+
+    fn handle(&mut self, msg: UserActive, _: &mut Self::Context) -> Self::Result {
+        self.active(msg.user_id);
+    }
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct InfoClientRequest {
+    pub user_id: i64,
+    pub conn_id: ConnId,
+    pub request: InfoRequest,
+}
+
+impl Handler<InfoClientRequest> for Info {
+    type Result = ();
+
+    fn handle(&mut self, msg: InfoClientRequest, ctx: &mut Self::Context) -> Self::Result {
+        self.active(msg.user_id);
+        let user_ids = match msg.request {
+            InfoRequest::Active => return,
+            InfoRequest::UnsubscribePresence { user_ids } => {
+                self.unwatch(msg.conn_id, &user_ids);
+                return;
+            }
+            // Ids past the connection's limit are left out of the subscription and the answer
+            InfoRequest::SubscribePresence { user_ids } => self.watch(msg.conn_id, user_ids),
+        };
+
+        // Current state right away, changes follow as PresenceChanged
+        let db = self.db.clone();
+        async move { db.read(move |db| db.get_last_seen(&user_ids)).await }
+            .into_actor(self)
+            .map(move |res, act, _| {
+                let last_seen = match res {
+                    Ok(last_seen) => last_seen,
+                    Err(err) => {
+                        log::error!("Error getting presence for user {}: {:?}", msg.user_id, err);
+                        return;
+                    }
+                };
+                let Some(socket) = act.sessions.get(&msg.conn_id) else {
+                    return;
+                };
+                let message = InfoMessage {
+                    message_type: MessageType::Presence,
+                    message: serde_json::to_string(&act.presence_of(last_seen)).unwrap(),
                     id: None,
                     date: format_date(Utc::now()),
-                },
-                conn_id,
-            )
-        })
+                };
+                socket.do_send(WsMessage(serde_json::to_string(&message).unwrap()));
+            })
+            .spawn(ctx);
+    }
+}
+
+// Presence for /user/presence, last_seen comes from UserTable::get_last_seen
+#[derive(Message)]
+#[rtype(result = "Vec<UserPresence>")]
+pub struct GetPresence {
+    pub last_seen: Vec<(i64, Option<String>)>,
+}
+
+impl Handler<GetPresence> for Info {
+    type Result = MessageResult<GetPresence>;
+
+    fn handle(&mut self, msg: GetPresence, _: &mut Self::Context) -> Self::Result {
+        MessageResult(self.presence_of(msg.last_seen))
     }
 }
@@ -4,30 +4,35 @@ use actix::{
 };
 use actix_web_actors::ws;
 use std::time::Instant;
+use uuid::Uuid;
 
-use crate::sockets::{WsMessage, CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+use crate::{config::SocketConfig, sockets::WsMessage};
 
-use super::info_actor::{Connect, Disconnect, Info};
+use super::info_actor::{ConnId, Connect, Disconnect, Info, InfoClientRequest, InfoRequest};
 
 #[derive(Debug)]
 pub struct InfoWS {
     id: i64,
+    conn_id: ConnId,
     info_addr: Addr<Info>,
     hb: Instant,
+    config: SocketConfig,
 }
 
 impl InfoWS {
-    pub fn new(id: i64, info_addr: Addr<Info>) -> Self {
+    pub fn new(id: i64, info_addr: Addr<Info>, config: SocketConfig) -> Self {
         Self {
             id,
+            conn_id: Uuid::new_v4(),
             info_addr,
             hb: Instant::now(),
+            config,
         }
     }
 
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
+        ctx.run_interval(self.config.heartbeat_interval(), |act, ctx| {
+            if Instant::now().duration_since(act.hb) > act.config.client_timeout() {
                 println!("Disconnecting failed heartbeat");
                 ctx.stop();
                 return;
@@ -47,6 +52,7 @@ impl Actor for InfoWS {
             .send(Connect {
                 addr: addr.recipient(),
                 user_id: self.id,
+                conn_id: self.conn_id,
             })
             .into_actor(self)
             .then(|res, _, ctx| {
@@ -59,7 +65,10 @@ impl Actor for InfoWS {
             .wait(ctx);
     }
     fn stopping(&mut self, _: &mut Self::Context) -> actix::Running {
-        self.info_addr.do_send(Disconnect { user_id: self.id });
+        self.info_addr.do_send(Disconnect {
+            user_id: self.id,
+            conn_id: self.conn_id,
+        });
         Running::Stop
     }
 }
@@ -91,7 +100,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for InfoWS {
                 ctx.stop();
             }
             Ok(ws::Message::Nop) => (),
-            Ok(ws::Message::Text(_)) => (),
+            Ok(ws::Message::Text(text)) => {
+                // Anything that isn't an InfoRequest is ignored, like before there were any
+                let Ok(request) = serde_json::from_str::<InfoRequest>(&text) else {
+                    return;
+                };
+                self.info_addr.do_send(InfoClientRequest {
+                    user_id: self.id,
+                    conn_id: self.conn_id,
+                    request,
+                });
+            }
             Err(e) => panic!("{}", e),
         }
     }
@@ -0,0 +1,211 @@
+pub mod cleanup;
+pub mod images;
+
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+use crate::config::StorageConfig;
+
+// Files live in <root>/blobs/<first two hex digits>/<sha256>, identical uploads share one file.
+// Thumbnails of images go the same way under <root>/thumbs as <sha256>-<size>.
+// Uploads are written to <root>/tmp and only moved in once they are complete, resumable ones
+// to <root>/partial/<upload id>.
+#[derive(Debug, Clone)]
+pub struct Storage {
+    root: PathBuf,
+    // Names of the files in tmp and partial being written right now
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+// Held while an upload's file is written, see Storage::temp_path and Storage::lock_upload
+pub struct UploadLock {
+    name: String,
+    busy: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Drop for UploadLock {
+    fn drop(&mut self) {
+        if let Ok(mut busy) = self.busy.lock() {
+            busy.remove(&self.name);
+        }
+    }
+}
+
+impl Storage {
+    pub fn open(config: &StorageConfig) -> io::Result<Storage> {
+        let storage = Storage {
+            root: config.root.clone(),
+            busy: Arc::new(Mutex::new(HashSet::new())),
+        };
+        fs::create_dir_all(storage.root.join("blobs"))?;
+        fs::create_dir_all(storage.temp_dir())?;
+        fs::create_dir_all(storage.partial_dir())?;
+        Ok(storage)
+    }
+
+    fn temp_dir(&self) -> PathBuf {
+        self.root.join("tmp")
+    }
+
+    fn partial_dir(&self) -> PathBuf {
+        self.root.join("partial")
+    }
+
+    // A fresh path for an upload in progress, kept by remove_stale_files while the lock is held
+    // however long the upload takes
+    pub fn temp_path(&self) -> (PathBuf, UploadLock) {
+        let name = Uuid::new_v4().to_string();
+        if let Ok(mut busy) = self.busy.lock() {
+            busy.insert(name.clone());
+        }
+        let path = self.temp_dir().join(&name);
+        let lock = UploadLock {
+            name,
+            busy: self.busy.clone(),
+        };
+        (path, lock)
+    }
+
+    pub fn partial_path(&self, upload_id: &str) -> PathBuf {
+        self.partial_dir().join(upload_id)
+    }
+
+    pub fn blob_path(&self, sha256: &str) -> PathBuf {
+        self.root
+            .join("blobs")
+            .join(sha256.get(..2).unwrap_or("00"))
+            .join(sha256)
+    }
+
+    pub fn thumbnail_path(&self, sha256: &str, size: u32) -> PathBuf {
+        self.root
+            .join("thumbs")
+            .join(sha256.get(..2).unwrap_or("00"))
+            .join(format!("{}-{}", sha256, size))
+    }
+
+    // Moves a finished upload in place, when the same contents were stored before the new copy
+    // is dropped
+    pub fn persist(&self, temp: &Path, sha256: &str) -> io::Result<PathBuf> {
+        let path = self.blob_path(sha256);
+        if path.exists() {
+            fs::remove_file(temp)?;
+            // Counts as new again, so remove_blob leaves it alone until its attachment is stored
+            File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(SystemTime::now())?;
+            return Ok(path);
+        }
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(temp, &path)?;
+        Ok(path)
+    }
+
+    // None when another request is already writing to the upload
+    pub fn lock_upload(&self, upload_id: &str) -> Option<UploadLock> {
+        let mut busy = self.busy.lock().ok()?;
+        if !busy.insert(upload_id.to_string()) {
+            return None;
+        }
+        Some(UploadLock {
+            name: upload_id.to_string(),
+            busy: self.busy.clone(),
+        })
+    }
+
+    // Removes temporary and partial files untouched for longer than max_age, those of uploads
+    // that were abandoned or whose chat is gone. Files still being written are kept, a slow
+    // client may not send anything for a while. Returns how many were removed.
+    pub fn remove_stale_files(&self, max_age: Duration) -> io::Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for dir in [self.temp_dir(), self.partial_dir()] {
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                let modified = entry.metadata()?.modified()?;
+                if now.duration_since(modified).unwrap_or_default() <= max_age {
+                    continue;
+                }
+                // Held while removing so a writer can't start on the file in between
+                let Ok(busy) = self.busy.lock() else {
+                    continue;
+                };
+                if busy.contains(entry.file_name().to_string_lossy().as_ref()) {
+                    continue;
+                }
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    // The contents of blobs untouched for longer than max_age, whether anything still points to
+    // them is up to the caller
+    pub fn stale_blobs(&self, max_age: Duration) -> io::Result<Vec<String>> {
+        let now = SystemTime::now();
+        let mut stale = Vec::new();
+        for dir in fs::read_dir(self.root.join("blobs"))? {
+            for entry in fs::read_dir(dir?.path())? {
+                let entry = entry?;
+                let modified = entry.metadata()?.modified()?;
+                if now.duration_since(modified).unwrap_or_default() > max_age {
+                    stale.push(entry.file_name().to_string_lossy().into_owned());
+                }
+            }
+        }
+        Ok(stale)
+    }
+
+    // Removes the blob and its thumbnails, unless it was stored again since stale_blobs found it
+    pub fn remove_blob(&self, sha256: &str, max_age: Duration) -> io::Result<bool> {
+        let path = self.blob_path(sha256);
+        let modified = fs::metadata(&path)?.modified()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age <= max_age {
+            return Ok(false);
+        }
+        fs::remove_file(&path)?;
+        for size in images::THUMBNAIL_SIZES {
+            match fs::remove_file(self.thumbnail_path(sha256, *size)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => (),
+            }
+        }
+        Ok(true)
+    }
+}
+
+// The sha256 of the file and up to head_len of its first bytes
+pub fn hash_file(path: &Path, head_len: usize) -> io::Result<(String, Vec<u8>)> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut head = Vec::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        if head.len() < head_len {
+            let take = read.min(head_len - head.len());
+            head.extend_from_slice(&buf[..take]);
+        }
+    }
+    Ok((format!("{:x}", hasher.finalize()), head))
+}
@@ -0,0 +1,126 @@
+use std::time::Duration;
+
+use actix::{prelude::ContextFutureSpawner, Actor, ActorFutureExt, AsyncContext, WrapFuture};
+use actix_web::web;
+use chrono::Utc;
+
+use crate::{
+    db::{attachment_db::AttachmentsTable, upload_db::UploadsTable, DatabasePool},
+    message::format_date,
+};
+
+use super::Storage;
+
+// How often abandoned uploads and unused files are looked for
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Forgets resumable uploads that went past their expiry and attachments that were never sent,
+// then removes the files left behind by them, by interrupted multipart uploads and by deleted
+// messages
+pub struct Cleanup {
+    db: DatabasePool,
+    storage: Storage,
+    expiry: Duration,
+}
+
+impl Cleanup {
+    pub fn new(db: DatabasePool, storage: Storage, expiry: Duration) -> Cleanup {
+        Cleanup {
+            db,
+            storage,
+            expiry,
+        }
+    }
+
+    fn sweep(&mut self, ctx: &mut actix::Context<Self>) {
+        let db = self.db.clone();
+        let storage = self.storage.clone();
+        let expiry = self.expiry;
+        async move {
+            let now = Utc::now();
+            let (expired_at, unsent_before) = (
+                format_date(now),
+                format_date(now - chrono::Duration::seconds(expiry.as_secs() as i64)),
+            );
+            let uploads = db
+                .write(move |db| db.delete_expired_uploads(&expired_at))
+                .await;
+            let unsent = db
+                .write(move |db| db.delete_unsent_attachments(&unsent_before))
+                .await;
+            let temp_storage = storage.clone();
+            let files = web::block(move || temp_storage.remove_stale_files(expiry)).await;
+            let blobs = remove_unused_blobs(db, storage, expiry).await;
+            (uploads, unsent, files, blobs)
+        }
+        .into_actor(self)
+        .map(|(uploads, unsent, files, blobs), _, _| {
+            match uploads {
+                Ok(0) => (),
+                Ok(count) => log::info!("Removed {} expired uploads", count),
+                Err(err) => log::error!("Error removing expired uploads: {:?}", err),
+            }
+            match unsent {
+                Ok(0) => (),
+                Ok(count) => log::info!("Removed {} attachments that were never sent", count),
+                Err(err) => log::error!("Error removing unsent attachments: {:?}", err),
+            }
+            match files {
+                Ok(Ok(0)) => (),
+                Ok(Ok(count)) => log::info!("Removed {} stale upload files", count),
+                Ok(Err(err)) => log::error!("Error removing stale upload files: {}", err),
+                Err(err) => log::error!("Error removing stale upload files: {}", err),
+            }
+            match blobs {
+                Ok(0) => (),
+                Ok(count) => log::info!("Removed {} files no attachment uses", count),
+                Err(err) => log::error!("Error removing unused files: {}", err),
+            }
+        })
+        .spawn(ctx);
+    }
+}
+
+impl Actor for Cleanup {
+    type Context = actix::Context<Cleanup>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.sweep(ctx);
+        ctx.run_interval(CLEANUP_INTERVAL, |act, ctx| act.sweep(ctx));
+    }
+}
+
+// Blobs are shared by every attachment with the same contents, one goes once the last of them
+// is gone. Only blobs older than expiry are looked at, newer ones may belong to an upload whose
+// attachment isn't stored yet.
+async fn remove_unused_blobs(
+    db: DatabasePool,
+    storage: Storage,
+    expiry: Duration,
+) -> Result<usize, String> {
+    let blob_storage = storage.clone();
+    let stale = web::block(move || blob_storage.stale_blobs(expiry))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| err.to_string())?;
+    if stale.is_empty() {
+        return Ok(0);
+    }
+    let unused = db
+        .read(move |db| db.unreferenced_blobs(stale))
+        .await
+        .map_err(|err| format!("{:?}", err))?;
+    web::block(move || {
+        let mut removed = 0;
+        for sha256 in unused {
+            match storage.remove_blob(&sha256, expiry) {
+                Ok(true) => removed += 1,
+                Ok(false) => (),
+                Err(err) => log::error!("Error removing file {}: {}", sha256, err),
+            }
+        }
+        removed
+    })
+    .await
+    .map_err(|err| err.to_string())
+}
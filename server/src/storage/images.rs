@@ -0,0 +1,193 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Cursor, Write},
+};
+
+use image::{
+    codecs::jpeg::JpegEncoder,
+    error::{DecodingError, ImageFormatHint},
+    metadata::Orientation,
+    DynamicImage, ImageDecoder, ImageError, ImageFormat, ImageReader, ImageResult, Limits,
+};
+use img_parts::{jpeg::Jpeg, png::Png, webp::WebP, Bytes, ImageEXIF};
+
+use super::Storage;
+
+// Longest side of each thumbnail, GET /attachments/{id}?size=N serves the one of size N
+pub const THUMBNAIL_SIZES: &[u32] = &[64, 256, 1024];
+
+// Larger images are not decoded, they are kept as plain files
+const MAX_IMAGE_SIDE: u32 = 12_000;
+const JPEG_QUALITY: u8 = 90;
+const THUMBNAIL_JPEG_QUALITY: u8 = 80;
+
+// Metadata that can tell where or when a picture was taken: EXIF and XMP (APP1), IPTC (APP13),
+// comments and PNG text chunks. JFIF, ICC profiles and Adobe's APP14 only change how the
+// pixels are shown so they stay.
+const JPEG_STRIPPED_MARKERS: &[u8] = &[
+    0xE1, 0xE3, 0xE4, 0xE5, 0xE6, 0xE7, 0xE8, 0xE9, 0xEA, 0xEB, 0xEC, 0xED, 0xEF, 0xFE,
+];
+const PNG_STRIPPED_CHUNKS: &[[u8; 4]] = &[*b"eXIf", *b"tEXt", *b"zTXt", *b"iTXt", *b"tIME"];
+
+// What the attachments table keeps about an image
+#[derive(Debug, Clone)]
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    pub blurhash: Option<String>,
+}
+
+pub struct StrippedImage {
+    pub info: ImageInfo,
+    format: ImageFormat,
+    image: DynamicImage,
+}
+
+// The formats whose metadata is stripped, the same ones /user/image and /chat/image accept
+pub fn image_format(mime_type: &str) -> Option<ImageFormat> {
+    match mime_type {
+        "image/png" => Some(ImageFormat::Png),
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/webp" => Some(ImageFormat::WebP),
+        _ => None,
+    }
+}
+
+// Thumbnails of photos are jpeg, everything else may be transparent so they are png
+pub fn thumbnail_mime_type(mime_type: &str) -> &'static str {
+    match mime_type {
+        "image/jpeg" => "image/jpeg",
+        _ => "image/png",
+    }
+}
+
+// The image file without its metadata, errors mean it isn't a valid image. Usually only the
+// metadata is cut out and the pixels are left untouched, pictures with an EXIF rotation are
+// re-encoded already rotated since the rotation is lost along with the rest.
+pub fn strip_metadata(
+    bytes: Vec<u8>,
+    format: ImageFormat,
+) -> ImageResult<(Vec<u8>, StrippedImage)> {
+    let mut reader = ImageReader::with_format(Cursor::new(&bytes), format);
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_SIDE);
+    limits.max_image_height = Some(MAX_IMAGE_SIDE);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+
+    let stripped = if orientation == Orientation::NoTransforms {
+        strip_containers(Bytes::from(bytes), format)?
+    } else {
+        image.apply_orientation(orientation);
+        encode(&image, format)?
+    };
+
+    let small = image.thumbnail(32, 32).to_rgba8();
+    let blurhash = match blurhash::encode(4, 3, small.width(), small.height(), small.as_raw()) {
+        Ok(blurhash) => Some(blurhash),
+        Err(err) => {
+            log::error!("Error computing blurhash: {}", err);
+            None
+        }
+    };
+    let info = ImageInfo {
+        width: image.width(),
+        height: image.height(),
+        blurhash,
+    };
+    Ok((
+        stripped,
+        StrippedImage {
+            info,
+            format,
+            image,
+        },
+    ))
+}
+
+fn strip_containers(bytes: Bytes, format: ImageFormat) -> ImageResult<Vec<u8>> {
+    let stripped = match format {
+        ImageFormat::Jpeg => {
+            let mut jpeg = Jpeg::from_bytes(bytes).map_err(invalid_data)?;
+            for marker in JPEG_STRIPPED_MARKERS {
+                jpeg.remove_segments_by_marker(*marker);
+            }
+            jpeg.encoder().bytes()
+        }
+        ImageFormat::Png => {
+            let mut png = Png::from_bytes(bytes).map_err(invalid_data)?;
+            for kind in PNG_STRIPPED_CHUNKS {
+                png.remove_chunks_by_type(*kind);
+            }
+            png.encoder().bytes()
+        }
+        ImageFormat::WebP => {
+            let mut webp = WebP::from_bytes(bytes).map_err(invalid_data)?;
+            webp.remove_chunks_by_id(img_parts::webp::CHUNK_XMP);
+            // Also updates the VP8X flags
+            webp.set_exif(None);
+            webp.encoder().bytes()
+        }
+        // GIF has no place for EXIF
+        _ => bytes,
+    };
+    Ok(stripped.to_vec())
+}
+
+fn encode(image: &DynamicImage, format: ImageFormat) -> ImageResult<Vec<u8>> {
+    let mut encoded = Cursor::new(Vec::new());
+    match format {
+        ImageFormat::Jpeg => image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, JPEG_QUALITY))?,
+        format => image.write_to(&mut encoded, format)?,
+    }
+    Ok(encoded.into_inner())
+}
+
+fn invalid_data(err: img_parts::Error) -> ImageError {
+    ImageError::Decoding(DecodingError::new(ImageFormatHint::Unknown, err))
+}
+
+impl StrippedImage {
+    // One thumbnail per size smaller than the image, those already there from an earlier upload
+    // of the same file are kept
+    pub fn write_thumbnails(&self, storage: &Storage, sha256: &str) -> ImageResult<()> {
+        for size in THUMBNAIL_SIZES {
+            if self.info.width.max(self.info.height) <= *size {
+                continue;
+            }
+            let path = storage.thumbnail_path(sha256, *size);
+            if path.exists() {
+                continue;
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            // Written to tmp first so a half written thumbnail is never served
+            let (temp, _lock) = storage.temp_path();
+            let thumbnail = self.image.thumbnail(*size, *size);
+            let mut file = BufWriter::new(File::create(&temp)?);
+            let written = match self.format {
+                ImageFormat::Jpeg => {
+                    thumbnail
+                        .to_rgb8()
+                        .write_with_encoder(JpegEncoder::new_with_quality(
+                            &mut file,
+                            THUMBNAIL_JPEG_QUALITY,
+                        ))
+                }
+                _ => thumbnail.write_to(&mut file, ImageFormat::Png),
+            };
+            if let Err(err) = written.and_then(|_| Ok(file.flush()?)) {
+                let _ = fs::remove_file(&temp);
+                return Err(err);
+            }
+            fs::rename(&temp, &path)?;
+        }
+        Ok(())
+    }
+}